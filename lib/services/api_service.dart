@@ -8,12 +8,14 @@ class ApiUser {
   final String username;
   final String displayName;
   final String role;
+  final bool mustChangePassword;
 
   ApiUser({
     required this.id,
     required this.username,
     required this.displayName,
     required this.role,
+    this.mustChangePassword = false,
   });
 
   factory ApiUser.fromJson(Map<String, dynamic> json) {
@@ -22,12 +24,32 @@ class ApiUser {
       username: json['username'] as String,
       displayName: (json['display_name'] as String?) ?? '',
       role: (json['role'] as String?) ?? 'user',
+      mustChangePassword: (json['must_change_password'] as bool?) ?? false,
     );
   }
 
   bool get isAdmin => role == 'admin';
 }
 
+/// One page of `GET /users`.
+class UserPage {
+  final List<ApiUser> users;
+  final int total;
+  final String? nextCursor;
+
+  UserPage({required this.users, required this.total, this.nextCursor});
+
+  factory UserPage.fromJson(Map<String, dynamic> json) {
+    return UserPage(
+      users: (json['users'] as List)
+          .map((j) => ApiUser.fromJson(j as Map<String, dynamic>))
+          .toList(),
+      total: json['total'] as int,
+      nextCursor: json['next_cursor'] as String?,
+    );
+  }
+}
+
 class ApiService {
   static final ApiService _instance = ApiService._();
   factory ApiService() => _instance;
@@ -53,6 +75,9 @@ class ApiService {
     return 'http://localhost:18884';
   }
 
+  /// Versioned API root; see `/api/openapi.json` for the routes.
+  String get _apiUrl => '$_baseUrl/api/v1';
+
   Map<String, String> get _headers {
     final h = <String, String>{'Content-Type': 'application/json'};
     if (_token != null) {
@@ -61,6 +86,15 @@ class ApiService {
     return h;
   }
 
+  /// Error text from an API error body, including password policy
+  /// violations when present.
+  String _errorMessage(dynamic body, String fallback) {
+    final message = body['error'] as String? ?? fallback;
+    final violations = body['violations'] as List<dynamic>?;
+    if (violations == null || violations.isEmpty) return message;
+    return [message, ...violations.map((v) => '- ${v['message']}')].join('\n');
+  }
+
   /// Try to restore a saved token on app startup.
   Future<bool> tryRestoreSession() async {
     final saved = await _storage.read(key: _tokenKey);
@@ -81,7 +115,7 @@ class ApiService {
   /// Login and store the JWT token.
   Future<ApiUser> login(String username, String password) async {
     final resp = await http.post(
-      Uri.parse('$_baseUrl/api/auth/login'),
+      Uri.parse('$_apiUrl/auth/login'),
       headers: {'Content-Type': 'application/json'},
       body: jsonEncode({'username': username, 'password': password}),
     );
@@ -108,7 +142,7 @@ class ApiService {
   /// Get the current user profile.
   Future<ApiUser> getMe() async {
     final resp = await http.get(
-      Uri.parse('$_baseUrl/api/auth/me'),
+      Uri.parse('$_apiUrl/auth/me'),
       headers: _headers,
     );
     if (resp.statusCode != 200) throw Exception('Sessão expirada');
@@ -118,7 +152,7 @@ class ApiService {
   /// Change password.
   Future<void> changePassword(String currentPassword, String newPassword) async {
     final resp = await http.put(
-      Uri.parse('$_baseUrl/api/auth/password'),
+      Uri.parse('$_apiUrl/auth/password'),
       headers: _headers,
       body: jsonEncode({
         'current_password': currentPassword,
@@ -127,20 +161,52 @@ class ApiService {
     );
     if (resp.statusCode != 200) {
       final body = jsonDecode(resp.body);
-      throw Exception(body['error'] ?? 'Erro ao alterar senha');
+      throw Exception(_errorMessage(body, 'Erro ao alterar senha'));
     }
+
+    // The server issues a new token once the pending password change is done.
+    final data = jsonDecode(resp.body);
+    _token = data['token'] as String;
+    _currentUser = ApiUser.fromJson(data['user'] as Map<String, dynamic>);
+    await _storage.write(key: _tokenKey, value: _token!);
   }
 
   // -- User management (admin) --
 
-  Future<List<ApiUser>> listUsers() async {
+  /// [sort] is `username`, `display_name` or `created_at`, with a leading
+  /// `-` for descending order.
+  Future<UserPage> listUsersPage({
+    String? query,
+    String? role,
+    String? sort,
+    int? limit,
+    String? cursor,
+  }) async {
+    final params = <String, String>{
+      if (query != null && query.isNotEmpty) 'q': query,
+      if (role != null) 'role': role,
+      if (sort != null) 'sort': sort,
+      if (limit != null) 'limit': '$limit',
+      if (cursor != null) 'cursor': cursor,
+    };
     final resp = await http.get(
-      Uri.parse('$_baseUrl/api/users'),
+      Uri.parse('$_apiUrl/users').replace(queryParameters: params),
       headers: _headers,
     );
     if (resp.statusCode != 200) throw Exception('Erro ao listar usuários');
-    final list = jsonDecode(resp.body) as List;
-    return list.map((j) => ApiUser.fromJson(j as Map<String, dynamic>)).toList();
+    return UserPage.fromJson(jsonDecode(resp.body) as Map<String, dynamic>);
+  }
+
+  /// Every user, following the pages.
+  Future<List<ApiUser>> listUsers() async {
+    final users = <ApiUser>[];
+    String? cursor;
+    do {
+      final page = await listUsersPage(limit: 200, cursor: cursor);
+      users.addAll(page.users);
+      cursor = page.nextCursor;
+    } while (cursor != null);
+    return users;
   }
 
   Future<ApiUser> createUser({
@@ -150,7 +216,7 @@ class ApiService {
     String role = 'user',
   }) async {
     final resp = await http.post(
-      Uri.parse('$_baseUrl/api/users'),
+      Uri.parse('$_apiUrl/users'),
       headers: _headers,
       body: jsonEncode({
         'username': username,
@@ -161,14 +227,14 @@ class ApiService {
     );
     if (resp.statusCode != 201) {
       final body = jsonDecode(resp.body);
-      throw Exception(body['error'] ?? 'Erro ao criar usuário');
+      throw Exception(_errorMessage(body, 'Erro ao criar usuário'));
     }
     return ApiUser.fromJson(jsonDecode(resp.body));
   }
 
   Future<ApiUser> updateUser(String id, {String? displayName, String? role, String? password}) async {
     final resp = await http.put(
-      Uri.parse('$_baseUrl/api/users/$id'),
+      Uri.parse('$_apiUrl/users/$id'),
       headers: _headers,
       body: jsonEncode({
         if (displayName != null) 'display_name': displayName,
@@ -178,14 +244,14 @@ class ApiService {
     );
     if (resp.statusCode != 200) {
       final body = jsonDecode(resp.body);
-      throw Exception(body['error'] ?? 'Erro ao atualizar');
+      throw Exception(_errorMessage(body, 'Erro ao atualizar'));
     }
     return ApiUser.fromJson(jsonDecode(resp.body));
   }
 
   Future<void> deleteUser(String id) async {
     final resp = await http.delete(
-      Uri.parse('$_baseUrl/api/users/$id'),
+      Uri.parse('$_apiUrl/users/$id'),
       headers: _headers,
     );
     if (resp.statusCode != 200) {
@@ -6,6 +6,7 @@ import 'package:flutter/material.dart';
 import 'package:web/web.dart' as web;
 import '../models/ssh_connection.dart';
 import '../providers/terminal_provider.dart';
+import '../services/api_service.dart';
 
 @JS('IronRdpSession')
 extension type IronRdpBridge._(JSObject _) implements JSObject {
@@ -18,6 +19,7 @@ extension type IronRdpBridge._(JSObject _) implements JSObject {
   external void onStatus(JSFunction callback);
   external void onError(JSFunction callback);
   external void onTerminate(JSFunction callback);
+  external void onNotice(JSFunction callback);
 }
 
 class RdpViewPanel extends StatefulWidget {
@@ -112,6 +114,15 @@ class _RdpViewPanelState extends State<RdpViewPanel> {
         }
       }).toJS);
 
+      rdp.onNotice(((JSAny? message) {
+        final text = (message as JSString?)?.toDart;
+        if (text != null && !_disposed && mounted) {
+          ScaffoldMessenger.of(context).showSnackBar(
+            SnackBar(content: Text(text), duration: const Duration(seconds: 10)),
+          );
+        }
+      }).toJS);
+
       if (mounted) setState(() => _status = 'loading_wasm');
       await rdp.init().toDart;
       if (_disposed) return;
@@ -146,6 +157,7 @@ class _RdpViewPanelState extends State<RdpViewPanel> {
       opts['password'] = (conn.password ?? '').toJS;
       opts['destination'] = destination.toJS;
       opts['proxyAddress'] = proxyAddress.toJS;
+      opts['authToken'] = (ApiService().token ?? '').toJS;
       opts['canvas'] = _canvas!;
       opts['width'] = (width as num).toJS;
       opts['height'] = (height as num).toJS;
@@ -0,0 +1,139 @@
+//! Concurrent login benchmark against a running rdp-proxy.
+//!
+//! ```text
+//! cargo run --release --example login_bench -- \
+//!     --url http://127.0.0.1:8443 --username bench --password '...' \
+//!     --concurrency 32 --requests 2000
+//! ```
+//!
+//! Also times `GET /api/auth/me` alongside the logins, since those reads are
+//! what a login storm should not be holding up.
+
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+struct Args {
+    url: String,
+    username: String,
+    password: String,
+    concurrency: usize,
+    requests: usize,
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut args = Args {
+        url: "http://127.0.0.1:8443".to_string(),
+        username: "root".to_string(),
+        password: String::new(),
+        concurrency: 32,
+        requests: 1000,
+    };
+    let mut it = std::env::args().skip(1);
+    while let Some(flag) = it.next() {
+        let value = it
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--url" => args.url = value,
+            "--username" => args.username = value,
+            "--password" => args.password = value,
+            "--concurrency" => args.concurrency = value.parse()?,
+            "--requests" => args.requests = value.parse()?,
+            other => anyhow::bail!("unknown flag {other}"),
+        }
+    }
+    Ok(args)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let idx = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[idx]
+}
+
+fn report(name: &str, mut samples: Vec<Duration>, errors: usize, elapsed: Duration) {
+    samples.sort();
+    println!(
+        "{name:<6} n={:<6} errors={:<4} rps={:<8.1} p50={:>8.2?} p90={:>8.2?} p99={:>8.2?} max={:>8.2?}",
+        samples.len(),
+        errors,
+        samples.len() as f64 / elapsed.as_secs_f64(),
+        percentile(&samples, 0.50),
+        percentile(&samples, 0.90),
+        percentile(&samples, 0.99),
+        samples.last().copied().unwrap_or_default(),
+    );
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    let client = reqwest::Client::new();
+    let login_url = format!("{}/api/auth/login", args.url);
+    let me_url = format!("{}/api/auth/me", args.url);
+    let body = json!({ "username": args.username, "password": args.password });
+
+    let token = client
+        .post(&login_url)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?["token"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("login response has no token"))?
+        .to_string();
+
+    let per_worker = args.requests.div_ceil(args.concurrency);
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for _ in 0..args.concurrency {
+        let (client, login_url, me_url, body, token) = (
+            client.clone(),
+            login_url.clone(),
+            me_url.clone(),
+            body.clone(),
+            token.clone(),
+        );
+        workers.push(tokio::spawn(async move {
+            let mut logins = Vec::with_capacity(per_worker);
+            let mut reads = Vec::with_capacity(per_worker);
+            let mut errors = (0, 0);
+            for _ in 0..per_worker {
+                let t = Instant::now();
+                match client.post(&login_url).json(&body).send().await {
+                    Ok(r) if r.status().is_success() => logins.push(t.elapsed()),
+                    _ => errors.0 += 1,
+                }
+                let t = Instant::now();
+                match client.get(&me_url).bearer_auth(&token).send().await {
+                    Ok(r) if r.status().is_success() => reads.push(t.elapsed()),
+                    _ => errors.1 += 1,
+                }
+            }
+            (logins, reads, errors)
+        }));
+    }
+
+    let (mut logins, mut reads, mut errors) = (Vec::new(), Vec::new(), (0, 0));
+    for worker in workers {
+        let (l, r, e) = worker.await?;
+        logins.extend(l);
+        reads.extend(r);
+        errors.0 += e.0;
+        errors.1 += e.1;
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "{} workers, {} requests each, {:.2?} total",
+        args.concurrency, per_worker, elapsed
+    );
+    report("login", logins, errors.0, elapsed);
+    report("me", reads, errors.1, elapsed);
+    Ok(())
+}
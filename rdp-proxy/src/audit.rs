@@ -0,0 +1,126 @@
+use sha2::{Digest, Sha256};
+
+use crate::db::AuditEntry;
+use crate::AppState;
+
+/// `prev_hash` of the first entry.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Each entry's hash covers its fields and the previous entry's hash, so
+/// editing, reordering or deleting an entry breaks every hash after it.
+/// Truncating the tail is only detectable against a head hash kept
+/// elsewhere, which is why `audit verify` prints it.
+pub fn entry_hash(
+    prev_hash: &str,
+    at: &str,
+    actor: &str,
+    action: &str,
+    target: &str,
+    details: &str,
+) -> String {
+    // A JSON array keeps field boundaries unambiguous.
+    let input = serde_json::json!([prev_hash, at, actor, action, target, details]).to_string();
+    format!("{:x}", Sha256::digest(input.as_bytes()))
+}
+
+/// Why the chain is broken at an entry.
+pub struct Broken {
+    pub id: i64,
+    pub reason: &'static str,
+}
+
+pub fn verify(entries: &[AuditEntry]) -> Result<(), Broken> {
+    let mut expected_prev = GENESIS;
+    for entry in entries {
+        if entry.prev_hash != expected_prev {
+            return Err(Broken {
+                id: entry.id,
+                reason: "does not link to the previous entry",
+            });
+        }
+        let hash = entry_hash(
+            &entry.prev_hash,
+            &entry.at,
+            &entry.actor,
+            &entry.action,
+            &entry.target,
+            &entry.details,
+        );
+        if entry.hash != hash {
+            return Err(Broken {
+                id: entry.id,
+                reason: "was modified after it was written",
+            });
+        }
+        expected_prev = &entry.hash;
+    }
+    Ok(())
+}
+
+/// Records an action taken through the API. A failed write is logged but
+/// does not fail the request, which has already taken effect.
+pub async fn record(
+    state: &AppState,
+    actor: &str,
+    action: &str,
+    target: &str,
+    details: serde_json::Value,
+) {
+    let details = if details.is_null() {
+        String::new()
+    } else {
+        details.to_string()
+    };
+    if let Err(e) = state
+        .db
+        .append_audit(actor, action, target, &details)
+        .await
+    {
+        tracing::error!("Failed to write audit entry {action} by {actor}: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{self, AuditCommand};
+    use crate::db::Database;
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn verify_fails_once_an_entry_is_edited_or_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("koder.db");
+        let db = Database::new(&path, 1).unwrap();
+        db.initialize().await.unwrap();
+        for (action, target) in [
+            ("user.create", "u1"),
+            ("user.update", "u1"),
+            ("user.delete", "u1"),
+        ] {
+            db.append_audit("cli:root", action, target, "")
+                .await
+                .unwrap();
+        }
+        cli::audit(&db, AuditCommand::Verify).await.unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute("UPDATE audit_log SET actor = 'someone' WHERE id = 2", [])
+            .unwrap();
+        let err = cli::audit(&db, AuditCommand::Verify).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Audit log entry 2 was modified after it was written"
+        );
+
+        conn.execute("UPDATE audit_log SET actor = 'cli:root' WHERE id = 2", [])
+            .unwrap();
+        cli::audit(&db, AuditCommand::Verify).await.unwrap();
+        conn.execute("DELETE FROM audit_log WHERE id = 2", [])
+            .unwrap();
+        let err = cli::audit(&db, AuditCommand::Verify).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Audit log entry 3 does not link to the previous entry"
+        );
+    }
+}
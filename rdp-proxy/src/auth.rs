@@ -1,12 +1,18 @@
 use std::sync::Arc;
 
 use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
+use axum::http::HeaderMap;
 use axum::Json;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-
-use crate::db::User;
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::config::{AuthBackendKind, Config};
+use crate::db::{Provisioned, User, UserRow};
+use crate::error::ApiError;
+use crate::ldap::{Ldap, LdapOutcome};
+use crate::openapi::ErrorBody;
 use crate::AppState;
 
 #[derive(Serialize, Deserialize)]
@@ -15,43 +21,167 @@ pub struct Claims {
     pub username: String,
     pub role: String,
     pub exp: usize,
+    /// Set when the request was authenticated with a personal access token;
+    /// limits what the token can do. Session JWTs carry no scopes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+    /// Tokens issued while the password must be changed are only good for
+    /// `me` and `change_password`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub must_change_password: bool,
 }
 
-#[derive(Deserialize)]
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scopes {
+            None => true,
+            Some(scopes) => scopes.iter().any(|s| s == scope),
+        }
+    }
+
+    pub fn is_api_token(&self) -> bool {
+        self.scopes.is_some()
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct LoginResponse {
     pub token: String,
     pub user: User,
 }
 
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+/// Where `login` checks credentials.
+pub enum AuthBackend {
+    /// Password hashes in the local `users` table.
+    Local,
+    /// LDAP / Active Directory bind, with users provisioned locally on
+    /// first login.
+    Ldap(Box<Ldap>),
 }
 
-fn err(status: StatusCode, msg: &str) -> (StatusCode, Json<serde_json::Value>) {
-    (
-        status,
-        Json(serde_json::json!({ "error": msg })),
-    )
+impl AuthBackend {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        match config.auth.backend {
+            AuthBackendKind::Local => Ok(AuthBackend::Local),
+            AuthBackendKind::Ldap => Ok(AuthBackend::Ldap(Box::new(Ldap::new(
+                crate::ldap::LdapConfig::from_config(&config.ldap)?,
+            )))),
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        state: &AppState,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserRow>, ApiError> {
+        match self {
+            AuthBackend::Local => verify_local(state, username, password).await,
+            AuthBackend::Ldap(ldap) => {
+                let outcome = ldap.authenticate(username, password).await.map_err(|e| {
+                    tracing::warn!("LDAP authentication error: {e:#}");
+                    ApiError::DirectoryUnavailable
+                })?;
+
+                match outcome {
+                    LdapOutcome::Authenticated(entry) => {
+                        let provisioned = state
+                            .db
+                            .provision_external_user(
+                                "ldap",
+                                &entry.id,
+                                username,
+                                &entry.display_name,
+                                entry.role,
+                            )
+                            .await
+                            .map_err(ApiError::internal)?;
+                        match provisioned {
+                            Provisioned::User(user) => state
+                                .db
+                                .get_user_by_id(&user.id)
+                                .await
+                                .map_err(ApiError::internal),
+                            Provisioned::Deleted => Ok(None),
+                            Provisioned::NameTaken => {
+                                state.metrics.login("password", "conflict");
+                                tracing::warn!(
+                                    "LDAP login for {username} refused: the name belongs to another account"
+                                );
+                                Err(ApiError::AccountConflict)
+                            }
+                        }
+                    }
+                    LdapOutcome::InvalidCredentials => Ok(None),
+                    LdapOutcome::NotFound if ldap.local_fallback() => {
+                        verify_local(state, username, password).await
+                    }
+                    LdapOutcome::NotFound => Ok(None),
+                }
+            }
+        }
+    }
 }
 
-pub fn create_token(state: &AppState, user_id: &str, username: &str, role: &str) -> Result<String, jsonwebtoken::errors::Error> {
+async fn verify_local(
+    state: &AppState,
+    username: &str,
+    password: &str,
+) -> Result<Option<UserRow>, ApiError> {
+    let Some(user) = state
+        .db
+        .get_user_by_username(username)
+        .await
+        .map_err(ApiError::internal)?
+    else {
+        return Ok(None);
+    };
+
+    if !state.hasher.verify(password, &user.password_hash).await {
+        return Ok(None);
+    }
+
+    // Upgrade bcrypt hashes and hashes made with older Argon2 parameters
+    // while the plaintext is at hand. Failure here must not block the login.
+    if state.hasher.needs_rehash(&user.password_hash) {
+        match state.hasher.hash(password).await {
+            Ok(hash) => {
+                let stored = state
+                    .db
+                    .rehash_password(&user.id, &user.password_hash, &hash)
+                    .await;
+                if let Err(e) = stored {
+                    tracing::warn!("Failed to store upgraded hash for {}: {e:#}", user.username);
+                }
+            }
+            Err(e) => tracing::warn!("Failed to rehash password for {}: {e:#}", user.username),
+        }
+    }
+
+    Ok(Some(user))
+}
+
+pub fn create_token(state: &AppState, user: &User) -> Result<String, jsonwebtoken::errors::Error> {
     let exp = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::hours(24))
+        .checked_add_signed(chrono::Duration::hours(
+            state.config.auth.session_ttl_hours.into(),
+        ))
         .unwrap()
         .timestamp() as usize;
 
     let claims = Claims {
-        sub: user_id.to_string(),
-        username: username.to_string(),
-        role: role.to_string(),
+        sub: user.id.clone(),
+        username: user.username.clone(),
+        role: user.role.clone(),
         exp,
+        scopes: None,
+        must_change_password: user.must_change_password,
     };
 
     jsonwebtoken::encode(
@@ -61,99 +191,271 @@ pub fn create_token(state: &AppState, user_id: &str, username: &str, role: &str)
     )
 }
 
-pub fn extract_auth(
-    headers: &HeaderMap,
-    state: &AppState,
-) -> Result<Claims, (StatusCode, Json<serde_json::Value>)> {
-    let token = headers
+fn bearer_token(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
         .get("Authorization")
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.strip_prefix("Bearer "))
-        .ok_or_else(|| err(StatusCode::UNAUTHORIZED, "Token ausente"))?;
+        .ok_or(ApiError::MissingToken)
+}
+
+pub async fn extract_auth(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<Claims, ApiError> {
+    authenticate_token(bearer_token(headers)?, state).await
+}
+
+/// Like `extract_auth`, but also accepts users who still have to change
+/// their password.
+pub async fn extract_auth_allow_password_change(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<Claims, ApiError> {
+    decode_token(bearer_token(headers)?, state).await
+}
+
+/// Validates a session JWT or personal access token. Used directly where
+/// the token does not arrive in an `Authorization` header.
+pub async fn authenticate_token(
+    token: &str,
+    state: &AppState,
+) -> Result<Claims, ApiError> {
+    let claims = decode_token(token, state).await?;
+    if claims.must_change_password {
+        return Err(ApiError::PasswordChangeRequired);
+    }
+    Ok(claims)
+}
+
+async fn decode_token(
+    token: &str,
+    state: &AppState,
+) -> Result<Claims, ApiError> {
+    if token.starts_with(crate::tokens::TOKEN_PREFIX) {
+        let owner = state
+            .db
+            .get_api_token_owner(&crate::tokens::hash_token(token))
+            .await
+            .map_err(ApiError::internal)?
+            .ok_or(ApiError::InvalidToken)?;
+
+        return Ok(Claims {
+            sub: owner.user_id,
+            username: owner.username,
+            role: owner.role,
+            exp: owner.expires_at as usize,
+            scopes: Some(owner.scopes),
+            must_change_password: owner.must_change_password,
+        });
+    }
 
     let data = jsonwebtoken::decode::<Claims>(
         token,
         &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
         &Validation::default(),
     )
-    .map_err(|_| err(StatusCode::UNAUTHORIZED, "Token inválido ou expirado"))?;
+    .map_err(|_| ApiError::InvalidToken)?;
 
-    Ok(data.claims)
+    // Sessions are stateless, so check the account is still usable, and
+    // authorize with its current role rather than the one it logged in with.
+    let user = state
+        .db
+        .get_user_by_id(&data.claims.sub)
+        .await
+        .map_err(ApiError::internal)?
+        .filter(|u| u.can_log_in())
+        .ok_or(ApiError::AccountUnavailable)?;
+
+    Ok(Claims {
+        role: user.role,
+        ..data.claims
+    })
 }
 
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    security(()),
+    request_body = LoginRequest,
+    responses(
+        (status = 200, body = LoginResponse),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+        (status = 502, body = ErrorBody),
+    )
+)]
 pub async fn login(
     State(state): State<Arc<AppState>>,
     Json(body): Json<LoginRequest>,
-) -> Result<Json<LoginResponse>, (StatusCode, Json<serde_json::Value>)> {
-    let user = state
-        .db
-        .get_user_by_username(&body.username)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?
-        .ok_or_else(|| err(StatusCode::UNAUTHORIZED, "Usuário ou senha incorretos"))?;
+) -> Result<Json<LoginResponse>, ApiError> {
+    let Some(user) = state
+        .auth_backend
+        .authenticate(&state, &body.username, &body.password)
+        .await?
+    else {
+        state.metrics.login("password", "failure");
+        audit::record(
+            &state,
+            &body.username,
+            "auth.login_failed",
+            "",
+            serde_json::Value::Null,
+        )
+        .await;
+        return Err(ApiError::InvalidCredentials);
+    };
 
-    let valid = bcrypt::verify(&body.password, &user.password_hash)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?;
+    if !user.enabled {
+        state.metrics.login("password", "disabled");
+        audit::record(
+            &state,
+            &user.username,
+            "auth.login_rejected",
+            &user.id,
+            serde_json::json!({ "reason": "disabled" }),
+        )
+        .await;
+        return Err(ApiError::UserDisabled);
+    }
 
-    if !valid {
-        return Err(err(StatusCode::UNAUTHORIZED, "Usuário ou senha incorretos"));
+    if user.expired {
+        state.metrics.login("password", "expired");
+        audit::record(
+            &state,
+            &user.username,
+            "auth.login_rejected",
+            &user.id,
+            serde_json::json!({ "reason": "expired" }),
+        )
+        .await;
+        return Err(ApiError::UserExpired);
     }
 
-    let token = create_token(&state, &user.id, &user.username, &user.role)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar token"))?;
+    if let Err(e) = state.db.record_login(&user.id).await {
+        tracing::warn!("Failed to record login of {}: {e:#}", user.username);
+    }
+    let user = user.to_public();
+    state.metrics.login("password", "success");
+    audit::record(&state, &user.username, "auth.login", &user.id, serde_json::Value::Null).await;
+    let token = create_token(&state, &user)
+        .map_err(ApiError::internal)?;
 
-    Ok(Json(LoginResponse {
-        token,
-        user: user.to_public(),
-    }))
+    Ok(Json(LoginResponse { token, user }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/auth/me",
+    tag = "auth",
+    responses(
+        (status = 200, body = User),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
 pub async fn me(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
-) -> Result<Json<User>, (StatusCode, Json<serde_json::Value>)> {
-    let claims = extract_auth(&headers, &state)?;
+) -> Result<Json<User>, ApiError> {
+    let claims = extract_auth_allow_password_change(&headers, &state).await?;
+    crate::rbac::require_scope(&claims, crate::rbac::PROFILE_READ)?;
 
     let user = state
         .db
         .get_user_by_id(&claims.sub)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?
-        .ok_or_else(|| err(StatusCode::NOT_FOUND, "Usuário não encontrado"))?;
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?;
 
     Ok(Json(user.to_public()))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct ChangePasswordRequest {
     pub current_password: String,
     pub new_password: String,
 }
 
+#[utoipa::path(
+    put,
+    path = "/auth/password",
+    tag = "auth",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, body = LoginResponse),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
 pub async fn change_password(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Json(body): Json<ChangePasswordRequest>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
-    let claims = extract_auth(&headers, &state)?;
+) -> Result<Json<LoginResponse>, ApiError> {
+    let claims = extract_auth_allow_password_change(&headers, &state).await?;
+    if claims.is_api_token() {
+        return Err(ApiError::ApiTokenNotAllowed);
+    }
 
     let user = state
         .db
         .get_user_by_id(&claims.sub)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?
-        .ok_or_else(|| err(StatusCode::NOT_FOUND, "Usuário não encontrado"))?;
-
-    let valid = bcrypt::verify(&body.current_password, &user.password_hash)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?;
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?;
+
+    if !state
+        .hasher
+        .verify(&body.current_password, &user.password_hash)
+        .await
+    {
+        return Err(ApiError::WrongPassword);
+    }
 
-    if !valid {
-        return Err(err(StatusCode::BAD_REQUEST, "Senha atual incorreta"));
+    if body.new_password == body.current_password {
+        return Err(ApiError::SamePassword);
     }
 
+    crate::password_policy::enforce(&state, &user.username, &body.new_password, Some(&claims.sub))
+        .await?;
+
+    let hash = state
+        .hasher
+        .hash(&body.new_password)
+        .await
+        .map_err(ApiError::internal)?;
     state
         .db
-        .update_password(&claims.sub, &body.new_password)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro ao atualizar senha"))?;
+        .update_password(&claims.sub, &hash)
+        .await
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "auth.password_change",
+        &claims.sub,
+        serde_json::Value::Null,
+    )
+    .await;
 
-    Ok(Json(serde_json::json!({ "ok": true })))
+    // The old token still carries the pending-change flag; hand out a fresh one.
+    let user = state
+        .db
+        .get_user_by_id(&claims.sub)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?
+        .to_public();
+    let token = create_token(&state, &user)
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(LoginResponse { token, user }))
 }
 
 mod chrono {
@@ -188,3 +490,50 @@ mod chrono {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::rbac::{self, PROFILE_READ};
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn api_tokens_need_a_scope_for_what_any_user_may_do() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let state = Arc::new(state);
+        let user = test_support::user(&state, "alice", "Alice-Pass-123!", "admin").await;
+
+        let session = test_support::session_headers(&state, &user);
+        let Json(me_user) = me(State(state.clone()), session).await.unwrap();
+        assert_eq!(me_user.username, "alice");
+
+        let unscoped = test_support::token_headers(&state, &user, &[rbac::USERS_READ]).await;
+        let denied = me(State(state.clone()), unscoped.clone()).await;
+        assert!(matches!(denied, Err(ApiError::Forbidden)));
+        let claims = extract_auth(&unscoped, &state).await.unwrap();
+        assert!(rbac::require_scope(&claims, rbac::RDP_CONNECT).is_err());
+
+        let scoped = test_support::token_headers(&state, &user, &[PROFILE_READ]).await;
+        assert!(me(State(state.clone()), scoped).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn an_empty_new_password_fails_the_policy() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let state = Arc::new(state);
+        let user = test_support::user(&state, "alice", "Alice-Pass-123!", "user").await;
+
+        let body = ChangePasswordRequest {
+            current_password: "Alice-Pass-123!".to_string(),
+            new_password: String::new(),
+        };
+        let headers = test_support::session_headers(&state, &user);
+        let Err(ApiError::PasswordPolicy(violations)) =
+            change_password(State(state.clone()), headers, Json(body)).await
+        else {
+            panic!("an empty password was not refused by the policy");
+        };
+        assert_eq!(violations[0].code(), "password.too_short");
+    }
+}
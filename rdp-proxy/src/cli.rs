@@ -0,0 +1,284 @@
+use std::io::BufRead as _;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context as _, Result};
+use clap::Subcommand;
+
+use crate::config::Config;
+use crate::db::{UserChanges, UserQuery, UserRow};
+use crate::password::Hasher;
+use crate::storage::{is_unique_violation, Storage};
+
+/// Administrative commands that work on the database directly, without a
+/// running server or an admin login, e.g. through `docker exec`.
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user. Prints a temporary password unless --password-stdin
+    /// is given; either way it must be changed at first login.
+    Add {
+        username: String,
+        #[arg(long, default_value = "user")]
+        role: String,
+        #[arg(long, default_value = "")]
+        display_name: String,
+        /// Read the password from the first line of stdin.
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// List all users.
+    List,
+    /// Set a new password that must be changed at next login.
+    ResetPassword {
+        username: String,
+        /// Read the password from the first line of stdin.
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Change a user's role.
+    SetRole { username: String, role: String },
+    /// Block a user from logging in and invalidate their sessions and tokens.
+    Disable { username: String },
+    /// Allow a disabled user to log in again.
+    Enable { username: String },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Delete every API token, or only those of one user.
+    RevokeAll {
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Write a consistent copy of the SQLite database to DEST.
+    Backup { dest: PathBuf },
+    /// Apply pending schema migrations.
+    Migrate {
+        /// Only list the pending migrations.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommand {
+    /// Check that no audit log entry was altered or removed.
+    Verify,
+}
+
+/// Audit actor for changes made from the command line.
+fn actor() -> String {
+    match std::env::var("USER") {
+        Ok(user) if !user.is_empty() => format!("cli:{user}"),
+        _ => "cli".to_string(),
+    }
+}
+
+async fn find_user(db: &dyn Storage, username: &str) -> Result<UserRow> {
+    db.get_user_by_username(username)
+        .await?
+        .with_context(|| format!("No user named {username}"))
+}
+
+async fn ensure_role(db: &dyn Storage, role: &str) -> Result<()> {
+    if !db.role_exists(role).await? {
+        bail!("No role named {role}");
+    }
+    Ok(())
+}
+
+/// Reads the password from stdin, or generates a temporary one and prints it.
+fn new_password(from_stdin: bool) -> Result<String> {
+    if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        let password = line.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            bail!("Empty password on stdin");
+        }
+        return Ok(password);
+    }
+    let password = uuid::Uuid::new_v4().simple().to_string();
+    println!("Temporary password: {password}");
+    Ok(password)
+}
+
+pub async fn user(db: &dyn Storage, config: &Config, command: UserCommand) -> Result<()> {
+    let hasher = Hasher::new(&config.argon2)?;
+    match command {
+        UserCommand::Add {
+            username,
+            role,
+            display_name,
+            password_stdin,
+        } => {
+            ensure_role(db, &role).await?;
+            if db.get_user_by_username(&username).await?.is_some() {
+                bail!("User {username} already exists");
+            }
+            let hash = hasher.hash(&new_password(password_stdin)?).await?;
+            let user = db
+                .create_user(&username, &hash, &display_name, &role, true, None)
+                .await
+                .map_err(|e| {
+                    if is_unique_violation(&e) {
+                        anyhow::anyhow!("User {username} was deleted; purge it to reuse the name")
+                    } else {
+                        e
+                    }
+                })?;
+            let details = serde_json::json!({ "username": username, "role": role });
+            db.append_audit(&actor(), "user.create", &user.id, &details.to_string())
+                .await?;
+            println!("Created {username} ({role})");
+        }
+        UserCommand::List => {
+            println!(
+                "{:<24} {:<12} {:<8} {:<14} {:<19} {:<19} CREATED",
+                "USERNAME", "ROLE", "ENABLED", "MUST_CHANGE_PW", "EXPIRES", "LAST_LOGIN"
+            );
+            for user in db.list_users(&UserQuery::default()).await?.users {
+                println!(
+                    "{:<24} {:<12} {:<8} {:<14} {:<19} {:<19} {}",
+                    user.username,
+                    user.role,
+                    user.enabled,
+                    user.must_change_password,
+                    user.expires_at.as_deref().unwrap_or("-"),
+                    user.last_login_at.as_deref().unwrap_or("-"),
+                    user.created_at
+                );
+            }
+        }
+        UserCommand::ResetPassword {
+            username,
+            password_stdin,
+        } => {
+            let user = find_user(db, &username).await?;
+            let hash = hasher.hash(&new_password(password_stdin)?).await?;
+            let changes = UserChanges {
+                password_hash: Some(hash),
+                ..Default::default()
+            };
+            db.update_user(&user.id, &changes).await?;
+            let details = serde_json::json!({ "password_reset": true });
+            db.append_audit(&actor(), "user.update", &user.id, &details.to_string())
+                .await?;
+            println!("Password reset for {username}; it must be changed at next login");
+            if !user.enabled {
+                println!("Note: {username} is disabled; run `user enable {username}` as well");
+            }
+        }
+        UserCommand::SetRole { username, role } => {
+            ensure_role(db, &role).await?;
+            let user = find_user(db, &username).await?;
+            let changes = UserChanges {
+                role: Some(role.clone()),
+                ..Default::default()
+            };
+            db.update_user(&user.id, &changes).await?;
+            let details = serde_json::json!({ "role": role });
+            db.append_audit(&actor(), "user.update", &user.id, &details.to_string())
+                .await?;
+            println!("{username} now has role {role}");
+        }
+        UserCommand::Disable { username } => {
+            let user = find_user(db, &username).await?;
+            let changes = UserChanges {
+                enabled: Some(false),
+                ..Default::default()
+            };
+            db.update_user(&user.id, &changes).await?;
+            db.append_audit(&actor(), "user.disable", &user.id, "")
+                .await?;
+            println!("Disabled {username}");
+        }
+        UserCommand::Enable { username } => {
+            let user = find_user(db, &username).await?;
+            let changes = UserChanges {
+                enabled: Some(true),
+                ..Default::default()
+            };
+            db.update_user(&user.id, &changes).await?;
+            db.append_audit(&actor(), "user.enable", &user.id, "")
+                .await?;
+            println!("Enabled {username}");
+        }
+    }
+    Ok(())
+}
+
+pub async fn token(db: &dyn Storage, command: TokenCommand) -> Result<()> {
+    match command {
+        TokenCommand::RevokeAll { user } => {
+            let user_id = match &user {
+                Some(username) => Some(find_user(db, username).await?.id),
+                None => None,
+            };
+            let count = db.revoke_api_tokens(user_id.as_deref()).await?;
+            db.append_audit(
+                &actor(),
+                "token.revoke_all",
+                user_id.as_deref().unwrap_or(""),
+                &serde_json::json!({ "count": count }).to_string(),
+            )
+            .await?;
+            println!("Revoked {count} token(s)");
+        }
+    }
+    Ok(())
+}
+
+pub async fn database(db: &dyn Storage, command: DbCommand) -> Result<()> {
+    match command {
+        DbCommand::Backup { dest } => {
+            if dest.exists() {
+                bail!("{} already exists", dest.display());
+            }
+            db.backup(&dest).await?;
+            println!("Backed up to {}", dest.display());
+        }
+        DbCommand::Migrate { dry_run } => migrate(db, dry_run).await?,
+    }
+    Ok(())
+}
+
+/// Applies pending schema migrations, or only lists them.
+pub async fn migrate(db: &dyn Storage, dry_run: bool) -> Result<()> {
+    let pending = db.pending_migrations().await?;
+    if pending.is_empty() {
+        println!("Schema is up to date");
+        return Ok(());
+    }
+    for migration in &pending {
+        println!("pending: {migration}");
+    }
+    if !dry_run {
+        db.initialize().await?;
+        println!("Applied {} migration(s)", pending.len());
+    }
+    Ok(())
+}
+
+pub async fn audit(db: &dyn Storage, command: AuditCommand) -> Result<()> {
+    match command {
+        AuditCommand::Verify => {
+            let entries = db.list_audit().await?;
+            if let Err(broken) = crate::audit::verify(&entries) {
+                bail!("Audit log entry {} {}", broken.id, broken.reason);
+            }
+            match entries.last() {
+                Some(last) => println!(
+                    "Audit log intact: {} entries, head {}",
+                    entries.len(),
+                    last.hash
+                ),
+                None => println!("Audit log is empty"),
+            }
+        }
+    }
+    Ok(())
+}
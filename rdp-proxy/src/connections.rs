@@ -0,0 +1,289 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::auth::{extract_auth, Claims};
+use crate::db::ConnectionProfile;
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::rbac::{self, require_permission};
+use crate::AppState;
+
+/// Splits `host:port`, accepting bracketed IPv6 literals.
+fn split_destination(destination: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = destination.strip_prefix('[') {
+        if let Some((host, tail)) = rest.split_once(']') {
+            return (host, tail.strip_prefix(':'));
+        }
+    }
+    match destination.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (destination, None),
+    }
+}
+
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// Matches a destination against a rule such as `10.0.0.*:3389`,
+/// `*.corp.local` (any port) or `rdp01:*`. Host comparison ignores case.
+pub fn destination_matches(pattern: &str, destination: &str) -> bool {
+    let (pat_host, pat_port) = split_destination(pattern);
+    let (host, port) = split_destination(destination);
+
+    if !glob_match(&pat_host.to_ascii_lowercase(), &host.to_ascii_lowercase()) {
+        return false;
+    }
+    match pat_port {
+        None | Some("*") => true,
+        Some(p) => port.unwrap_or("3389") == p,
+    }
+}
+
+/// Whether the user may open a session to `destination`. Holders of
+/// `connections:admin` may reach anything; everyone else needs a matching
+/// rule or connection in one of their groups.
+pub async fn destination_allowed(
+    state: &AppState,
+    claims: &Claims,
+    destination: &str,
+) -> Result<bool, ApiError> {
+    if rbac::has_permission(state, claims, rbac::CONNECTIONS_ADMIN).await? {
+        return Ok(true);
+    }
+    let patterns = state
+        .db
+        .destination_patterns_for_user(&claims.sub)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(patterns.iter().any(|p| destination_matches(p, destination)))
+}
+
+async fn validate_groups(state: &AppState, group_ids: &[String]) -> Result<(), ApiError> {
+    for id in group_ids {
+        state
+            .db
+            .get_group(id)
+            .await
+            .map_err(ApiError::internal)?
+            .ok_or(ApiError::UnknownGroup)?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/connections",
+    tag = "connections",
+    responses(
+        (status = 200, body = [ConnectionProfile]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_connections(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ConnectionProfile>>, ApiError> {
+    let claims = extract_auth(&headers, &state).await?;
+    if !claims.has_scope(rbac::CONNECTIONS_ADMIN) {
+        rbac::require_scope(&claims, rbac::CONNECTIONS_READ)?;
+    }
+
+    let connections = if rbac::has_permission(&state, &claims, rbac::CONNECTIONS_ADMIN).await? {
+        state.db.list_connections().await
+    } else {
+        state.db.list_connections_for_user(&claims.sub).await
+    }
+    .map_err(ApiError::internal)?;
+
+    Ok(Json(connections))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateConnectionRequest {
+    pub name: String,
+    pub protocol: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub group_ids: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/connections",
+    tag = "connections",
+    request_body = CreateConnectionRequest,
+    responses(
+        (status = 201, body = ConnectionProfile),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn create_connection(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateConnectionRequest>,
+) -> Result<(StatusCode, Json<ConnectionProfile>), ApiError> {
+    let claims = require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    if body.name.trim().is_empty() || body.host.trim().is_empty() {
+        return Err(ApiError::ConnectionFieldsRequired);
+    }
+
+    let protocol = body.protocol.as_deref().unwrap_or("rdp");
+    if protocol != "rdp" && protocol != "ssh" {
+        return Err(ApiError::InvalidProtocol);
+    }
+    let port = body
+        .port
+        .unwrap_or(if protocol == "ssh" { 22 } else { 3389 });
+
+    validate_groups(&state, &body.group_ids).await?;
+
+    let connection = state
+        .db
+        .create_connection(
+            body.name.trim(),
+            protocol,
+            body.host.trim(),
+            port,
+            &body.group_ids,
+        )
+        .await
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "connection.create",
+        &connection.id,
+        serde_json::json!({ "host": connection.host, "port": connection.port }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(connection)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateConnectionRequest {
+    pub name: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub group_ids: Option<Vec<String>>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/connections/{id}",
+    tag = "connections",
+    params(("id" = String, Path)),
+    request_body = UpdateConnectionRequest,
+    responses(
+        (status = 200, body = ConnectionProfile),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn update_connection(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<UpdateConnectionRequest>,
+) -> Result<Json<ConnectionProfile>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    if let Some(ref groups) = body.group_ids {
+        validate_groups(&state, groups).await?;
+    }
+
+    let connection = state
+        .db
+        .update_connection(
+            &id,
+            body.name.as_deref(),
+            body.host.as_deref(),
+            body.port,
+            body.group_ids.as_deref(),
+        )
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::ConnectionNotFound)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "connection.update",
+        &id,
+        serde_json::json!({ "host": connection.host, "port": connection.port }),
+    )
+    .await;
+
+    Ok(Json(connection))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/connections/{id}",
+    tag = "connections",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn delete_connection(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    let deleted = state
+        .db
+        .delete_connection(&id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    if !deleted {
+        return Err(ApiError::ConnectionNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "connection.delete",
+        &id,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
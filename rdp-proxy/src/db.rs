@@ -1,207 +1,1561 @@
-use anyhow::Result;
-use rusqlite::Connection;
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
 use serde::Serialize;
-use std::sync::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info_span, Instrument as _};
+use utoipa::ToSchema;
 
-#[derive(Clone, Serialize)]
+use crate::storage::Storage;
+
+#[derive(Clone, Serialize, ToSchema)]
 pub struct User {
     pub id: String,
     pub username: String,
     pub display_name: String,
     pub role: String,
+    pub must_change_password: bool,
+    pub enabled: bool,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`. The account cannot log in from then on.
+    pub expires_at: Option<String>,
+    pub last_login_at: Option<String>,
+    /// `local`, or the identity provider that vouches for the user (`ldap`,
+    /// `oidc`).
+    pub auth_source: String,
     pub created_at: String,
     pub updated_at: String,
 }
 
+/// Outcome of `Storage::provision_external_user`.
+pub enum Provisioned {
+    User(Box<User>),
+    /// The account linked to the identity was deleted.
+    Deleted,
+    /// The username belongs to an account the identity is not linked to.
+    NameTaken,
+}
+
+/// Sort keys for `Storage::list_users`. Ties are broken by id, so pages
+/// never skip or repeat users.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UserSort {
+    Username,
+    DisplayName,
+    #[default]
+    CreatedAt,
+}
+
+impl UserSort {
+    pub fn name(self) -> &'static str {
+        match self {
+            UserSort::Username => "username",
+            UserSort::DisplayName => "display_name",
+            UserSort::CreatedAt => "created_at",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            UserSort::Username,
+            UserSort::DisplayName,
+            UserSort::CreatedAt,
+        ]
+        .into_iter()
+        .find(|s| s.name() == name)
+    }
+
+    /// `user`'s value for this key, as stored in a page cursor.
+    pub fn key(self, user: &User) -> &str {
+        match self {
+            UserSort::Username => &user.username,
+            UserSort::DisplayName => &user.display_name,
+            UserSort::CreatedAt => &user.created_at,
+        }
+    }
+}
+
+/// Filters and page position for `Storage::list_users`. The default lists
+/// every user that has not been deleted, oldest first.
+#[derive(Debug, Default)]
+pub struct UserQuery {
+    /// Case-insensitive substring of the username or display name.
+    pub search: Option<String>,
+    pub role: Option<String>,
+    pub sort: UserSort,
+    pub descending: bool,
+    pub limit: Option<usize>,
+    /// Sort key and id of the last user on the previous page.
+    pub after: Option<(String, String)>,
+}
+
+/// Fields for `Storage::update_user` to change; `None` leaves one as it is.
+#[derive(Clone, Debug, Default)]
+pub struct UserChanges {
+    pub display_name: Option<String>,
+    pub role: Option<String>,
+    /// Also makes the user choose a new password at their next login.
+    pub password_hash: Option<String>,
+    pub enabled: Option<bool>,
+    /// `Some(None)` removes the expiry.
+    pub expires_at: Option<Option<String>>,
+}
+
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// Users matching `search` and `role`, on every page.
+    pub total: i64,
+}
+
+/// What user search matches against, kept in `username_search` and
+/// `display_name_search`. SQL `LOWER()` would not do: SQLite's folds ASCII
+/// only and Postgres's depends on the database locale.
+pub fn search_text(text: &str) -> String {
+    text.to_lowercase()
+}
+
+/// `UserQuery` as SQL for either backend.
+pub struct UserQuerySql {
+    pub select: String,
+    pub count: String,
+    pub params: Vec<String>,
+    /// How many of `params` belong to `count`.
+    pub count_params: usize,
+}
+
+impl UserQuery {
+    /// `placeholder(n)` renders the backend's n-th (1-based) parameter.
+    pub fn to_sql(&self, columns: &str, placeholder: fn(usize) -> String) -> UserQuerySql {
+        let mut conditions = vec!["deleted_at IS NULL".to_string()];
+        let mut params = Vec::new();
+        if let Some(search) = &self.search {
+            let escaped = search_text(search)
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            params.push(format!("%{escaped}%"));
+            let p = placeholder(params.len());
+            conditions.push(format!(
+                "(username_search LIKE {p} ESCAPE '\\' OR display_name_search LIKE {p} ESCAPE '\\')"
+            ));
+        }
+        if let Some(role) = &self.role {
+            params.push(role.clone());
+            conditions.push(format!("role = {}", placeholder(params.len())));
+        }
+        let where_clause = |conditions: &[String]| format!(" WHERE {}", conditions.join(" AND "));
+        let count = format!("SELECT COUNT(*) FROM users{}", where_clause(&conditions));
+        let count_params = params.len();
+
+        let column = self.sort.name();
+        let (cmp, direction) = if self.descending {
+            ("<", "DESC")
+        } else {
+            (">", "ASC")
+        };
+        if let Some((key, id)) = &self.after {
+            params.push(key.clone());
+            let k = placeholder(params.len());
+            params.push(id.clone());
+            let i = placeholder(params.len());
+            conditions.push(format!(
+                "({column} {cmp} {k} OR ({column} = {k} AND id {cmp} {i}))"
+            ));
+        }
+        let mut select = format!(
+            "SELECT {columns} FROM users{} ORDER BY {column} {direction}, id {direction}",
+            where_clause(&conditions)
+        );
+        if let Some(limit) = self.limit {
+            select.push_str(&format!(" LIMIT {limit}"));
+        }
+
+        UserQuerySql {
+            select,
+            count,
+            params,
+            count_params,
+        }
+    }
+}
+
 pub struct UserRow {
     pub id: String,
     pub username: String,
     pub password_hash: String,
     pub display_name: String,
     pub role: String,
+    pub must_change_password: bool,
+    pub enabled: bool,
+    pub expires_at: Option<String>,
+    pub last_login_at: Option<String>,
+    /// Whether `expires_at` has passed, by the database's clock.
+    pub expired: bool,
+    pub auth_source: String,
     pub created_at: String,
     pub updated_at: String,
 }
 
 impl UserRow {
+    /// Enabled and not expired. Deleted users are never loaded.
+    pub fn can_log_in(&self) -> bool {
+        self.enabled && !self.expired
+    }
+
     pub fn to_public(&self) -> User {
         User {
             id: self.id.clone(),
             username: self.username.clone(),
             display_name: self.display_name.clone(),
             role: self.role.clone(),
+            must_change_password: self.must_change_password,
+            enabled: self.enabled,
+            expires_at: self.expires_at.clone(),
+            last_login_at: self.last_login_at.clone(),
+            auth_source: self.auth_source.clone(),
             created_at: self.created_at.clone(),
             updated_at: self.updated_at.clone(),
         }
     }
 }
 
+#[derive(Clone, Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub builtin: bool,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub member_count: i64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct ConnectionProfile {
+    pub id: String,
+    pub name: String,
+    pub protocol: String,
+    pub host: String,
+    pub port: u16,
+    pub group_ids: Vec<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct DestinationRule {
+    pub id: String,
+    pub group_id: String,
+    pub pattern: String,
+    pub created_at: String,
+}
+
+/// One link of the hash-chained audit log; see `audit.rs`.
+#[derive(Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: String,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub details: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+/// An API token resolved to its owner, for authenticating a request.
+pub struct ApiTokenOwner {
+    pub token_id: String,
+    pub scopes: Vec<String>,
+    pub expires_at: i64,
+    pub user_id: String,
+    pub username: String,
+    pub role: String,
+    pub must_change_password: bool,
+}
+
+const USER_COLUMNS: &str =
+    "id, username, password_hash, display_name, role, must_change_password, created_at, updated_at, enabled,
+     expires_at, last_login_at, COALESCE(expires_at <= datetime('now'), 0), auth_source";
+
+fn map_user_row(row: &rusqlite::Row) -> rusqlite::Result<UserRow> {
+    Ok(UserRow {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        password_hash: row.get(2)?,
+        display_name: row.get(3)?,
+        role: row.get(4)?,
+        must_change_password: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+        enabled: row.get(8)?,
+        expires_at: row.get(9)?,
+        last_login_at: row.get(10)?,
+        expired: row.get(11)?,
+        auth_source: row.get(12)?,
+    })
+}
+
+fn map_api_token(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(2)?;
+    Ok(ApiToken {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: scopes.split_whitespace().map(str::to_string).collect(),
+        expires_at: row.get(3)?,
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+    })
+}
+
+fn map_role(row: &rusqlite::Row) -> rusqlite::Result<Role> {
+    let permissions: String = row.get(3)?;
+    Ok(Role {
+        name: row.get(0)?,
+        description: row.get(1)?,
+        builtin: row.get(2)?,
+        permissions: permissions.split_whitespace().map(str::to_string).collect(),
+    })
+}
+
+fn map_group(row: &rusqlite::Row) -> rusqlite::Result<Group> {
+    Ok(Group {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        member_count: row.get(3)?,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+fn map_connection(row: &rusqlite::Row) -> rusqlite::Result<ConnectionProfile> {
+    let group_ids: String = row.get(5)?;
+    Ok(ConnectionProfile {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        protocol: row.get(2)?,
+        host: row.get(3)?,
+        port: row.get(4)?,
+        group_ids: group_ids.split_whitespace().map(str::to_string).collect(),
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+fn map_destination_rule(row: &rusqlite::Row) -> rusqlite::Result<DestinationRule> {
+    Ok(DestinationRule {
+        id: row.get(0)?,
+        group_id: row.get(1)?,
+        pattern: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+const GROUP_COLUMNS: &str = "g.id, g.name, g.description,
+    (SELECT COUNT(*) FROM group_members m WHERE m.group_id = g.id), g.created_at, g.updated_at";
+
+const CONNECTION_COLUMNS: &str = "c.id, c.name, c.protocol, c.host, c.port,
+    COALESCE((SELECT group_concat(gc.group_id, ' ') FROM group_connections gc WHERE gc.connection_id = c.id), ''),
+    c.created_at, c.updated_at";
+
+/// A fixed set of SQLite connections. WAL lets readers run alongside the
+/// single writer, and `busy_timeout` queues competing writers instead of
+/// failing them. Queries run on the blocking thread pool so they never
+/// stall the async workers that relay RDP traffic.
+#[derive(Clone)]
 pub struct Database {
-    conn: Mutex<Connection>,
+    pool: Arc<Pool>,
+}
+
+struct Pool {
+    conns: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+/// A connection taken from the pool with the permit that reserved it.
+/// Dropping it puts the connection back before releasing the permit, also
+/// when the query using it panicked.
+struct PooledConn {
+    conn: Option<Connection>,
+    pool: Arc<Pool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    fn checkout(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> PooledConn {
+        let conn = self
+            .conns
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit guarantees a free connection");
+        PooledConn {
+            conn: Some(conn),
+            pool: self.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+impl Deref for PooledConn {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.conns.lock().unwrap().push(conn);
+        }
+    }
+}
+
+/// Appends to `password_history`, keeping only the newest entries.
+fn record_password(conn: &Connection, user_id: &str, hash: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO password_history (user_id, password_hash) VALUES (?1, ?2)",
+        (user_id, hash),
+    )?;
+    conn.execute(
+        "DELETE FROM password_history WHERE user_id = ?1 AND id NOT IN (
+             SELECT id FROM password_history WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2
+         )",
+        (user_id, crate::password_policy::HISTORY_MAX as i64),
+    )?;
+    Ok(())
+}
+
+fn user_by_id(conn: &Connection, id: &str) -> Result<Option<UserRow>> {
+    let mut stmt = conn.prepare(
+        &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1 AND deleted_at IS NULL"),
+    )?;
+    let mut rows = stmt.query_map([id], map_user_row)?;
+    Ok(rows.next().transpose()?)
+}
+
+fn group_by_id(conn: &Connection, id: &str) -> Result<Option<Group>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {GROUP_COLUMNS} FROM groups g WHERE g.id = ?1"
+    ))?;
+    let mut rows = stmt.query_map([id], map_group)?;
+    Ok(rows.next().transpose()?)
+}
+
+fn connection_by_id(conn: &Connection, id: &str) -> Result<Option<ConnectionProfile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {CONNECTION_COLUMNS} FROM connections c WHERE c.id = ?1"
+    ))?;
+    let mut rows = stmt.query_map([id], map_connection)?;
+    Ok(rows.next().transpose()?)
+}
+
+/// Applies `changes` in one statement. Callers hold a transaction, since a
+/// new password is also recorded in the history.
+fn update_user_fields(conn: &Connection, id: &str, changes: &UserChanges) -> Result<Option<User>> {
+    let rows = conn.execute(
+        "UPDATE users SET
+            display_name = COALESCE(?1, display_name),
+            display_name_search = COALESCE(?2, display_name_search),
+            role = COALESCE(?3, role),
+            password_hash = COALESCE(?4, password_hash),
+            must_change_password = CASE WHEN ?4 IS NULL THEN must_change_password ELSE 1 END,
+            enabled = COALESCE(?5, enabled),
+            expires_at = CASE WHEN ?6 THEN ?7 ELSE expires_at END,
+            updated_at = datetime('now')
+         WHERE id = ?8 AND deleted_at IS NULL",
+        rusqlite::params![
+            changes.display_name,
+            changes.display_name.as_deref().map(search_text),
+            changes.role,
+            changes.password_hash,
+            changes.enabled,
+            changes.expires_at.is_some(),
+            changes.expires_at.clone().flatten(),
+            id,
+        ],
+    )?;
+    if rows == 0 {
+        return Ok(None);
+    }
+    if let Some(hash) = &changes.password_hash {
+        record_password(conn, id, hash)?;
+    }
+    Ok(user_by_id(conn, id)?.map(|u| u.to_public()))
+}
+
+fn insert_user(
+    conn: &Connection,
+    username: &str,
+    password_hash: &str,
+    display_name: &str,
+    role: &str,
+    must_change_password: bool,
+    expires_at: Option<&str>,
+) -> Result<User> {
+    let id = uuid::Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO users (id, username, password_hash, display_name, role, must_change_password,
+                            expires_at, username_search, display_name_search)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            &id,
+            username,
+            password_hash,
+            display_name,
+            role,
+            must_change_password,
+            expires_at,
+            search_text(username),
+            search_text(display_name),
+        ),
+    )?;
+    record_password(conn, &id, password_hash)?;
+    Ok(user_by_id(conn, &id)?.unwrap().to_public())
 }
 
 impl Database {
-    pub fn new(path: &str) -> Result<Self> {
-        if let Some(parent) = std::path::Path::new(path).parent() {
+    /// Opens `size` connections to `path`.
+    pub fn new(path: &std::path::Path, size: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
-        let conn = Connection::open(path)?;
-        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+        let mut conns = Vec::with_capacity(size);
+        for _ in 0..size {
+            let conn = Connection::open(path)?;
+            conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+            conn.busy_timeout(std::time::Duration::from_secs(5))?;
+            conns.push(conn);
+        }
         Ok(Database {
-            conn: Mutex::new(conn),
-        })
-    }
-
-    pub fn initialize(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT UNIQUE NOT NULL,
-                password_hash TEXT NOT NULL,
-                display_name TEXT NOT NULL DEFAULT '',
-                role TEXT NOT NULL DEFAULT 'user',
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
-            )",
-        )?;
-
-        let count: i64 =
-            conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
-        if count == 0 {
-            let id = uuid::Uuid::new_v4().to_string();
-            let password_hash =
-                bcrypt::hash("Koder@123", bcrypt::DEFAULT_COST)?;
-            conn.execute(
-                "INSERT INTO users (id, username, password_hash, display_name, role) VALUES (?1, ?2, ?3, ?4, ?5)",
-                (&id, "root", &password_hash, "Administrador", "admin"),
+            pool: Arc::new(Pool {
+                conns: Mutex::new(conns),
+                permits: Arc::new(Semaphore::new(size)),
+            }),
+        })
+    }
+
+    /// Runs `f` on a pooled connection in `spawn_blocking`, in a span named
+    /// after `operation`. The connection goes back to the pool from the
+    /// blocking task itself, so it is not lost if the caller's future is
+    /// dropped or `f` panics.
+    async fn with_conn<T, F>(&self, operation: &'static str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let span = info_span!("db.query", db.system = "sqlite", db.operation = operation);
+        let permit = self
+            .pool
+            .permits
+            .clone()
+            .acquire_owned()
+            .instrument(span.clone())
+            .await?;
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            let mut conn = pool.checkout(permit);
+            f(&mut conn)
+        })
+        .await?
+    }
+
+}
+
+#[async_trait]
+impl Storage for Database {
+    async fn pending_migrations(&self) -> Result<Vec<String>> {
+        self.with_conn("pending_migrations", |conn| {
+            Ok(crate::migrations::pending(conn)?
+                .iter()
+                .map(|m| format!("{:04} {}", m.version, m.name))
+                .collect())
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.with_conn("ping", |conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn backup(&self, dest: &std::path::Path) -> Result<()> {
+        let dest = dest.to_string_lossy().into_owned();
+        self.with_conn("backup", move |conn| {
+            // A consistent snapshot, even with writers active on the live file.
+            conn.execute("VACUUM INTO ?1", [&dest])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn initialize(&self) -> Result<()> {
+        self.with_conn("initialize", |conn| {
+            crate::migrations::run(conn)?;
+
+            for (name, description, permissions) in crate::rbac::BUILTIN_ROLES {
+                conn.execute(
+                    "INSERT INTO roles (name, description, builtin) VALUES (?1, ?2, 1)
+                     ON CONFLICT(name) DO UPDATE SET description = excluded.description, builtin = 1",
+                    (name, description),
+                )?;
+                conn.execute("DELETE FROM role_permissions WHERE role = ?1", [name])?;
+                for permission in *permissions {
+                    conn.execute(
+                        "INSERT INTO role_permissions (role, permission) VALUES (?1, ?2)",
+                        (name, permission),
+                    )?;
+                }
+            }
+
+            // Older releases seeded root / Koder@123. Force that account off the
+            // published password.
+            let legacy_root: Option<(String, String)> = conn
+                .query_row(
+                    "SELECT id, password_hash FROM users WHERE username = 'root' AND must_change_password = 0",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            if let Some((id, hash)) = legacy_root {
+                if crate::password::verify_blocking("Koder@123", &hash) {
+                    conn.execute(
+                        "UPDATE users SET must_change_password = 1 WHERE id = ?1",
+                        [&id],
+                    )?;
+                    tracing::warn!("root still uses the old default password; it must be changed at next login");
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<UserRow>> {
+        let username = username.to_string();
+        self.with_conn("get_user_by_username", move |conn| {
+            let mut stmt = conn.prepare(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE username = ?1 AND deleted_at IS NULL"),
             )?;
-            tracing::info!("Created default root user (root / Koder@123)");
-        }
+            let mut rows = stmt.query_map([username], map_user_row)?;
+            Ok(rows.next().transpose()?)
+        })
+        .await
+    }
 
-        Ok(())
-    }
-
-    pub fn get_user_by_username(&self, username: &str) -> Result<Option<UserRow>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, username, password_hash, display_name, role, created_at, updated_at FROM users WHERE username = ?1",
-        )?;
-        let mut rows = stmt.query_map([username], |row| {
-            Ok(UserRow {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                password_hash: row.get(2)?,
-                display_name: row.get(3)?,
-                role: row.get(4)?,
-                created_at: row.get(5)?,
-                updated_at: row.get(6)?,
-            })
-        })?;
-        Ok(rows.next().transpose()?)
-    }
-
-    pub fn get_user_by_id(&self, id: &str) -> Result<Option<UserRow>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, username, password_hash, display_name, role, created_at, updated_at FROM users WHERE id = ?1",
-        )?;
-        let mut rows = stmt.query_map([id], |row| {
-            Ok(UserRow {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                password_hash: row.get(2)?,
-                display_name: row.get(3)?,
-                role: row.get(4)?,
-                created_at: row.get(5)?,
-                updated_at: row.get(6)?,
-            })
-        })?;
-        Ok(rows.next().transpose()?)
-    }
-
-    pub fn list_users(&self) -> Result<Vec<User>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, username, password_hash, display_name, role, created_at, updated_at FROM users ORDER BY created_at",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(UserRow {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                password_hash: row.get(2)?,
-                display_name: row.get(3)?,
-                role: row.get(4)?,
-                created_at: row.get(5)?,
-                updated_at: row.get(6)?,
-            })
-        })?;
-        Ok(rows.filter_map(|r| r.ok()).map(|r| r.to_public()).collect())
+    async fn get_user_by_id(&self, id: &str) -> Result<Option<UserRow>> {
+        let id = id.to_string();
+        self.with_conn("get_user_by_id", move |conn| user_by_id(conn, &id)).await
+    }
+
+    async fn list_users(&self, query: &UserQuery) -> Result<UserPage> {
+        let sql = query.to_sql(USER_COLUMNS, |n| format!("?{n}"));
+        self.with_conn("list_users", move |conn| {
+            let total = conn.query_row(
+                &sql.count,
+                rusqlite::params_from_iter(&sql.params[..sql.count_params]),
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(&sql.select)?;
+            let users = stmt
+                .query_map(rusqlite::params_from_iter(&sql.params), |row| {
+                    map_user_row(row).map(|r| r.to_public())
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(UserPage { users, total })
+        })
+        .await
+    }
+
+    async fn count_users(&self) -> Result<i64> {
+        self.with_conn("count_users", |conn| {
+            Ok(conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?)
+        })
+        .await
     }
 
-    pub fn create_user(
+    async fn create_user(
         &self,
         username: &str,
-        password: &str,
+        password_hash: &str,
         display_name: &str,
         role: &str,
+        must_change_password: bool,
+        expires_at: Option<&str>,
     ) -> Result<User> {
-        let id = uuid::Uuid::new_v4().to_string();
-        let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT INTO users (id, username, password_hash, display_name, role) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (&id, username, &password_hash, display_name, role),
-        )?;
-        drop(conn);
-        Ok(self.get_user_by_id(&id)?.unwrap().to_public())
+        let (username, password_hash, display_name, role) = (
+            username.to_string(),
+            password_hash.to_string(),
+            display_name.to_string(),
+            role.to_string(),
+        );
+        let expires_at = expires_at.map(str::to_string);
+        self.with_conn("create_user", move |conn| {
+            let tx = conn.transaction()?;
+            let user = insert_user(
+                &tx,
+                &username,
+                &password_hash,
+                &display_name,
+                &role,
+                must_change_password,
+                expires_at.as_deref(),
+            )?;
+            tx.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn provision_external_user(
+        &self,
+        source: &str,
+        external_id: &str,
+        username: &str,
+        display_name: &str,
+        role: &str,
+    ) -> Result<Provisioned> {
+        let (source, external_id, username, display_name, role) = (
+            source.to_string(),
+            external_id.to_string(),
+            username.to_string(),
+            display_name.to_string(),
+            role.to_string(),
+        );
+        self.with_conn("provision_external_user", move |conn| {
+            let tx = conn.transaction()?;
+            let linked: Option<(String, bool)> = tx
+                .query_row(
+                    "SELECT id, deleted_at IS NOT NULL FROM users WHERE auth_source = ?1 AND external_id = ?2",
+                    (&source, &external_id),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let id = match linked {
+                Some((_, true)) => return Ok(Provisioned::Deleted),
+                Some((id, false)) => id,
+                None => {
+                    // Users provisioned before identities were linked have
+                    // no password and no link, and are adopted. Any other
+                    // user with the name is off limits.
+                    let unlinked: Option<String> = tx
+                        .query_row(
+                            "SELECT id FROM users
+                             WHERE username = ?1 AND password_hash = ?2
+                               AND external_id IS NULL AND deleted_at IS NULL",
+                            (&username, crate::password::NO_PASSWORD),
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    let taken: bool = tx.query_row(
+                        "SELECT COUNT(*) > 0 FROM users WHERE username = ?1",
+                        [&username],
+                        |row| row.get(0),
+                    )?;
+                    let id = match unlinked {
+                        Some(id) => id,
+                        None if taken => return Ok(Provisioned::NameTaken),
+                        None => {
+                            insert_user(
+                                &tx,
+                                &username,
+                                crate::password::NO_PASSWORD,
+                                &display_name,
+                                &role,
+                                false,
+                                None,
+                            )?
+                            .id
+                        }
+                    };
+                    tx.execute(
+                        "UPDATE users SET auth_source = ?1, external_id = ?2 WHERE id = ?3",
+                        (&source, &external_id, &id),
+                    )?;
+                    id
+                }
+            };
+            let changes = UserChanges {
+                display_name: Some(display_name).filter(|d| !d.is_empty()),
+                role: Some(role),
+                ..Default::default()
+            };
+            let user = update_user_fields(&tx, &id, &changes)?
+                .context("Provisioned user not found")?;
+            tx.commit()?;
+            Ok(Provisioned::User(Box::new(user)))
+        })
+        .await
     }
 
-    pub fn update_user(
+    async fn update_user(&self, id: &str, changes: &UserChanges) -> Result<Option<User>> {
+        let (id, changes) = (id.to_string(), changes.clone());
+        self.with_conn("update_user", move |conn| {
+            let tx = conn.transaction()?;
+            let user = update_user_fields(&tx, &id, &changes)?;
+            tx.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_conn("delete_user", move |conn| {
+            let tx = conn.transaction()?;
+            let rows = tx.execute(
+                "UPDATE users SET enabled = 0, deleted_at = datetime('now'), updated_at = datetime('now')
+                 WHERE id = ?1 AND deleted_at IS NULL",
+                [&id],
+            )?;
+            tx.execute("DELETE FROM api_tokens WHERE user_id = ?1", [&id])?;
+            tx.execute("DELETE FROM group_members WHERE user_id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn purge_user(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_conn("purge_user", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM api_tokens WHERE user_id = ?1", [&id])?;
+            tx.execute("DELETE FROM group_members WHERE user_id = ?1", [&id])?;
+            tx.execute("DELETE FROM password_history WHERE user_id = ?1", [&id])?;
+            let rows = tx.execute("DELETE FROM users WHERE id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn update_password(&self, id: &str, password_hash: &str) -> Result<()> {
+        let (id, password_hash) = (id.to_string(), password_hash.to_string());
+        self.with_conn("update_password", move |conn| {
+            let tx = conn.transaction()?;
+            let rows = tx.execute(
+                "UPDATE users SET password_hash = ?1, must_change_password = 0, updated_at = datetime('now')
+                 WHERE id = ?2 AND deleted_at IS NULL",
+                (&password_hash, &id),
+            )?;
+            if rows > 0 {
+                record_password(&tx, &id, &password_hash)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn record_login(&self, id: &str) -> Result<()> {
+        let id = id.to_string();
+        self.with_conn("record_login", move |conn| {
+            conn.execute(
+                "UPDATE users SET last_login_at = datetime('now') WHERE id = ?1",
+                [&id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn rehash_password(
         &self,
         id: &str,
-        display_name: Option<&str>,
-        role: Option<&str>,
-        password: Option<&str>,
-    ) -> Result<Option<User>> {
-        let conn = self.conn.lock().unwrap();
+        expected: &str,
+        password_hash: &str,
+    ) -> Result<bool> {
+        let (id, expected, password_hash) =
+            (id.to_string(), expected.to_string(), password_hash.to_string());
+        self.with_conn("rehash_password", move |conn| {
+            let rows = conn.execute(
+                "UPDATE users SET password_hash = ?1 WHERE id = ?2 AND password_hash = ?3",
+                (&password_hash, &id, &expected),
+            )?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn recent_password_hashes(&self, user_id: &str, limit: usize) -> Result<Vec<String>> {
+        let user_id = user_id.to_string();
+        self.with_conn("recent_password_hashes", move |conn| {
+            let mut hashes: Vec<String> = conn
+                .query_row(
+                    "SELECT password_hash FROM users WHERE id = ?1",
+                    [&user_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .into_iter()
+                .collect();
+            let mut stmt = conn.prepare(
+                "SELECT password_hash FROM password_history WHERE user_id = ?1 ORDER BY id DESC LIMIT ?2",
+            )?;
+            for hash in stmt.query_map((&user_id, limit as i64), |row| row.get::<_, String>(0))? {
+                let hash = hash?;
+                if !hashes.contains(&hash) {
+                    hashes.push(hash);
+                }
+            }
+            Ok(hashes)
+        })
+        .await
+    }
 
-        if let Some(dn) = display_name {
+    async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[String],
+        expires_in_days: u32,
+    ) -> Result<ApiToken> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (user_id, name, token_hash, scopes) = (
+            user_id.to_string(),
+            name.to_string(),
+            token_hash.to_string(),
+            scopes.join(" "),
+        );
+        self.with_conn("create_api_token", move |conn| {
             conn.execute(
-                "UPDATE users SET display_name = ?1, updated_at = datetime('now') WHERE id = ?2",
-                (dn, id),
+                "INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, datetime('now', '+' || ?6 || ' days'))",
+                (&id, &user_id, &name, &token_hash, &scopes, expires_in_days),
             )?;
-        }
-        if let Some(r) = role {
+            let token = conn.query_row(
+                "SELECT id, name, scopes, expires_at, created_at, last_used_at FROM api_tokens WHERE id = ?1",
+                [&id],
+                map_api_token,
+            )?;
+            Ok(token)
+        })
+        .await
+    }
+
+    async fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let user_id = user_id.to_string();
+        self.with_conn("list_api_tokens", move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, name, scopes, expires_at, created_at, last_used_at FROM api_tokens WHERE user_id = ?1 ORDER BY created_at",
+            )?;
+            let rows = stmt.query_map([&user_id], map_api_token)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn delete_api_token(&self, user_id: &str, id: &str) -> Result<bool> {
+        let (user_id, id) = (user_id.to_string(), id.to_string());
+        self.with_conn("delete_api_token", move |conn| {
+            let rows = conn.execute(
+                "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+                (&id, &user_id),
+            )?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn revoke_api_tokens(&self, user_id: Option<&str>) -> Result<usize> {
+        let user_id = user_id.map(str::to_string);
+        self.with_conn("revoke_api_tokens", move |conn| {
+            Ok(match &user_id {
+                Some(id) => conn.execute("DELETE FROM api_tokens WHERE user_id = ?1", [id])?,
+                None => conn.execute("DELETE FROM api_tokens", [])?,
+            })
+        })
+        .await
+    }
+
+    async fn get_api_token_owner(&self, token_hash: &str) -> Result<Option<ApiTokenOwner>> {
+        let token_hash = token_hash.to_string();
+        self.with_conn("get_api_token_owner", move |conn| {
+            let owner = conn
+                .query_row(
+                    "SELECT t.id, t.scopes, CAST(strftime('%s', t.expires_at) AS INTEGER), u.id, u.username, u.role, u.must_change_password
+                     FROM api_tokens t JOIN users u ON u.id = t.user_id
+                     WHERE t.token_hash = ?1 AND t.expires_at > datetime('now') AND u.enabled = 1
+                       AND u.deleted_at IS NULL AND (u.expires_at IS NULL OR u.expires_at > datetime('now'))",
+                    [&token_hash],
+                    |row| {
+                        let scopes: String = row.get(1)?;
+                        Ok(ApiTokenOwner {
+                            token_id: row.get(0)?,
+                            scopes: scopes.split_whitespace().map(str::to_string).collect(),
+                            expires_at: row.get(2)?,
+                            user_id: row.get(3)?,
+                            username: row.get(4)?,
+                            role: row.get(5)?,
+                            must_change_password: row.get(6)?,
+                        })
+                    },
+                )
+                .optional()?;
+
+            if let Some(ref o) = owner {
+                conn.execute(
+                    "UPDATE api_tokens SET last_used_at = datetime('now') WHERE id = ?1",
+                    [&o.token_id],
+                )?;
+            }
+            Ok(owner)
+        })
+        .await
+    }
+
+    async fn role_has_permission(&self, role: &str, permission: &str) -> Result<bool> {
+        let (role, permission) = (role.to_string(), permission.to_string());
+        self.with_conn("role_has_permission", move |conn| {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM role_permissions WHERE role = ?1 AND permission = ?2",
+                (&role, &permission),
+                |row| row.get(0),
+            )?;
+            Ok(count > 0)
+        })
+        .await
+    }
+
+    async fn role_exists(&self, name: &str) -> Result<bool> {
+        let name = name.to_string();
+        self.with_conn("role_exists", move |conn| {
+            let count: i64 =
+                conn.query_row("SELECT COUNT(*) FROM roles WHERE name = ?1", [&name], |row| {
+                    row.get(0)
+                })?;
+            Ok(count > 0)
+        })
+        .await
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>> {
+        self.with_conn("list_roles", |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT r.name, r.description, r.builtin, COALESCE(group_concat(p.permission, ' '), '')
+                 FROM roles r LEFT JOIN role_permissions p ON p.role = r.name
+                 GROUP BY r.name ORDER BY r.builtin DESC, r.name",
+            )?;
+            let rows = stmt.query_map([], map_role)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>> {
+        let name = name.to_string();
+        self.with_conn("get_role", move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT r.name, r.description, r.builtin, COALESCE(group_concat(p.permission, ' '), '')
+                 FROM roles r LEFT JOIN role_permissions p ON p.role = r.name
+                 WHERE r.name = ?1 GROUP BY r.name",
+            )?;
+            let mut rows = stmt.query_map([&name], map_role)?;
+            Ok(rows.next().transpose()?)
+        })
+        .await
+    }
+
+    async fn create_role(
+        &self,
+        name: &str,
+        description: &str,
+        permissions: &[String],
+    ) -> Result<()> {
+        let (name, description, permissions) =
+            (name.to_string(), description.to_string(), permissions.to_vec());
+        self.with_conn("create_role", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO roles (name, description) VALUES (?1, ?2)",
+                (&name, &description),
+            )?;
+            for permission in &permissions {
+                tx.execute(
+                    "INSERT OR IGNORE INTO role_permissions (role, permission) VALUES (?1, ?2)",
+                    (&name, permission),
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_role(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        permissions: Option<&[String]>,
+    ) -> Result<bool> {
+        let name = name.to_string();
+        let description = description.map(str::to_string);
+        let permissions = permissions.map(<[String]>::to_vec);
+        self.with_conn("update_role", move |conn| {
+            let tx = conn.transaction()?;
+            let exists: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM roles WHERE name = ?1 AND builtin = 0",
+                [&name],
+                |row| row.get(0),
+            )?;
+            if exists == 0 {
+                return Ok(false);
+            }
+            if let Some(d) = description {
+                tx.execute("UPDATE roles SET description = ?1 WHERE name = ?2", (&d, &name))?;
+            }
+            if let Some(perms) = permissions {
+                tx.execute("DELETE FROM role_permissions WHERE role = ?1", [&name])?;
+                for permission in &perms {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO role_permissions (role, permission) VALUES (?1, ?2)",
+                        (&name, permission),
+                    )?;
+                }
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn count_users_with_role(&self, name: &str) -> Result<i64> {
+        let name = name.to_string();
+        self.with_conn("count_users_with_role", move |conn| {
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM users WHERE role = ?1 AND deleted_at IS NULL",
+                [&name],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool> {
+        let name = name.to_string();
+        self.with_conn("delete_role", move |conn| {
+            let rows = conn.execute("DELETE FROM roles WHERE name = ?1 AND builtin = 0", [&name])?;
+            if rows > 0 {
+                conn.execute("DELETE FROM role_permissions WHERE role = ?1", [&name])?;
+            }
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        self.with_conn("list_groups", |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {GROUP_COLUMNS} FROM groups g ORDER BY g.name"
+            ))?;
+            let rows = stmt.query_map([], map_group)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn get_group(&self, id: &str) -> Result<Option<Group>> {
+        let id = id.to_string();
+        self.with_conn("get_group", move |conn| group_by_id(conn, &id)).await
+    }
+
+    async fn create_group(&self, name: &str, description: &str) -> Result<Group> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (name, description) = (name.to_string(), description.to_string());
+        self.with_conn("create_group", move |conn| {
             conn.execute(
-                "UPDATE users SET role = ?1, updated_at = datetime('now') WHERE id = ?2",
-                (r, id),
+                "INSERT INTO groups (id, name, description) VALUES (?1, ?2, ?3)",
+                (&id, &name, &description),
             )?;
-        }
-        if let Some(pw) = password {
-            let hash = bcrypt::hash(pw, bcrypt::DEFAULT_COST)?;
+            Ok(group_by_id(conn, &id)?.unwrap())
+        })
+        .await
+    }
+
+    async fn update_group(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<Option<Group>> {
+        let id = id.to_string();
+        let name = name.map(str::to_string);
+        let description = description.map(str::to_string);
+        self.with_conn("update_group", move |conn| {
+            if let Some(n) = name {
+                conn.execute(
+                    "UPDATE groups SET name = ?1, updated_at = datetime('now') WHERE id = ?2",
+                    (&n, &id),
+                )?;
+            }
+            if let Some(d) = description {
+                conn.execute(
+                    "UPDATE groups SET description = ?1, updated_at = datetime('now') WHERE id = ?2",
+                    (&d, &id),
+                )?;
+            }
+            group_by_id(conn, &id)
+        })
+        .await
+    }
+
+    async fn delete_group(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_conn("delete_group", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM group_members WHERE group_id = ?1", [&id])?;
+            tx.execute("DELETE FROM group_connections WHERE group_id = ?1", [&id])?;
+            tx.execute("DELETE FROM destination_rules WHERE group_id = ?1", [&id])?;
+            let rows = tx.execute("DELETE FROM groups WHERE id = ?1", [&id])?;
+            tx.commit()?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn list_group_members(&self, group_id: &str) -> Result<Vec<User>> {
+        let group_id = group_id.to_string();
+        self.with_conn("list_group_members", move |conn| {
+            let mut stmt = conn.prepare(
+                &format!(
+                    "SELECT {USER_COLUMNS} FROM users
+                     WHERE id IN (SELECT user_id FROM group_members WHERE group_id = ?1)
+                       AND deleted_at IS NULL
+                     ORDER BY username"
+                ),
+            )?;
+            let rows = stmt.query_map([&group_id], map_user_row)?;
+            Ok(rows
+                .map(|r| r.map(|u| u.to_public()))
+                .collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn add_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        let (group_id, user_id) = (group_id.to_string(), user_id.to_string());
+        self.with_conn("add_group_member", move |conn| {
             conn.execute(
-                "UPDATE users SET password_hash = ?1, updated_at = datetime('now') WHERE id = ?2",
-                (&hash, id),
+                "INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?1, ?2)",
+                (&group_id, &user_id),
             )?;
-        }
-        drop(conn);
-        Ok(self.get_user_by_id(id)?.map(|u| u.to_public()))
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<bool> {
+        let (group_id, user_id) = (group_id.to_string(), user_id.to_string());
+        self.with_conn("remove_group_member", move |conn| {
+            let rows = conn.execute(
+                "DELETE FROM group_members WHERE group_id = ?1 AND user_id = ?2",
+                (&group_id, &user_id),
+            )?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn list_connections(&self) -> Result<Vec<ConnectionProfile>> {
+        self.with_conn("list_connections", |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {CONNECTION_COLUMNS} FROM connections c ORDER BY c.name"
+            ))?;
+            let rows = stmt.query_map([], map_connection)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn list_connections_for_user(&self, user_id: &str) -> Result<Vec<ConnectionProfile>> {
+        let user_id = user_id.to_string();
+        self.with_conn("list_connections_for_user", move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {CONNECTION_COLUMNS} FROM connections c
+                 WHERE c.id IN (
+                    SELECT gc.connection_id FROM group_connections gc
+                    JOIN group_members m ON m.group_id = gc.group_id
+                    WHERE m.user_id = ?1
+                 )
+                 ORDER BY c.name"
+            ))?;
+            let rows = stmt.query_map([&user_id], map_connection)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn get_connection(&self, id: &str) -> Result<Option<ConnectionProfile>> {
+        let id = id.to_string();
+        self.with_conn("get_connection", move |conn| connection_by_id(conn, &id)).await
+    }
+
+    async fn create_connection(
+        &self,
+        name: &str,
+        protocol: &str,
+        host: &str,
+        port: u16,
+        group_ids: &[String],
+    ) -> Result<ConnectionProfile> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (name, protocol, host, group_ids) = (
+            name.to_string(),
+            protocol.to_string(),
+            host.to_string(),
+            group_ids.to_vec(),
+        );
+        self.with_conn("create_connection", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO connections (id, name, protocol, host, port) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (&id, &name, &protocol, &host, port),
+            )?;
+            for group_id in &group_ids {
+                tx.execute(
+                    "INSERT OR IGNORE INTO group_connections (group_id, connection_id) VALUES (?1, ?2)",
+                    (group_id, &id),
+                )?;
+            }
+            tx.commit()?;
+            Ok(connection_by_id(conn, &id)?.unwrap())
+        })
+        .await
+    }
+
+    async fn update_connection(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        host: Option<&str>,
+        port: Option<u16>,
+        group_ids: Option<&[String]>,
+    ) -> Result<Option<ConnectionProfile>> {
+        let id = id.to_string();
+        let name = name.map(str::to_string);
+        let host = host.map(str::to_string);
+        let group_ids = group_ids.map(<[String]>::to_vec);
+        self.with_conn("update_connection", move |conn| {
+            let tx = conn.transaction()?;
+            if let Some(n) = name {
+                tx.execute(
+                    "UPDATE connections SET name = ?1, updated_at = datetime('now') WHERE id = ?2",
+                    (&n, &id),
+                )?;
+            }
+            if let Some(h) = host {
+                tx.execute(
+                    "UPDATE connections SET host = ?1, updated_at = datetime('now') WHERE id = ?2",
+                    (&h, &id),
+                )?;
+            }
+            if let Some(p) = port {
+                tx.execute(
+                    "UPDATE connections SET port = ?1, updated_at = datetime('now') WHERE id = ?2",
+                    (p, &id),
+                )?;
+            }
+            if let Some(groups) = group_ids {
+                tx.execute("DELETE FROM group_connections WHERE connection_id = ?1", [&id])?;
+                for group_id in &groups {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO group_connections (group_id, connection_id) VALUES (?1, ?2)",
+                        (group_id, &id),
+                    )?;
+                }
+            }
+            tx.commit()?;
+            connection_by_id(conn, &id)
+        })
+        .await
+    }
+
+    async fn delete_connection(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.with_conn("delete_connection", move |conn| {
+            conn.execute("DELETE FROM group_connections WHERE connection_id = ?1", [&id])?;
+            let rows = conn.execute("DELETE FROM connections WHERE id = ?1", [&id])?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn list_destination_rules(&self, group_id: &str) -> Result<Vec<DestinationRule>> {
+        let group_id = group_id.to_string();
+        self.with_conn("list_destination_rules", move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, group_id, pattern, created_at FROM destination_rules WHERE group_id = ?1 ORDER BY created_at",
+            )?;
+            let rows = stmt.query_map([&group_id], map_destination_rule)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn create_destination_rule(
+        &self,
+        group_id: &str,
+        pattern: &str,
+    ) -> Result<DestinationRule> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let (group_id, pattern) = (group_id.to_string(), pattern.to_string());
+        self.with_conn("create_destination_rule", move |conn| {
+            conn.execute(
+                "INSERT INTO destination_rules (id, group_id, pattern) VALUES (?1, ?2, ?3)",
+                (&id, &group_id, &pattern),
+            )?;
+            Ok(conn.query_row(
+                "SELECT id, group_id, pattern, created_at FROM destination_rules WHERE id = ?1",
+                [&id],
+                map_destination_rule,
+            )?)
+        })
+        .await
+    }
+
+    async fn delete_destination_rule(&self, group_id: &str, id: &str) -> Result<bool> {
+        let (group_id, id) = (group_id.to_string(), id.to_string());
+        self.with_conn("delete_destination_rule", move |conn| {
+            let rows = conn.execute(
+                "DELETE FROM destination_rules WHERE id = ?1 AND group_id = ?2",
+                (&id, &group_id),
+            )?;
+            Ok(rows > 0)
+        })
+        .await
+    }
+
+    async fn destination_patterns_for_user(&self, user_id: &str) -> Result<Vec<String>> {
+        let user_id = user_id.to_string();
+        self.with_conn("destination_patterns_for_user", move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT r.pattern FROM destination_rules r
+                 JOIN group_members m ON m.group_id = r.group_id
+                 WHERE m.user_id = ?1
+                 UNION
+                 SELECT c.host || ':' || c.port FROM connections c
+                 JOIN group_connections gc ON gc.connection_id = c.id
+                 JOIN group_members m ON m.group_id = gc.group_id
+                 WHERE m.user_id = ?1",
+            )?;
+            let rows = stmt.query_map([&user_id], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn append_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        details: &str,
+    ) -> Result<()> {
+        let (actor, action, target, details) = (
+            actor.to_string(),
+            action.to_string(),
+            target.to_string(),
+            details.to_string(),
+        );
+        self.with_conn("append_audit", move |conn| {
+            // Immediate, so two writers cannot both extend the same head.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let prev_hash: String = tx
+                .query_row(
+                    "SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or_else(|| crate::audit::GENESIS.to_string());
+            let at: String = tx.query_row("SELECT datetime('now')", [], |row| row.get(0))?;
+            let hash =
+                crate::audit::entry_hash(&prev_hash, &at, &actor, &action, &target, &details);
+            tx.execute(
+                "INSERT INTO audit_log (at, actor, action, target, details, prev_hash, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                (&at, &actor, &action, &target, &details, &prev_hash, &hash),
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn delete_user(&self, id: &str) -> Result<bool> {
-        let conn = self.conn.lock().unwrap();
-        let rows = conn.execute("DELETE FROM users WHERE id = ?1", [id])?;
-        Ok(rows > 0)
+    async fn list_audit(&self) -> Result<Vec<AuditEntry>> {
+        self.with_conn("list_audit", |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, at, actor, action, target, details, prev_hash, hash
+                 FROM audit_log ORDER BY id",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(AuditEntry {
+                    id: row.get(0)?,
+                    at: row.get(1)?,
+                    actor: row.get(2)?,
+                    action: row.get(3)?,
+                    target: row.get(4)?,
+                    details: row.get(5)?,
+                    prev_hash: row.get(6)?,
+                    hash: row.get(7)?,
+                })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_panicking_query_returns_its_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("koder.db"), 1).unwrap();
+
+        let panicked = db.with_conn("panic", |_| -> Result<()> { panic!("query failed") }).await;
+        assert!(panicked.is_err());
 
-    pub fn update_password(&self, id: &str, new_password: &str) -> Result<()> {
-        let hash = bcrypt::hash(new_password, bcrypt::DEFAULT_COST)?;
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "UPDATE users SET password_hash = ?1, updated_at = datetime('now') WHERE id = ?2",
-            (&hash, id),
-        )?;
-        Ok(())
+        let one: i64 = db
+            .with_conn("select", |conn| Ok(conn.query_row("SELECT 1", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
     }
 }
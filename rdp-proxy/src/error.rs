@@ -0,0 +1,479 @@
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+use crate::password_policy::Violation;
+
+/// Languages with a message catalog. pt-BR is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    PtBr,
+    En,
+}
+
+impl Lang {
+    /// The supported language the client prefers most, by `q` weight and
+    /// then by order.
+    pub fn from_accept_language(value: &str) -> Lang {
+        let mut ranges: Vec<(&str, f32)> = value
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && q > 0.0).then_some((tag, q))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges
+            .iter()
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or_default();
+                if primary.eq_ignore_ascii_case("pt") {
+                    Some(Lang::PtBr)
+                } else if primary.eq_ignore_ascii_case("en") {
+                    Some(Lang::En)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Lang::PtBr => "pt-BR",
+            Lang::En => "en",
+        }
+    }
+
+    /// The language of the request being handled.
+    pub fn current() -> Lang {
+        LANG.try_with(|lang| *lang).unwrap_or_default()
+    }
+}
+
+tokio::task_local! {
+    static LANG: Lang;
+}
+
+/// Makes the request's `Accept-Language` available to `Lang::current`, so
+/// errors are rendered in it wherever they are built.
+pub async fn localize(req: Request, next: Next) -> Response {
+    let lang = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map(Lang::from_accept_language)
+        .unwrap_or_default();
+    LANG.scope(lang, next.run(req)).await
+}
+
+/// API failures. The JSON body carries a stable `code` for clients and an
+/// `error` message in the request's language.
+#[derive(Debug)]
+pub enum ApiError {
+    Internal,
+    MissingToken,
+    InvalidToken,
+    AccountUnavailable,
+    PasswordChangeRequired,
+    InvalidCredentials,
+    UserDisabled,
+    UserExpired,
+    DirectoryUnavailable,
+    AccountConflict,
+    ApiTokenNotAllowed,
+    WrongPassword,
+    SamePassword,
+    Forbidden,
+    OriginNotAllowed,
+    PasswordPolicy(Vec<Violation>),
+    UserNotFound,
+    UserExists,
+    UserFieldsRequired,
+    CannotDeleteSelf,
+    CannotDisableSelf,
+    InvalidExpiry,
+    RoleNotFound,
+    SessionNotFound,
+    InvalidSort,
+    InvalidCursor,
+    NameRequired,
+    RouteNotFound,
+    ShuttingDown,
+    SetupCompleted,
+    InvalidSetupToken,
+    TokenScopesRequired,
+    UnknownScope(String),
+    InvalidTokenLifetime(u32),
+    TokenNotFound,
+    InvalidRoleName,
+    UnknownPermission(String),
+    RoleExists,
+    RoleInUse,
+    CustomRoleNotFound,
+    GroupNotFound,
+    GroupExists,
+    MemberNotFound,
+    RuleNotFound,
+    InvalidDestinationPattern,
+    ConnectionNotFound,
+    ConnectionFieldsRequired,
+    InvalidProtocol,
+    UnknownGroup,
+    SsoNotConfigured,
+    IdentityProviderUnavailable,
+    TooManyPendingLogins,
+    SsoDenied,
+    SsoMissingParameters,
+    SsoSessionInvalid,
+    SsoFailed,
+    SsoMissingUsername,
+    SsoMissingSubject,
+}
+
+impl ApiError {
+    /// Logs the cause, which clients never see.
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        tracing::error!("Internal error: {e:#}");
+        ApiError::Internal
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::MissingToken
+            | ApiError::InvalidToken
+            | ApiError::AccountUnavailable
+            | ApiError::InvalidCredentials
+            | ApiError::SsoDenied
+            | ApiError::SsoFailed
+            | ApiError::SsoMissingUsername
+            | ApiError::SsoMissingSubject => StatusCode::UNAUTHORIZED,
+            ApiError::PasswordChangeRequired
+            | ApiError::UserDisabled
+            | ApiError::UserExpired
+            | ApiError::ApiTokenNotAllowed
+            | ApiError::Forbidden
+            | ApiError::OriginNotAllowed
+            | ApiError::InvalidSetupToken => StatusCode::FORBIDDEN,
+            ApiError::DirectoryUnavailable | ApiError::IdentityProviderUnavailable => {
+                StatusCode::BAD_GATEWAY
+            }
+            ApiError::WrongPassword
+            | ApiError::SamePassword
+            | ApiError::PasswordPolicy(_)
+            | ApiError::UserFieldsRequired
+            | ApiError::CannotDeleteSelf
+            | ApiError::CannotDisableSelf
+            | ApiError::InvalidExpiry
+            | ApiError::RoleNotFound
+            | ApiError::InvalidSort
+            | ApiError::InvalidCursor
+            | ApiError::NameRequired
+            | ApiError::TokenScopesRequired
+            | ApiError::UnknownScope(_)
+            | ApiError::InvalidTokenLifetime(_)
+            | ApiError::InvalidRoleName
+            | ApiError::UnknownPermission(_)
+            | ApiError::InvalidDestinationPattern
+            | ApiError::ConnectionFieldsRequired
+            | ApiError::InvalidProtocol
+            | ApiError::UnknownGroup
+            | ApiError::SsoMissingParameters
+            | ApiError::SsoSessionInvalid => StatusCode::BAD_REQUEST,
+            ApiError::UserNotFound
+            | ApiError::SessionNotFound
+            | ApiError::RouteNotFound
+            | ApiError::TokenNotFound
+            | ApiError::CustomRoleNotFound
+            | ApiError::GroupNotFound
+            | ApiError::MemberNotFound
+            | ApiError::RuleNotFound
+            | ApiError::ConnectionNotFound
+            | ApiError::SsoNotConfigured => StatusCode::NOT_FOUND,
+            ApiError::UserExists
+            | ApiError::AccountConflict
+            | ApiError::SetupCompleted
+            | ApiError::RoleExists
+            | ApiError::RoleInUse
+            | ApiError::GroupExists => StatusCode::CONFLICT,
+            ApiError::TooManyPendingLogins => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Internal => "internal",
+            ApiError::MissingToken => "auth.missing_token",
+            ApiError::InvalidToken => "auth.invalid_token",
+            ApiError::AccountUnavailable => "auth.account_unavailable",
+            ApiError::PasswordChangeRequired => "auth.password_change_required",
+            ApiError::InvalidCredentials => "auth.invalid_credentials",
+            ApiError::UserDisabled => "auth.user_disabled",
+            ApiError::UserExpired => "auth.user_expired",
+            ApiError::DirectoryUnavailable => "auth.directory_unavailable",
+            ApiError::AccountConflict => "auth.account_conflict",
+            ApiError::ApiTokenNotAllowed => "auth.api_token_not_allowed",
+            ApiError::WrongPassword => "auth.wrong_password",
+            ApiError::SamePassword => "auth.same_password",
+            ApiError::Forbidden => "auth.forbidden",
+            ApiError::OriginNotAllowed => "auth.origin_not_allowed",
+            ApiError::PasswordPolicy(_) => "password.policy",
+            ApiError::UserNotFound => "user.not_found",
+            ApiError::UserExists => "user.exists",
+            ApiError::UserFieldsRequired => "user.fields_required",
+            ApiError::CannotDeleteSelf => "user.cannot_delete_self",
+            ApiError::CannotDisableSelf => "user.cannot_disable_self",
+            ApiError::InvalidExpiry => "user.invalid_expiry",
+            ApiError::RoleNotFound => "role.not_found",
+            ApiError::SessionNotFound => "session.not_found",
+            ApiError::InvalidSort => "list.invalid_sort",
+            ApiError::InvalidCursor => "list.invalid_cursor",
+            ApiError::NameRequired => "validation.name_required",
+            ApiError::RouteNotFound => "route.not_found",
+            ApiError::ShuttingDown => "server.shutting_down",
+            ApiError::SetupCompleted => "setup.completed",
+            ApiError::InvalidSetupToken => "setup.invalid_token",
+            ApiError::TokenScopesRequired => "token.scopes_required",
+            ApiError::UnknownScope(_) => "token.unknown_scope",
+            ApiError::InvalidTokenLifetime(_) => "token.invalid_lifetime",
+            ApiError::TokenNotFound => "token.not_found",
+            ApiError::InvalidRoleName => "role.invalid_name",
+            ApiError::UnknownPermission(_) => "role.unknown_permission",
+            ApiError::RoleExists => "role.exists",
+            ApiError::RoleInUse => "role.in_use",
+            ApiError::CustomRoleNotFound => "role.not_editable",
+            ApiError::GroupNotFound => "group.not_found",
+            ApiError::GroupExists => "group.exists",
+            ApiError::MemberNotFound => "group.member_not_found",
+            ApiError::RuleNotFound => "group.rule_not_found",
+            ApiError::InvalidDestinationPattern => "group.invalid_pattern",
+            ApiError::ConnectionNotFound => "connection.not_found",
+            ApiError::ConnectionFieldsRequired => "connection.fields_required",
+            ApiError::InvalidProtocol => "connection.invalid_protocol",
+            ApiError::UnknownGroup => "connection.unknown_group",
+            ApiError::SsoNotConfigured => "sso.not_configured",
+            ApiError::IdentityProviderUnavailable => "sso.provider_unavailable",
+            ApiError::TooManyPendingLogins => "sso.too_many_pending",
+            ApiError::SsoDenied => "sso.denied",
+            ApiError::SsoMissingParameters => "sso.missing_parameters",
+            ApiError::SsoSessionInvalid => "sso.invalid_session",
+            ApiError::SsoFailed => "sso.failed",
+            ApiError::SsoMissingUsername => "sso.missing_username",
+            ApiError::SsoMissingSubject => "sso.missing_subject",
+        }
+    }
+
+    pub fn message(&self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::PtBr => self.pt_br(),
+            Lang::En => self.en(),
+        }
+    }
+
+    fn pt_br(&self) -> &'static str {
+        match self {
+            ApiError::Internal => "Erro interno",
+            ApiError::MissingToken => "Token ausente",
+            ApiError::InvalidToken => "Token inválido ou expirado",
+            ApiError::AccountUnavailable => "Usuário desativado, expirado ou removido",
+            ApiError::PasswordChangeRequired => "Troca de senha obrigatória",
+            ApiError::InvalidCredentials => "Usuário ou senha incorretos",
+            ApiError::UserDisabled => "Usuário desativado",
+            ApiError::UserExpired => "Conta expirada",
+            ApiError::DirectoryUnavailable => "Servidor de diretório indisponível",
+            ApiError::AccountConflict => "Nome de usuário já pertence a outra conta",
+            ApiError::ApiTokenNotAllowed => "Operação não permitida com token de API",
+            ApiError::WrongPassword => "Senha atual incorreta",
+            ApiError::SamePassword => "A nova senha deve ser diferente da atual",
+            ApiError::Forbidden => "Permissão insuficiente",
+            ApiError::OriginNotAllowed => "Origem não permitida",
+            ApiError::PasswordPolicy(_) => "A senha não atende à política de senhas",
+            ApiError::UserNotFound => "Usuário não encontrado",
+            ApiError::UserExists => "Usuário já existe",
+            ApiError::UserFieldsRequired => "Usuário e senha são obrigatórios",
+            ApiError::CannotDeleteSelf => "Não é possível excluir o próprio usuário",
+            ApiError::CannotDisableSelf => "Não é possível desativar o próprio usuário",
+            ApiError::InvalidExpiry => "Data de expiração inválida (use AAAA-MM-DD HH:MM:SS, UTC)",
+            ApiError::RoleNotFound => "Perfil inexistente",
+            ApiError::SessionNotFound => "Sessão não encontrada",
+            ApiError::InvalidSort => "Ordenação inválida",
+            ApiError::InvalidCursor => "Cursor de paginação inválido",
+            ApiError::NameRequired => "Nome é obrigatório",
+            ApiError::RouteNotFound => "Rota não encontrada",
+            ApiError::ShuttingDown => "Servidor em desligamento",
+            ApiError::SetupCompleted => "Configuração inicial já concluída",
+            ApiError::InvalidSetupToken => "Token de configuração inválido",
+            ApiError::TokenScopesRequired => "Informe ao menos um escopo",
+            ApiError::UnknownScope(_) => "Escopo desconhecido",
+            ApiError::InvalidTokenLifetime(_) => "Validade fora do intervalo permitido",
+            ApiError::TokenNotFound => "Token não encontrado",
+            ApiError::InvalidRoleName => {
+                "Nome deve conter apenas letras minúsculas, números, '-' e '_'"
+            }
+            ApiError::UnknownPermission(_) => "Permissão desconhecida",
+            ApiError::RoleExists => "Perfil já existe",
+            ApiError::RoleInUse => "Perfil atribuído a usuários",
+            ApiError::CustomRoleNotFound => "Perfil não encontrado ou não editável",
+            ApiError::GroupNotFound => "Grupo não encontrado",
+            ApiError::GroupExists => "Grupo já existe",
+            ApiError::MemberNotFound => "Membro não encontrado",
+            ApiError::RuleNotFound => "Regra não encontrada",
+            ApiError::InvalidDestinationPattern => "Padrão de destino inválido",
+            ApiError::ConnectionNotFound => "Conexão não encontrada",
+            ApiError::ConnectionFieldsRequired => "Nome e host são obrigatórios",
+            ApiError::InvalidProtocol => "Protocolo deve ser 'rdp' ou 'ssh'",
+            ApiError::UnknownGroup => "Grupo inexistente",
+            ApiError::SsoNotConfigured => "SSO não configurado",
+            ApiError::IdentityProviderUnavailable => "Provedor de identidade indisponível",
+            ApiError::TooManyPendingLogins => "Muitos logins em andamento; tente novamente",
+            ApiError::SsoDenied => "Login recusado pelo provedor de identidade",
+            ApiError::SsoMissingParameters => "Parâmetros ausentes",
+            ApiError::SsoSessionInvalid => "Sessão de login inválida ou expirada",
+            ApiError::SsoFailed => "Falha na autenticação SSO",
+            ApiError::SsoMissingUsername => "Token sem nome de usuário",
+            ApiError::SsoMissingSubject => "Token sem identificador",
+        }
+    }
+
+    fn en(&self) -> &'static str {
+        match self {
+            ApiError::Internal => "Internal error",
+            ApiError::MissingToken => "Missing token",
+            ApiError::InvalidToken => "Invalid or expired token",
+            ApiError::AccountUnavailable => "User disabled, expired or deleted",
+            ApiError::PasswordChangeRequired => "Password change required",
+            ApiError::InvalidCredentials => "Incorrect username or password",
+            ApiError::UserDisabled => "User disabled",
+            ApiError::UserExpired => "Account expired",
+            ApiError::DirectoryUnavailable => "Directory server unavailable",
+            ApiError::AccountConflict => "The username belongs to another account",
+            ApiError::ApiTokenNotAllowed => "Not allowed with an API token",
+            ApiError::WrongPassword => "Current password is incorrect",
+            ApiError::SamePassword => "The new password must differ from the current one",
+            ApiError::Forbidden => "Insufficient permissions",
+            ApiError::OriginNotAllowed => "Origin not allowed",
+            ApiError::PasswordPolicy(_) => "The password does not meet the password policy",
+            ApiError::UserNotFound => "User not found",
+            ApiError::UserExists => "User already exists",
+            ApiError::UserFieldsRequired => "Username and password are required",
+            ApiError::CannotDeleteSelf => "You cannot delete your own user",
+            ApiError::CannotDisableSelf => "You cannot disable your own user",
+            ApiError::InvalidExpiry => "Invalid expiry date (use YYYY-MM-DD HH:MM:SS, UTC)",
+            ApiError::RoleNotFound => "Role does not exist",
+            ApiError::SessionNotFound => "Session not found",
+            ApiError::InvalidSort => "Invalid sort order",
+            ApiError::InvalidCursor => "Invalid pagination cursor",
+            ApiError::NameRequired => "Name is required",
+            ApiError::RouteNotFound => "Route not found",
+            ApiError::ShuttingDown => "The server is shutting down",
+            ApiError::SetupCompleted => "Initial setup is already complete",
+            ApiError::InvalidSetupToken => "Invalid setup token",
+            ApiError::TokenScopesRequired => "At least one scope is required",
+            ApiError::UnknownScope(_) => "Unknown scope",
+            ApiError::InvalidTokenLifetime(_) => "Lifetime out of the allowed range",
+            ApiError::TokenNotFound => "Token not found",
+            ApiError::InvalidRoleName => {
+                "Names may only contain lowercase letters, digits, '-' and '_'"
+            }
+            ApiError::UnknownPermission(_) => "Unknown permission",
+            ApiError::RoleExists => "Role already exists",
+            ApiError::RoleInUse => "The role is assigned to users",
+            ApiError::CustomRoleNotFound => "Role not found or not editable",
+            ApiError::GroupNotFound => "Group not found",
+            ApiError::GroupExists => "Group already exists",
+            ApiError::MemberNotFound => "Member not found",
+            ApiError::RuleNotFound => "Rule not found",
+            ApiError::InvalidDestinationPattern => "Invalid destination pattern",
+            ApiError::ConnectionNotFound => "Connection not found",
+            ApiError::ConnectionFieldsRequired => "Name and host are required",
+            ApiError::InvalidProtocol => "Protocol must be 'rdp' or 'ssh'",
+            ApiError::UnknownGroup => "Group does not exist",
+            ApiError::SsoNotConfigured => "SSO is not configured",
+            ApiError::IdentityProviderUnavailable => "Identity provider unavailable",
+            ApiError::TooManyPendingLogins => "Too many logins in progress; try again",
+            ApiError::SsoDenied => "The identity provider refused the login",
+            ApiError::SsoMissingParameters => "Missing parameters",
+            ApiError::SsoSessionInvalid => "Invalid or expired login session",
+            ApiError::SsoFailed => "SSO authentication failed",
+            ApiError::SsoMissingUsername => "The token has no username",
+            ApiError::SsoMissingSubject => "The token has no subject",
+        }
+    }
+
+    fn body(&self, lang: Lang) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "error": self.message(lang),
+            "code": self.code(),
+        });
+        match self {
+            ApiError::PasswordPolicy(violations) => {
+                body["violations"] = violations.iter().map(|v| v.to_json(lang)).collect();
+            }
+            ApiError::UnknownScope(scope) => body["scope"] = scope.as_str().into(),
+            ApiError::UnknownPermission(permission) => {
+                body["permission"] = permission.as_str().into();
+            }
+            ApiError::InvalidTokenLifetime(max_days) => body["max_days"] = (*max_days).into(),
+            _ => {}
+        }
+        body
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let lang = Lang::current();
+        let mut res = (self.status(), Json(self.body(lang))).into_response();
+        res.headers_mut().insert(
+            header::CONTENT_LANGUAGE,
+            HeaderValue::from_static(lang.tag()),
+        );
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn render(lang: Lang, error: ApiError) -> (StatusCode, String, serde_json::Value) {
+        let res = LANG.scope(lang, async { error.into_response() }).await;
+        let status = res.status();
+        let language = res.headers()[header::CONTENT_LANGUAGE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, language, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn bodies_carry_a_code_and_details_in_the_request_language() {
+        let (status, language, body) =
+            render(Lang::En, ApiError::UnknownScope("bogus".into())).await;
+        assert_eq!((status, language.as_str()), (StatusCode::BAD_REQUEST, "en"));
+        assert_eq!(body["code"], "token.unknown_scope");
+        assert_eq!(body["error"], "Unknown scope");
+        assert_eq!(body["scope"], "bogus");
+
+        let (status, language, body) = render(Lang::PtBr, ApiError::RouteNotFound).await;
+        assert_eq!(
+            (status, language.as_str()),
+            (StatusCode::NOT_FOUND, "pt-BR")
+        );
+        assert_eq!(body["code"], "route.not_found");
+        assert_eq!(body["error"], "Rota não encontrada");
+    }
+}
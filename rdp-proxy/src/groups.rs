@@ -0,0 +1,445 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::db::{DestinationRule, Group, User};
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::rbac::{self, require_permission};
+use crate::AppState;
+
+async fn find_group(state: &AppState, id: &str) -> Result<Group, ApiError> {
+    state
+        .db
+        .get_group(id)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::GroupNotFound)
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups",
+    tag = "groups",
+    responses(
+        (status = 200, body = [Group]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_groups(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Group>>, ApiError> {
+    require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    let groups = state.db.list_groups().await.map_err(ApiError::internal)?;
+
+    Ok(Json(groups))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups",
+    tag = "groups",
+    request_body = CreateGroupRequest,
+    responses(
+        (status = 201, body = Group),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn create_group(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateGroupRequest>,
+) -> Result<(StatusCode, Json<Group>), ApiError> {
+    let claims = require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::NameRequired);
+    }
+
+    let group = state
+        .db
+        .create_group(name, body.description.as_deref().unwrap_or(""))
+        .await
+        .map_err(|e| {
+            if crate::storage::is_unique_violation(&e) {
+                ApiError::GroupExists
+            } else {
+                ApiError::internal(e)
+            }
+        })?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.create",
+        &group.id,
+        serde_json::json!({ "name": group.name }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(group)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct GroupDetail {
+    #[serde(flatten)]
+    pub group: Group,
+    pub members: Vec<User>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups/{id}",
+    tag = "groups",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = GroupDetail),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_group(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<GroupDetail>, ApiError> {
+    require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    let group = find_group(&state, &id).await?;
+    let members = state
+        .db
+        .list_group_members(&id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(GroupDetail { group, members }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateGroupRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/groups/{id}",
+    tag = "groups",
+    params(("id" = String, Path)),
+    request_body = UpdateGroupRequest,
+    responses(
+        (status = 200, body = Group),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn update_group(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<UpdateGroupRequest>,
+) -> Result<Json<Group>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    if body.name.as_deref().is_some_and(|n| n.trim().is_empty()) {
+        return Err(ApiError::NameRequired);
+    }
+
+    let group = state
+        .db
+        .update_group(
+            &id,
+            body.name.as_deref().map(str::trim),
+            body.description.as_deref(),
+        )
+        .await
+        .map_err(|e| {
+            if crate::storage::is_unique_violation(&e) {
+                ApiError::GroupExists
+            } else {
+                ApiError::internal(e)
+            }
+        })?
+        .ok_or(ApiError::GroupNotFound)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.update",
+        &id,
+        serde_json::json!({ "name": group.name }),
+    )
+    .await;
+
+    Ok(Json(group))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/groups/{id}",
+    tag = "groups",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn delete_group(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    let deleted = state
+        .db
+        .delete_group(&id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    if !deleted {
+        return Err(ApiError::GroupNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.delete",
+        &id,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    put,
+    path = "/groups/{id}/members/{user_id}",
+    tag = "groups",
+    params(("id" = String, Path), ("user_id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((id, user_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    find_group(&state, &id).await?;
+    state
+        .db
+        .get_user_by_id(&user_id)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?;
+
+    state
+        .db
+        .add_group_member(&id, &user_id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.member_add",
+        &id,
+        serde_json::json!({ "user_id": user_id }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/groups/{id}/members/{user_id}",
+    tag = "groups",
+    params(("id" = String, Path), ("user_id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((id, user_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::GROUPS_ADMIN).await?;
+
+    let removed = state
+        .db
+        .remove_group_member(&id, &user_id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    if !removed {
+        return Err(ApiError::MemberNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.member_remove",
+        &id,
+        serde_json::json!({ "user_id": user_id }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups/{id}/rules",
+    tag = "groups",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = [DestinationRule]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn list_rules(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<DestinationRule>>, ApiError> {
+    require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    find_group(&state, &id).await?;
+    let rules = state
+        .db
+        .list_destination_rules(&id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(rules))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateRuleRequest {
+    pub pattern: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/groups/{id}/rules",
+    tag = "groups",
+    params(("id" = String, Path)),
+    request_body = CreateRuleRequest,
+    responses(
+        (status = 201, body = DestinationRule),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn create_rule(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<CreateRuleRequest>,
+) -> Result<(StatusCode, Json<DestinationRule>), ApiError> {
+    let claims = require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    let pattern = body.pattern.trim();
+    if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+        return Err(ApiError::InvalidDestinationPattern);
+    }
+
+    find_group(&state, &id).await?;
+    let rule = state
+        .db
+        .create_destination_rule(&id, pattern)
+        .await
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.rule_create",
+        &id,
+        serde_json::json!({ "pattern": rule.pattern }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/groups/{id}/rules/{rule_id}",
+    tag = "groups",
+    params(("id" = String, Path), ("rule_id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn delete_rule(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((id, rule_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::CONNECTIONS_ADMIN).await?;
+
+    let deleted = state
+        .db
+        .delete_destination_rule(&id, &rule_id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    if !deleted {
+        return Err(ApiError::RuleNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "group.rule_delete",
+        &id,
+        serde_json::json!({ "rule_id": rule_id }),
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
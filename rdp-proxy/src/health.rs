@@ -0,0 +1,106 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::json;
+
+use crate::AppState;
+
+/// A check that takes longer than this counts as failed, so a wedged
+/// database makes the server unready instead of hanging the probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn info(state: &AppState, status: &str) -> serde_json::Value {
+    json!({
+        "status": status,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+    })
+}
+
+/// `GET /healthz`: the process is up and serving requests.
+pub async fn healthz(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(info(&state, "ok"))
+}
+
+/// `GET /readyz`: the database answers, every volume has room left and the
+/// server is not shutting down. 503 otherwise.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let database = match tokio::time::timeout(CHECK_TIMEOUT, state.db.ping()).await {
+        Ok(Ok(())) => json!({ "ok": true }),
+        Ok(Err(e)) => json!({ "ok": false, "error": format!("{e:#}") }),
+        Err(_) => json!({ "ok": false, "error": "timed out" }),
+    };
+
+    let min_free = state.config.health.min_free_mb * 1024 * 1024;
+    let volumes = volumes(&state);
+    let disk = tokio::time::timeout(
+        CHECK_TIMEOUT,
+        tokio::task::spawn_blocking(move || {
+            volumes
+                .iter()
+                .map(|path| match free_bytes(path) {
+                    Ok(free) => json!({
+                        "path": path,
+                        "free_mb": free / 1024 / 1024,
+                        "ok": free >= min_free,
+                    }),
+                    Err(e) => json!({ "path": path, "ok": false, "error": e.to_string() }),
+                })
+                .collect::<Vec<_>>()
+        }),
+    )
+    .await;
+    let disk = match disk {
+        Ok(Ok(disk)) => disk,
+        _ => vec![json!({ "ok": false, "error": "timed out" })],
+    };
+
+    let draining = state.draining.load(Ordering::Relaxed);
+    let healthy = database["ok"] == true && disk.iter().all(|d| d["ok"] == true);
+    let (code, status) = match (draining, healthy) {
+        (true, _) => (StatusCode::SERVICE_UNAVAILABLE, "draining"),
+        (false, true) => (StatusCode::OK, "ready"),
+        (false, false) => (StatusCode::SERVICE_UNAVAILABLE, "not_ready"),
+    };
+
+    let mut body = info(&state, status);
+    body["checks"] = json!({ "database": database, "disk": disk });
+    (code, Json(body))
+}
+
+/// The SQLite file's directory plus any configured volumes.
+fn volumes(state: &AppState) -> Vec<PathBuf> {
+    let mut volumes = Vec::new();
+    if state.config.database.url.is_none() {
+        let dir = state
+            .config
+            .database
+            .path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        volumes.push(dir.to_path_buf());
+    }
+    volumes.extend(state.config.health.volumes.iter().cloned());
+    volumes
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[allow(clippy::unnecessary_cast)]
+fn free_bytes(path: &Path) -> std::io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid out-pointer.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // The field types differ between platforms.
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
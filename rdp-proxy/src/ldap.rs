@@ -0,0 +1,460 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use async_trait::async_trait;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+pub struct LdapConfig {
+    pub url: String,
+    pub starttls: bool,
+    pub tls_insecure: bool,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    pub user_filter: String,
+    pub display_name_attr: String,
+    pub group_attr: String,
+    pub admin_groups: Vec<String>,
+    pub user_groups: Vec<String>,
+    pub local_fallback: bool,
+}
+
+impl LdapConfig {
+    /// Expects a validated `Config`, which has `url` and `base_dn` when LDAP
+    /// is the auth backend.
+    pub fn from_config(config: &crate::config::LdapConfig) -> anyhow::Result<Self> {
+        let url = config.url.clone().context("ldap.url is not set")?;
+        let base_dn = config.base_dn.clone().context("ldap.base_dn is not set")?;
+        // Compared with the user's groups in lowercase.
+        let lowercase = |groups: &[String]| groups.iter().map(|g| g.to_ascii_lowercase()).collect();
+
+        Ok(LdapConfig {
+            starttls: config.starttls.unwrap_or(url.starts_with("ldap://")),
+            tls_insecure: config.tls_insecure,
+            bind_dn: config.bind_dn.clone(),
+            bind_password: config.bind_password.clone(),
+            base_dn,
+            user_filter: config.user_filter.clone(),
+            display_name_attr: config.display_name_attr.clone(),
+            group_attr: config.group_attr.clone(),
+            admin_groups: lowercase(&config.admin_groups),
+            user_groups: lowercase(&config.user_groups),
+            local_fallback: config.local_fallback,
+            url,
+        })
+    }
+}
+
+pub struct DirectoryUser {
+    /// Stable across renames and moves in the directory; see `entry_id`.
+    pub id: String,
+    pub display_name: String,
+    pub role: &'static str,
+}
+
+pub enum LdapOutcome {
+    Authenticated(DirectoryUser),
+    InvalidCredentials,
+    /// The user is not in the directory (or not in any allowed group).
+    NotFound,
+}
+
+pub struct Ldap {
+    config: LdapConfig,
+}
+
+impl Ldap {
+    pub fn new(config: LdapConfig) -> Self {
+        Ldap { config }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    pub fn local_fallback(&self) -> bool {
+        self.config.local_fallback
+    }
+
+    /// Search-then-bind: find the user's DN with the service account, then
+    /// bind as that DN with the supplied password.
+    pub async fn authenticate(&self, username: &str, password: &str) -> anyhow::Result<LdapOutcome> {
+        // An empty password would be an unauthenticated bind, which most
+        // servers accept without checking anything.
+        if password.is_empty() {
+            return Ok(LdapOutcome::InvalidCredentials);
+        }
+
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(10))
+            .set_starttls(self.config.starttls)
+            .set_no_tls_verify(self.config.tls_insecure);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .context("Failed to connect to LDAP server")?;
+        ldap3::drive!(conn);
+
+        let outcome = self.search_and_bind(&mut ldap, username, password).await;
+        let _ = ldap.unbind().await;
+        outcome
+    }
+
+    async fn search_and_bind(
+        &self,
+        directory: &mut dyn Directory,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<LdapOutcome> {
+        if let (Some(dn), Some(pw)) = (&self.config.bind_dn, &self.config.bind_password) {
+            let rc = directory.bind(dn, pw).await?;
+            if rc != 0 {
+                return Err(anyhow!("LDAP service account bind failed (rc={rc})"));
+            }
+        }
+
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let mut entries = directory
+            .search(
+                &self.config.base_dn,
+                &filter,
+                vec![
+                    self.config.display_name_attr.as_str(),
+                    self.config.group_attr.as_str(),
+                    OBJECT_GUID,
+                    ENTRY_UUID,
+                ],
+            )
+            .await
+            .context("LDAP user search failed")?;
+
+        let entry = match entries.len() {
+            0 => return Ok(LdapOutcome::NotFound),
+            1 => entries.remove(0),
+            n => return Err(anyhow!("LDAP filter matched {n} entries for {username}")),
+        };
+
+        // Only a rejected password is the user's fault; anything else, such
+        // as a locked-down or busy server, is reported as an outage.
+        match directory.bind(&entry.dn, password).await? {
+            0 => {}
+            INVALID_CREDENTIALS => return Ok(LdapOutcome::InvalidCredentials),
+            rc => return Err(anyhow!("LDAP bind as {} failed (rc={rc})", entry.dn)),
+        }
+
+        let groups: Vec<String> = entry
+            .attrs
+            .get(&self.config.group_attr)
+            .map(|v| v.iter().map(|g| g.to_ascii_lowercase()).collect())
+            .unwrap_or_default();
+        let in_any = |wanted: &[String]| groups.iter().any(|g| wanted.contains(g));
+
+        let role = if in_any(&self.config.admin_groups) {
+            "admin"
+        } else if self.config.user_groups.is_empty() || in_any(&self.config.user_groups) {
+            "user"
+        } else {
+            return Ok(LdapOutcome::NotFound);
+        };
+
+        let display_name = entry
+            .attrs
+            .get(&self.config.display_name_attr)
+            .and_then(|v| v.first())
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(LdapOutcome::Authenticated(DirectoryUser {
+            id: entry_id(&entry),
+            display_name,
+            role,
+        }))
+    }
+}
+
+/// Active Directory's immutable object identifier, 16 bytes.
+const OBJECT_GUID: &str = "objectGUID";
+/// The RFC 4530 equivalent on OpenLDAP and most other servers.
+const ENTRY_UUID: &str = "entryUUID";
+
+/// Identifies the entry for linking to a local user: its GUID or UUID when
+/// the server has one, else its DN. Login names can be reassigned to someone
+/// else, so they are never used.
+fn entry_id(entry: &SearchEntry) -> String {
+    // ldap3 only puts values that are not valid UTF-8 in `bin_attrs`.
+    let guid = entry
+        .bin_attrs
+        .get(OBJECT_GUID)
+        .and_then(|v| v.first())
+        .map(Vec::as_slice)
+        .or_else(|| {
+            entry
+                .attrs
+                .get(OBJECT_GUID)
+                .and_then(|v| v.first())
+                .map(|v| v.as_bytes())
+        });
+    if let Some(Ok(bytes)) = guid.map(<[u8; 16]>::try_from) {
+        return uuid::Uuid::from_bytes_le(bytes).to_string();
+    }
+    if let Some(uuid) = entry.attrs.get(ENTRY_UUID).and_then(|v| v.first()) {
+        return uuid.to_ascii_lowercase();
+    }
+    entry.dn.to_ascii_lowercase()
+}
+
+/// LDAP result code for a simple bind with a wrong DN or password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// The operations `Ldap::authenticate` performs on a connection.
+#[async_trait]
+trait Directory: Send {
+    /// Simple bind. Returns the LDAP result code.
+    async fn bind(&mut self, dn: &str, password: &str) -> anyhow::Result<u32>;
+    /// Subtree search under `base`.
+    async fn search(
+        &mut self,
+        base: &str,
+        filter: &str,
+        attrs: Vec<&str>,
+    ) -> anyhow::Result<Vec<SearchEntry>>;
+}
+
+#[async_trait]
+impl Directory for ldap3::Ldap {
+    async fn bind(&mut self, dn: &str, password: &str) -> anyhow::Result<u32> {
+        Ok(self.simple_bind(dn, password).await?.rc)
+    }
+
+    async fn search(
+        &mut self,
+        base: &str,
+        filter: &str,
+        attrs: Vec<&str>,
+    ) -> anyhow::Result<Vec<SearchEntry>> {
+        let (entries, _) = ldap3::Ldap::search(self, base, Scope::Subtree, filter, attrs)
+            .await?
+            .success()?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const SERVICE_DN: &str = "cn=koder,ou=services,dc=example,dc=com";
+    const ADMINS: &str = "cn=koder-admins,ou=groups,dc=example,dc=com";
+    const STAFF: &str = "cn=staff,ou=groups,dc=example,dc=com";
+    /// `objectGUID` bytes are little-endian in the first three fields.
+    const ALICE_GUID: [u8; 16] = [
+        0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    const BOB_UUID: &str = "5c1e8f4a-2b6d-4e0a-9f3c-7d8e9a0b1c2d";
+
+    /// An in-memory directory: users by `sAMAccountName`, with their DN,
+    /// password and groups. alice has an AD `objectGUID`, bob an
+    /// `entryUUID` and carol neither.
+    struct Stub {
+        users: Vec<(&'static str, &'static str, &'static str, Vec<&'static str>)>,
+        /// Result code for every user bind, to simulate server failures.
+        bind_rc: Option<u32>,
+    }
+
+    impl Stub {
+        fn new() -> Self {
+            Stub {
+                users: vec![
+                    (
+                        "alice",
+                        "cn=alice,dc=example,dc=com",
+                        "alice-pw",
+                        vec![ADMINS, STAFF],
+                    ),
+                    ("bob", "cn=bob,dc=example,dc=com", "bob-pw", vec![STAFF]),
+                    ("carol", "cn=carol,dc=example,dc=com", "carol-pw", vec![]),
+                ],
+                bind_rc: None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Directory for Stub {
+        async fn bind(&mut self, dn: &str, password: &str) -> anyhow::Result<u32> {
+            if dn == SERVICE_DN {
+                return Ok(if password == "service-pw" {
+                    0
+                } else {
+                    INVALID_CREDENTIALS
+                });
+            }
+            if let Some(rc) = self.bind_rc {
+                return Ok(rc);
+            }
+            let ok = self
+                .users
+                .iter()
+                .any(|(_, user_dn, pw, _)| *user_dn == dn && *pw == password);
+            Ok(if ok { 0 } else { INVALID_CREDENTIALS })
+        }
+
+        async fn search(
+            &mut self,
+            base: &str,
+            filter: &str,
+            _attrs: Vec<&str>,
+        ) -> anyhow::Result<Vec<SearchEntry>> {
+            assert_eq!(base, "dc=example,dc=com");
+            Ok(self
+                .users
+                .iter()
+                .filter(|(name, ..)| filter.contains(&format!("(sAMAccountName={name})")))
+                .map(|(name, dn, _, groups)| {
+                    let mut attrs = HashMap::from([
+                        ("displayName".to_string(), vec![name.to_uppercase()]),
+                        (
+                            "memberOf".to_string(),
+                            groups.iter().map(|g| g.to_string()).collect(),
+                        ),
+                    ]);
+                    let mut bin_attrs = HashMap::new();
+                    match *name {
+                        "alice" => {
+                            bin_attrs.insert(OBJECT_GUID.to_string(), vec![ALICE_GUID.to_vec()]);
+                        }
+                        "bob" => {
+                            attrs.insert(ENTRY_UUID.to_string(), vec![BOB_UUID.to_uppercase()]);
+                        }
+                        _ => {}
+                    }
+                    SearchEntry {
+                        dn: dn.to_string(),
+                        attrs,
+                        bin_attrs,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    fn ldap(user_groups: &[&str]) -> Ldap {
+        Ldap::new(LdapConfig {
+            url: "ldap://directory.test".to_string(),
+            starttls: false,
+            tls_insecure: false,
+            bind_dn: Some(SERVICE_DN.to_string()),
+            bind_password: Some("service-pw".to_string()),
+            base_dn: "dc=example,dc=com".to_string(),
+            user_filter: "(&(objectClass=user)(sAMAccountName={username}))".to_string(),
+            display_name_attr: "displayName".to_string(),
+            group_attr: "memberOf".to_string(),
+            admin_groups: vec![ADMINS.to_string()],
+            user_groups: user_groups.iter().map(|g| g.to_string()).collect(),
+            local_fallback: false,
+        })
+    }
+
+    async fn authenticate(ldap: &Ldap, stub: &mut Stub, user: &str, pw: &str) -> LdapOutcome {
+        ldap.search_and_bind(stub, user, pw).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn binds_as_the_user() {
+        let outcome = authenticate(&ldap(&[]), &mut Stub::new(), "bob", "bob-pw").await;
+        let LdapOutcome::Authenticated(user) = outcome else {
+            panic!("bob was not authenticated");
+        };
+        assert_eq!(user.display_name, "BOB");
+        assert_eq!(user.role, "user");
+    }
+
+    #[tokio::test]
+    async fn identifies_users_by_directory_id_rather_than_name() {
+        let ldap = ldap(&[]);
+        let mut stub = Stub::new();
+        let mut ids = Vec::new();
+        for (user, pw) in [
+            ("alice", "alice-pw"),
+            ("bob", "bob-pw"),
+            ("carol", "carol-pw"),
+        ] {
+            let LdapOutcome::Authenticated(entry) = authenticate(&ldap, &mut stub, user, pw).await
+            else {
+                panic!("{user} was not authenticated");
+            };
+            ids.push(entry.id);
+        }
+        assert_eq!(
+            ids,
+            [
+                "00112233-4455-6677-8899-aabbccddeeff",
+                BOB_UUID,
+                "cn=carol,dc=example,dc=com",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_password() {
+        let outcome = authenticate(&ldap(&[]), &mut Stub::new(), "bob", "alice-pw").await;
+        assert!(matches!(outcome, LdapOutcome::InvalidCredentials));
+    }
+
+    #[tokio::test]
+    async fn reports_an_unknown_user_as_not_found() {
+        let outcome = authenticate(&ldap(&[]), &mut Stub::new(), "mallory", "pw").await;
+        assert!(matches!(outcome, LdapOutcome::NotFound));
+    }
+
+    #[tokio::test]
+    async fn escapes_the_username_in_the_filter() {
+        let outcome = authenticate(
+            &ldap(&[]),
+            &mut Stub::new(),
+            "*)(sAMAccountName=bob",
+            "bob-pw",
+        )
+        .await;
+        assert!(matches!(outcome, LdapOutcome::NotFound));
+    }
+
+    #[tokio::test]
+    async fn maps_groups_to_roles() {
+        let ldap = ldap(&[STAFF]);
+        let mut stub = Stub::new();
+
+        let outcome = authenticate(&ldap, &mut stub, "alice", "alice-pw").await;
+        assert!(matches!(outcome, LdapOutcome::Authenticated(u) if u.role == "admin"));
+        let outcome = authenticate(&ldap, &mut stub, "bob", "bob-pw").await;
+        assert!(matches!(outcome, LdapOutcome::Authenticated(u) if u.role == "user"));
+        // Not in an allowed group: treated as unknown to the directory.
+        let outcome = authenticate(&ldap, &mut stub, "carol", "carol-pw").await;
+        assert!(matches!(outcome, LdapOutcome::NotFound));
+    }
+
+    #[tokio::test]
+    async fn reports_server_failures_as_errors() {
+        // unwillingToPerform, e.g. a server that refuses simple binds.
+        let mut stub = Stub {
+            bind_rc: Some(53),
+            ..Stub::new()
+        };
+        assert!(ldap(&[])
+            .search_and_bind(&mut stub, "bob", "bob-pw")
+            .await
+            .is_err());
+
+        let mut config = ldap(&[]);
+        config.config.bind_password = Some("stale".to_string());
+        let result = config
+            .search_and_bind(&mut Stub::new(), "bob", "bob-pw")
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -1,63 +1,382 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use axum::routing::{delete, get, post, put};
+use axum::http::HeaderValue;
+use axum::middleware;
+use axum::routing::{any, delete, get, post, put};
 use axum::Router;
-use tower_http::cors::CorsLayer;
-use tracing::info;
+use clap::{Parser, Subcommand};
+use tower::ServiceBuilder;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn};
 
+mod audit;
 mod auth;
+mod cli;
+mod config;
+mod connections;
 mod db;
+mod error;
+mod groups;
+mod health;
+mod ldap;
+mod metrics;
+mod migrations;
+mod oidc;
+mod openapi;
+mod origin;
+mod password;
+mod password_policy;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod rbac;
 mod rdp;
+mod roles;
+mod sessions;
+mod setup;
+mod storage;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod tokens;
 mod users;
+mod web;
 
 pub struct AppState {
-    pub db: db::Database,
+    pub config: config::Config,
+    pub db: Arc<dyn storage::Storage>,
     pub jwt_secret: String,
+    pub auth_backend: auth::AuthBackend,
+    pub oidc: Option<oidc::Oidc>,
+    pub password_policy: password_policy::PasswordPolicy,
+    pub hasher: password::Hasher,
+    pub metrics: metrics::Metrics,
+    pub started_at: std::time::Instant,
+    /// Set once shutdown starts, so `/readyz` fails while connections finish.
+    pub draining: AtomicBool,
+    pub sessions: sessions::Sessions,
+    /// Pending one-time token for `/api/setup`, while no users exist.
+    pub setup_token: tokio::sync::Mutex<Option<String>>,
+}
+
+#[derive(Parser)]
+#[command(version, about = "koder server: web API and RDP WebSocket proxy")]
+struct Cli {
+    /// TOML configuration file.
+    #[arg(long, env = "KODER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Address to listen on [env: PROXY_LISTEN]
+    #[arg(long, global = true)]
+    listen: Option<SocketAddr>,
+    /// SQLite database file [env: DB_PATH]
+    #[arg(long, global = true)]
+    db_path: Option<PathBuf>,
+    /// PostgreSQL URL; takes precedence over --db-path [env: DATABASE_URL]
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    /// Validate the configuration, print it with secrets redacted and exit.
+    #[arg(long)]
+    check_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default).
+    Serve,
+    /// Manage users.
+    #[command(subcommand)]
+    User(cli::UserCommand),
+    /// Manage API tokens.
+    #[command(subcommand)]
+    Token(cli::TokenCommand),
+    /// Back up or migrate the database.
+    #[command(subcommand)]
+    Db(cli::DbCommand),
+    /// Inspect the audit log.
+    #[command(subcommand)]
+    Audit(cli::AuditCommand),
+    /// Same as `db migrate`.
+    #[command(hide = true)]
+    Migrate {
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "rdp_proxy=info".into()),
-        )
-        .init();
+    let cli = Cli::parse();
+    let config = config::Config::load(
+        cli.config.as_deref(),
+        config::Overrides {
+            listen: cli.listen,
+            db_path: cli.db_path,
+            database_url: cli.database_url,
+        },
+    )?;
+
+    if cli.check_config {
+        print!("{}", config.redacted()?);
+        return Ok(());
+    }
+    let _telemetry = telemetry::init(&config)?;
+
+    let database = storage::open(&config.database).await?;
 
-    let db_path = std::env::var("DB_PATH").unwrap_or_else(|_| "/data/koder.db".to_string());
-    let database = db::Database::new(&db_path)?;
-    database.initialize()?;
+    let db = database.as_ref();
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {}
+        Command::Db(command) => return cli::database(db, command).await,
+        Command::Migrate { dry_run } => return cli::migrate(db, dry_run).await,
+        command => {
+            db.initialize().await?;
+            return match command {
+                Command::User(command) => cli::user(db, &config, command).await,
+                Command::Token(command) => cli::token(db, command).await,
+                Command::Audit(command) => cli::audit(db, command).await,
+                _ => unreachable!(),
+            };
+        }
+    }
 
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+    database.initialize().await?;
+    let hasher = password::Hasher::new(&config.argon2)?;
+    let setup_token = setup::bootstrap(database.as_ref(), &hasher).await?;
+
+    let jwt_secret = match &config.auth.jwt_secret {
+        Some(secret) => {
+            if secret.len() < 32 {
+                warn!("JWT secret is shorter than 32 characters");
+            }
+            secret.clone()
+        }
+        None => {
+            warn!("No JWT secret configured; sessions will not survive a restart");
+            uuid::Uuid::new_v4().to_string()
+        }
+    };
+
+    let auth_backend = auth::AuthBackend::new(&config)?;
+    if let auth::AuthBackend::Ldap(ldap) = &auth_backend {
+        info!("LDAP authentication enabled ({})", ldap.url());
+    }
+
+    let oidc = oidc::OidcConfig::from_config(&config.oidc)?
+        .map(oidc::Oidc::new)
+        .transpose()?;
+    if let Some(o) = &oidc {
+        info!("OIDC single sign-on enabled ({})", o.issuer());
+    }
+
+    let password_policy = password_policy::PasswordPolicy::new(&config.password)?;
+    info!(
+        "Password policy: min length {}, {} character classes, history {}, {} blocklisted",
+        password_policy.min_length,
+        password_policy.min_classes,
+        password_policy.history,
+        password_policy.blocklist_len()
+    );
+
+    let listen_addr = config.server.listen;
+    let tls = tls::load(&config.tls).await?;
+    let web = web::router(config.server.web_root.as_deref());
+    match (&web, &config.server.web_root) {
+        (None, _) => info!("No web bundle configured; serving the API only"),
+        (Some(_), Some(root)) => info!("Serving the web app from {}", root.display()),
+        (Some(_), None) => info!("Serving the embedded web app"),
+    }
 
     let state = Arc::new(AppState {
+        config,
         db: database,
         jwt_secret,
+        auth_backend,
+        oidc,
+        password_policy,
+        hasher,
+        metrics: metrics::Metrics::new()?,
+        started_at: std::time::Instant::now(),
+        draining: AtomicBool::new(false),
+        sessions: sessions::Sessions::new(),
+        setup_token: tokio::sync::Mutex::new(setup_token),
     });
 
+    let app = router(state.clone(), web)?;
+
+    match tls {
+        Some(tls) => {
+            info!("koder server listening on https://{listen_addr}");
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal(state).await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(listen_addr, tls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            info!("koder server listening on http://{listen_addr}");
+            let listener = tokio::net::TcpListener::bind(listen_addr).await?;
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal(state))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The whole HTTP surface: the API, the RDP WebSocket, health checks and
+/// the web app, if any.
+fn router(state: Arc<AppState>, web: Option<Router>) -> anyhow::Result<Router> {
+    // Same-origin requests need no CORS headers; `origin::check` refuses
+    // preflights from anywhere else.
+    let origins = state
+        .config
+        .server
+        .cors_origins
+        .iter()
+        .map(|o| HeaderValue::from_str(o))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cors = CorsLayer::permissive().allow_origin(AllowOrigin::list(origins));
+
+    let api = Router::new()
+        .route("/setup", get(setup::status))
+        .route("/setup", post(setup::setup))
+        .route("/auth/login", post(auth::login))
+        .route("/auth/me", get(auth::me))
+        .route("/auth/password", put(auth::change_password))
+        .route("/auth/oidc/login", get(oidc::login))
+        .route("/auth/oidc/callback", get(oidc::callback))
+        .route("/users", post(users::create_user))
+        .route("/users/:id", get(users::get_user))
+        .route("/users/:id", put(users::update_user))
+        .route("/users/:id", delete(users::delete_user))
+        .route("/roles", get(roles::list_roles))
+        .route("/roles", post(roles::create_role))
+        .route("/roles/:name", put(roles::update_role))
+        .route("/roles/:name", delete(roles::delete_role))
+        .route("/permissions", get(roles::list_permissions))
+        .route("/groups", get(groups::list_groups))
+        .route("/groups", post(groups::create_group))
+        .route("/groups/:id", get(groups::get_group))
+        .route("/groups/:id", put(groups::update_group))
+        .route("/groups/:id", delete(groups::delete_group))
+        .route("/groups/:id/members/:user_id", put(groups::add_member))
+        .route("/groups/:id/members/:user_id", delete(groups::remove_member))
+        .route("/groups/:id/rules", get(groups::list_rules))
+        .route("/groups/:id/rules", post(groups::create_rule))
+        .route("/groups/:id/rules/:rule_id", delete(groups::delete_rule))
+        .route("/connections", get(connections::list_connections))
+        .route("/connections", post(connections::create_connection))
+        .route("/connections/:id", put(connections::update_connection))
+        .route("/connections/:id", delete(connections::delete_connection))
+        .route("/tokens", get(tokens::list_tokens))
+        .route("/tokens", post(tokens::create_token))
+        .route("/tokens/:id", delete(tokens::revoke_token))
+        .route("/sessions", get(sessions::list_sessions))
+        .route("/sessions/:id", delete(sessions::terminate_session));
     let app = Router::new()
-        .route("/api/auth/login", post(auth::login))
-        .route("/api/auth/me", get(auth::me))
-        .route("/api/auth/password", put(auth::change_password))
-        .route("/api/users", get(users::list_users))
-        .route("/api/users", post(users::create_user))
-        .route("/api/users/{id}", get(users::get_user))
-        .route("/api/users/{id}", put(users::update_user))
-        .route("/api/users/{id}", delete(users::delete_user))
+        .nest(
+            "/api/v1",
+            api.clone().route("/users", get(users::list_users)),
+        )
+        // Unversioned paths from before /api/v1, for older clients.
+        .nest(
+            "/api",
+            api.route("/users", get(users::list_users_unversioned)),
+        )
+        .route("/api/openapi.json", get(openapi::handler))
         .route("/rdp-proxy", get(rdp::ws_handler))
-        .layer(CorsLayer::permissive())
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ));
+    let app = if state.config.metrics.enabled {
+        app.route("/metrics", get(metrics::handler))
+    } else {
+        app
+    };
+    let app = app
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+    let app = match web {
+        Some(web) => app
+            .route("/api/*path", any(web::api_not_found))
+            .fallback_service(web),
+        None => app,
+    };
+    let app = app
+        .layer(cors)
+        .layer(middleware::from_fn_with_state(state.clone(), origin::check))
+        .layer(middleware::from_fn(error::localize))
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(TraceLayer::new_for_http().make_span_with(request_span))
+                .layer(PropagateRequestIdLayer::x_request_id()),
+        )
         .with_state(state);
+    Ok(app)
+}
 
-    let listen_addr: SocketAddr = std::env::var("PROXY_LISTEN")
-        .unwrap_or_else(|_| "127.0.0.1:8443".to_string())
-        .parse()?;
-
-    info!("koder server listening on {listen_addr}");
+/// Tags everything logged while handling a request with its id, which is
+/// echoed back in `x-request-id`.
+fn request_span(req: &axum::http::Request<axum::body::Body>) -> tracing::Span {
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        request_id,
+    )
+}
 
-    let listener = tokio::net::TcpListener::bind(listen_addr).await?;
-    axum::serve(listener, app).await?;
+/// Resolves on SIGTERM or Ctrl-C, after marking the server as draining and
+/// letting RDP relays finish. The listener stays open meanwhile, so the API
+/// and `/readyz` keep answering; new relays are refused.
+async fn shutdown_signal(state: Arc<AppState>) {
+    shutdown_requested().await;
+    info!("Shutting down");
+    state.draining.store(true, Ordering::Relaxed);
+    let grace = std::time::Duration::from_secs(state.config.timeouts.shutdown_grace_secs);
+    state.sessions.drain(grace).await;
+}
 
-    Ok(())
+/// Resolves on the next SIGTERM or Ctrl-C.
+pub async fn shutdown_requested() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
 }
@@ -0,0 +1,208 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder as _, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::ApiError;
+use crate::AppState;
+
+/// Handshake stages are network round trips, so the buckets go from 5 ms to
+/// the default handshake timeout.
+const HANDSHAKE_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// Relays currently running, by protocol.
+    pub active_sessions: IntGaugeVec,
+    /// Bytes relayed, by direction: `client_to_server` or `server_to_client`.
+    pub relayed_bytes: IntCounterVec,
+    /// Handshake stage durations: `dns`, `tcp`, `x224` and `tls`.
+    pub handshake_seconds: HistogramVec,
+    /// Failed RDCleanPath handshakes, by reason.
+    pub rdcleanpath_errors: IntCounterVec,
+    /// Logins by method (`password`, `oidc`) and result (`success`,
+    /// `failure`, `disabled`, `expired`, `conflict`).
+    pub logins: IntCounterVec,
+    /// API request latency by method, route pattern and status.
+    pub http_request_seconds: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("koder".to_string()), None)?;
+        let metrics = Metrics {
+            active_sessions: IntGaugeVec::new(
+                Opts::new("active_sessions", "Relays currently running"),
+                &["protocol"],
+            )?,
+            relayed_bytes: IntCounterVec::new(
+                Opts::new(
+                    "relayed_bytes_total",
+                    "Bytes relayed between client and server",
+                ),
+                &["direction"],
+            )?,
+            handshake_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "handshake_duration_seconds",
+                    "Duration of each handshake stage",
+                )
+                .buckets(HANDSHAKE_BUCKETS.to_vec()),
+                &["stage"],
+            )?,
+            rdcleanpath_errors: IntCounterVec::new(
+                Opts::new("rdcleanpath_errors_total", "Failed RDCleanPath handshakes"),
+                &["reason"],
+            )?,
+            logins: IntCounterVec::new(
+                Opts::new("logins_total", "Login attempts"),
+                &["method", "result"],
+            )?,
+            http_request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "API request latency"),
+                &["method", "route", "status"],
+            )?,
+            registry,
+        };
+        let collectors: [Box<dyn Collector>; 6] = [
+            Box::new(metrics.active_sessions.clone()),
+            Box::new(metrics.relayed_bytes.clone()),
+            Box::new(metrics.handshake_seconds.clone()),
+            Box::new(metrics.rdcleanpath_errors.clone()),
+            Box::new(metrics.logins.clone()),
+            Box::new(metrics.http_request_seconds.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    pub fn login(&self, method: &str, result: &str) {
+        self.logins.with_label_values(&[method, result]).inc();
+    }
+
+    pub fn rdcleanpath_error(&self, reason: &str) {
+        self.rdcleanpath_errors.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts a relay as active until the guard is dropped.
+    pub fn session(&self, protocol: &str) -> SessionGuard {
+        let gauge = self.active_sessions.with_label_values(&[protocol]);
+        gauge.inc();
+        SessionGuard(gauge)
+    }
+}
+
+pub struct SessionGuard(prometheus::IntGauge);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Records request latency. Installed as a route layer, so only matched API
+/// routes are measured and labelled by their pattern, not the raw path.
+pub async fn track(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    state
+        .metrics
+        .http_request_seconds
+        .with_label_values(&[&method, &route, res.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
+
+/// `GET /metrics`, limited to loopback clients and/or a bearer token if
+/// configured.
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let config = &state.config.metrics;
+    if config.localhost_only && !peer.ip().to_canonical().is_loopback() {
+        return Err(ApiError::Forbidden);
+    }
+    if let Some(token) = &config.token {
+        let given = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(ApiError::MissingToken)?;
+        // Comparing digests keeps the comparison time independent of the token.
+        if Sha256::digest(given.as_bytes()) != Sha256::digest(token.as_bytes()) {
+            return Err(ApiError::InvalidToken);
+        }
+    }
+
+    let mut body = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder
+        .encode(&state.metrics.registry.gather(), &mut body)
+        .map_err(ApiError::internal)?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support;
+
+    async fn scrape(
+        config: Config,
+        peer: [u8; 4],
+        auth: Option<&str>,
+    ) -> Result<Response, ApiError> {
+        let (state, _dir) = test_support::state(config).await;
+        let mut headers = HeaderMap::new();
+        if let Some(auth) = auth {
+            headers.insert(header::AUTHORIZATION, auth.parse().unwrap());
+        }
+        let peer = SocketAddr::from((peer, 40000));
+        handler(State(Arc::new(state)), ConnectInfo(peer), headers).await
+    }
+
+    #[tokio::test]
+    async fn refuses_scrapes_with_coded_errors() {
+        let mut config = Config::default();
+        config.metrics.localhost_only = true;
+        config.metrics.token = Some("scrape-token".to_string());
+
+        let remote = scrape(config.clone(), [10, 0, 0, 1], Some("Bearer scrape-token")).await;
+        assert!(matches!(remote, Err(ApiError::Forbidden)));
+        let anonymous = scrape(config.clone(), [127, 0, 0, 1], None).await;
+        assert!(matches!(anonymous, Err(ApiError::MissingToken)));
+        let wrong = scrape(config.clone(), [127, 0, 0, 1], Some("Bearer other")).await;
+        assert!(matches!(wrong, Err(ApiError::InvalidToken)));
+        let res = scrape(config, [127, 0, 0, 1], Some("Bearer scrape-token"))
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+    }
+}
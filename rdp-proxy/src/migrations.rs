@@ -0,0 +1,408 @@
+use anyhow::{bail, Context as _, Result};
+use rusqlite::{Connection, Transaction};
+
+use crate::db::search_text;
+
+/// Schema changes, applied in order and recorded in `schema_version`. Never
+/// edit or reorder a released migration; append a new one instead.
+///
+/// Databases created before this runner existed have no `schema_version`
+/// table and are migrated from zero, so every step must tolerate objects
+/// that already exist.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS users (
+                id TEXT PRIMARY KEY,
+                username TEXT UNIQUE NOT NULL,
+                password_hash TEXT NOT NULL,
+                display_name TEXT NOT NULL DEFAULT '',
+                role TEXT NOT NULL DEFAULT 'user',
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );",
+        ),
+    },
+    Migration {
+        version: 2,
+        name: "external_identities",
+        step: Step::Code(|tx| {
+            add_column(tx, "users", "auth_source", "TEXT NOT NULL DEFAULT 'local'")?;
+            add_column(tx, "users", "external_id", "TEXT")?;
+            tx.execute_batch(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_users_external ON users(auth_source, external_id);",
+            )?;
+            Ok(())
+        }),
+    },
+    Migration {
+        version: 3,
+        name: "api_tokens",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                name TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                scopes TEXT NOT NULL DEFAULT '',
+                expires_at TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                last_used_at TEXT
+            );
+            CREATE INDEX IF NOT EXISTS idx_api_tokens_user ON api_tokens(user_id);",
+        ),
+    },
+    Migration {
+        version: 4,
+        name: "roles",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL DEFAULT '',
+                builtin INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+            CREATE TABLE IF NOT EXISTS role_permissions (
+                role TEXT NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+                permission TEXT NOT NULL,
+                PRIMARY KEY (role, permission)
+            );",
+        ),
+    },
+    Migration {
+        version: 5,
+        name: "groups_and_connections",
+        // Before groups, every user could reach every host. The legacy group
+        // keeps that for the users of an upgraded database until an admin
+        // sets up groups.
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS groups (
+                id TEXT PRIMARY KEY,
+                name TEXT UNIQUE NOT NULL,
+                description TEXT NOT NULL DEFAULT '',
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+            CREATE TABLE IF NOT EXISTS group_members (
+                group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                PRIMARY KEY (group_id, user_id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_group_members_user ON group_members(user_id);
+            CREATE TABLE IF NOT EXISTS connections (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                protocol TEXT NOT NULL DEFAULT 'rdp',
+                host TEXT NOT NULL,
+                port INTEGER NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+            CREATE TABLE IF NOT EXISTS group_connections (
+                group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                connection_id TEXT NOT NULL REFERENCES connections(id) ON DELETE CASCADE,
+                PRIMARY KEY (group_id, connection_id)
+            );
+            CREATE TABLE IF NOT EXISTS destination_rules (
+                id TEXT PRIMARY KEY,
+                group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                pattern TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+            INSERT INTO groups (id, name, description)
+            SELECT 'legacy-rdp-access', 'Acesso RDP legado',
+                'Criado na atualização para manter o acesso de quem já usava o proxy. Exclua após configurar os grupos.'
+            WHERE EXISTS (SELECT 1 FROM users) AND NOT EXISTS (SELECT 1 FROM groups);
+            INSERT INTO group_members (group_id, user_id)
+            SELECT 'legacy-rdp-access', id FROM users
+            WHERE EXISTS (SELECT 1 FROM groups WHERE id = 'legacy-rdp-access');
+            INSERT INTO destination_rules (id, group_id, pattern)
+            SELECT 'legacy-rdp-access', 'legacy-rdp-access', '*'
+            WHERE EXISTS (SELECT 1 FROM groups WHERE id = 'legacy-rdp-access');",
+        ),
+    },
+    Migration {
+        version: 6,
+        name: "users_must_change_password",
+        step: Step::Code(|tx| {
+            add_column(
+                tx,
+                "users",
+                "must_change_password",
+                "INTEGER NOT NULL DEFAULT 0",
+            )
+        }),
+    },
+    Migration {
+        version: 7,
+        name: "password_history",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS password_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                password_hash TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_password_history_user ON password_history(user_id);",
+        ),
+    },
+    Migration {
+        version: 8,
+        name: "users_enabled",
+        step: Step::Code(|tx| add_column(tx, "users", "enabled", "INTEGER NOT NULL DEFAULT 1")),
+    },
+    Migration {
+        version: 9,
+        name: "audit_log",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                at TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT NOT NULL DEFAULT '',
+                details TEXT NOT NULL DEFAULT '',
+                prev_hash TEXT NOT NULL,
+                hash TEXT NOT NULL
+            );",
+        ),
+    },
+    Migration {
+        version: 10,
+        name: "user_search",
+        step: Step::Code(|tx| {
+            add_column(tx, "users", "username_search", "TEXT")?;
+            add_column(tx, "users", "display_name_search", "TEXT")?;
+            let mut stmt = tx.prepare("SELECT id, username, display_name FROM users")?;
+            let users = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for (id, username, display_name) in users {
+                tx.execute(
+                    "UPDATE users SET username_search = ?1, display_name_search = ?2 WHERE id = ?3",
+                    (search_text(&username), search_text(&display_name), id),
+                )?;
+            }
+            Ok(())
+        }),
+    },
+    Migration {
+        version: 11,
+        name: "users_lifecycle",
+        step: Step::Code(|tx| {
+            add_column(tx, "users", "expires_at", "TEXT")?;
+            add_column(tx, "users", "last_login_at", "TEXT")?;
+            add_column(tx, "users", "deleted_at", "TEXT")
+        }),
+    },
+];
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql(&'static str),
+    /// For changes SQLite cannot express idempotently, such as adding a
+    /// column.
+    Code(fn(&Transaction) -> Result<()>),
+}
+
+fn add_column(tx: &Transaction, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if exists == 0 {
+        tx.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// The applied schema version, without creating anything.
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    let has_table: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_table == 0 {
+        return Ok(0);
+    }
+    Ok(conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// Migrations not yet applied. Fails if the database was migrated by a
+/// newer binary, since this one cannot know what changed.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        bail!(
+            "Database schema version {current} is newer than this build supports ({latest}); refusing to start"
+        );
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+/// Applies pending migrations, each in its own transaction.
+pub fn run(conn: &mut Connection) -> Result<()> {
+    run_until(conn, latest_version())
+}
+
+fn run_until(conn: &mut Connection, last: i64) -> Result<()> {
+    let pending: Vec<_> = pending(conn)?
+        .into_iter()
+        .filter(|m| m.version <= last)
+        .collect();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );",
+    )?;
+
+    for migration in pending {
+        let tx = conn.transaction()?;
+        match migration.step {
+            Step::Sql(sql) => tx.execute_batch(sql).map_err(Into::into),
+            Step::Code(f) => f(&tx),
+        }
+        .with_context(|| {
+            format!(
+                "Migration {:04} {} failed",
+                migration.version, migration.name
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name) VALUES (?1, ?2)",
+            (migration.version, migration.name),
+        )?;
+        tx.commit()?;
+        tracing::info!(
+            "Applied migration {:04} {}",
+            migration.version,
+            migration.name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The version just before migration `name`.
+    fn before(name: &str) -> i64 {
+        MIGRATIONS.iter().find(|m| m.name == name).unwrap().version - 1
+    }
+
+    fn rdp_rules_of(conn: &Connection, user_id: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT r.pattern FROM destination_rules r
+                 JOIN group_members m ON m.group_id = r.group_id
+                 WHERE m.user_id = ?1",
+            )
+            .unwrap();
+        stmt.query_map([user_id], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn upgrades_keep_rdp_access_for_existing_users() {
+        // Users from a release without groups could reach any host.
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_until(&mut conn, before("groups_and_connections")).unwrap();
+        conn.execute(
+            "INSERT INTO users (id, username, password_hash) VALUES ('u1', 'alice', 'x')",
+            [],
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        assert_eq!(rdp_rules_of(&conn, "u1"), ["*"]);
+    }
+
+    #[test]
+    fn upgrades_fill_in_search_text() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_until(&mut conn, before("user_search")).unwrap();
+        conn.execute(
+            "INSERT INTO users (id, username, password_hash, display_name)
+             VALUES ('u1', 'ÉRICA', 'x', 'Érica Ávila')",
+            [],
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+        let search: (String, String) = conn
+            .query_row(
+                "SELECT username_search, display_name_search FROM users WHERE id = 'u1'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(search, ("érica".to_string(), "érica ávila".to_string()));
+    }
+
+    #[test]
+    fn new_databases_deny_rdp_by_default() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        let groups: i64 = conn
+            .query_row("SELECT COUNT(*) FROM groups", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(groups, 0);
+    }
+
+    #[test]
+    fn refuses_a_schema_from_a_newer_build() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        let newer = latest_version() + 1;
+        conn.execute(
+            "INSERT INTO schema_version (version, name) VALUES (?1, 'from_the_future')",
+            [newer],
+        )
+        .unwrap();
+
+        let err = run(&mut conn).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!(
+                "version {newer} is newer than this build supports"
+            )),
+            "{err}"
+        );
+        assert!(pending(&conn).is_err());
+        assert_eq!(current_version(&conn).unwrap(), newer);
+    }
+}
@@ -0,0 +1,825 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context as _};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Redirect, Response};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+use utoipa::IntoParams;
+
+use crate::db::Provisioned;
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::AppState;
+
+const PENDING_TTL: Duration = Duration::from_secs(600);
+/// Logins started but not finished within `PENDING_TTL`. Starting one needs
+/// no credentials, so this bounds what anonymous clients can make us hold.
+const MAX_PENDING: usize = 10_000;
+/// Carries `state` back to the callback, so a login can only be completed
+/// by the browser that started it.
+const STATE_COOKIE: &str = "koder_oidc_state";
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub role_claim: String,
+    pub admin_values: Vec<String>,
+    /// Claim value to role, for roles other than `admin`. The first match
+    /// wins, after `admin_values`.
+    pub role_values: Vec<(String, String)>,
+    pub post_login_redirect: String,
+}
+
+impl OidcConfig {
+    /// Returns `None` when no issuer is configured, which leaves single
+    /// sign-on disabled. Expects a validated `Config`.
+    pub fn from_config(config: &crate::config::OidcConfig) -> anyhow::Result<Option<Self>> {
+        let Some(issuer) = &config.issuer else {
+            return Ok(None);
+        };
+
+        Ok(Some(OidcConfig {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: config.client_id.clone().context("oidc.client_id is not set")?,
+            client_secret: config.client_secret.clone(),
+            redirect_uri: config
+                .redirect_uri
+                .clone()
+                .context("oidc.redirect_uri is not set")?,
+            scopes: config.scopes.clone(),
+            username_claim: config.username_claim.clone(),
+            role_claim: config.role_claim.clone(),
+            admin_values: config.admin_values.clone(),
+            role_values: parse_role_values(&config.role_values)?,
+            post_login_redirect: config.post_login_redirect.clone(),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+impl Discovery {
+    /// The algorithms the IdP signs ID tokens with, RS256 if it does not
+    /// say. The token's own header never widens this.
+    fn signing_algorithms(&self) -> Vec<Algorithm> {
+        let advertised: Vec<Algorithm> = self
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| alg.parse().ok())
+            .collect();
+        if advertised.is_empty() {
+            vec![Algorithm::RS256]
+        } else {
+            advertised
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+pub struct Oidc {
+    config: OidcConfig,
+    http: reqwest::Client,
+    discovery: OnceCell<Discovery>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Oidc {
+            config,
+            http,
+            discovery: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    async fn discovery(&self) -> anyhow::Result<&Discovery> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+                let doc: Discovery = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .context("Invalid OIDC discovery document")?;
+                if doc.issuer.trim_end_matches('/') != self.config.issuer {
+                    return Err(anyhow!("OIDC issuer mismatch: {}", doc.issuer));
+                }
+                Ok(doc)
+            })
+            .await
+    }
+
+    /// `None` when too many logins are pending.
+    fn start(&self) -> Option<(String, String, String)> {
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = format!("{}{}", random_token(), random_token());
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.created.elapsed() < PENDING_TTL);
+        if pending.len() >= MAX_PENDING {
+            return None;
+        }
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                verifier: verifier.clone(),
+                nonce: nonce.clone(),
+                created: Instant::now(),
+            },
+        );
+
+        Some((state, nonce, verifier))
+    }
+
+    /// `Set-Cookie` value for `state`, scoped to the callback path. An empty
+    /// `state` clears the cookie.
+    fn state_cookie(&self, state: &str) -> String {
+        let url = reqwest::Url::parse(&self.config.redirect_uri).ok();
+        let path = url.as_ref().map_or("/", |u| u.path());
+        let max_age = if state.is_empty() {
+            0
+        } else {
+            PENDING_TTL.as_secs()
+        };
+        let mut cookie = format!(
+            "{STATE_COOKIE}={state}; Path={path}; Max-Age={max_age}; HttpOnly; SameSite=Lax"
+        );
+        if url.is_some_and(|u| u.scheme() == "https") {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    fn take_pending(&self, state: &str) -> Option<PendingLogin> {
+        let mut pending = self.pending.lock().unwrap();
+        pending
+            .remove(state)
+            .filter(|p| p.created.elapsed() < PENDING_TTL)
+    }
+
+    async fn exchange_code(&self, code: &str, verifier: &str) -> anyhow::Result<String> {
+        let discovery = self.discovery().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let resp: TokenResponse = self
+            .http
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()
+            .context("OIDC token endpoint rejected the code")?
+            .json()
+            .await
+            .context("Invalid OIDC token response")?;
+
+        Ok(resp.id_token)
+    }
+
+    async fn validate_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let discovery = self.discovery().await?;
+        let header = jsonwebtoken::decode_header(id_token)?;
+
+        let jwks: JwkSet = self
+            .http
+            .get(&discovery.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Invalid OIDC JWKS document")?;
+
+        let algorithms = discovery.signing_algorithms();
+        if !algorithms.contains(&header.alg) {
+            return Err(anyhow!("ID token signed with unexpected {:?}", header.alg));
+        }
+        let kid = header
+            .kid
+            .as_deref()
+            .ok_or_else(|| anyhow!("ID token header has no kid"))?;
+        let jwk = jwks
+            .find(kid)
+            .ok_or_else(|| anyhow!("No signing key {kid} in JWKS"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+
+        let claims = jsonwebtoken::decode::<serde_json::Value>(
+            id_token,
+            &DecodingKey::from_jwk(jwk)?,
+            &validation,
+        )?
+        .claims;
+
+        if claims.get("nonce").and_then(|v| v.as_str()) != Some(nonce) {
+            return Err(anyhow!("ID token nonce mismatch"));
+        }
+
+        Ok(claims)
+    }
+
+    fn map_role(&self, claims: &serde_json::Value) -> &str {
+        let values: Vec<&str> = match claims.get(&self.config.role_claim) {
+            Some(serde_json::Value::String(v)) => vec![v.as_str()],
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().filter_map(|v| v.as_str()).collect()
+            }
+            _ => Vec::new(),
+        };
+        if self.config.admin_values.iter().any(|a| values.contains(&a.as_str())) {
+            return "admin";
+        }
+        self.config
+            .role_values
+            .iter()
+            .find(|(value, _)| values.contains(&value.as_str()))
+            .map_or("user", |(_, role)| role.as_str())
+    }
+}
+
+/// `OIDC_ROLE_VALUES`: comma-separated `value=role` pairs.
+fn parse_role_values(pairs: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    pairs
+        .iter()
+        .map(|pair| {
+            let (value, role) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("oidc.role_values entry {pair:?} is not value=role"))?;
+            Ok((value.trim().to_string(), role.trim().to_string()))
+        })
+        .collect()
+}
+
+fn random_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/login",
+    tag = "auth",
+    security(()),
+    responses(
+        (status = 303, description = "Redirect"),
+        (status = 404, body = ErrorBody),
+        (status = 429, body = ErrorBody),
+        (status = 502, body = ErrorBody),
+    )
+)]
+pub async fn login(State(state): State<Arc<AppState>>) -> Result<Response, ApiError> {
+    let oidc = state.oidc.as_ref().ok_or(ApiError::SsoNotConfigured)?;
+
+    let discovery = oidc.discovery().await.map_err(|e| {
+        warn!("OIDC discovery failed: {e:#}");
+        ApiError::IdentityProviderUnavailable
+    })?;
+
+    let (csrf_state, nonce, verifier) = oidc.start().ok_or_else(|| {
+        warn!("Too many pending OIDC logins");
+        ApiError::TooManyPendingLogins
+    })?;
+
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", oidc.config.client_id.as_str()),
+            ("redirect_uri", oidc.config.redirect_uri.as_str()),
+            ("scope", oidc.config.scopes.as_str()),
+            ("state", csrf_state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", pkce_challenge(&verifier).as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(ApiError::internal)?;
+
+    let cookie = oidc.state_cookie(&csrf_state);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/callback",
+    tag = "auth",
+    security(()),
+    params(CallbackQuery),
+    responses(
+        (status = 303, description = "Redirect"),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+        (status = 502, body = ErrorBody),
+    )
+)]
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, ApiError> {
+    let oidc = state.oidc.as_ref().ok_or(ApiError::SsoNotConfigured)?;
+
+    if let Some(e) = query.error {
+        warn!("OIDC provider returned error: {e}");
+        return Err(ApiError::SsoDenied);
+    }
+
+    let (Some(code), Some(csrf_state)) = (query.code, query.state) else {
+        return Err(ApiError::SsoMissingParameters);
+    };
+
+    let cookie_state = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(STATE_COOKIE)?.strip_prefix('='));
+    if cookie_state != Some(csrf_state.as_str()) {
+        warn!("OIDC callback state does not match the browser's cookie");
+        return Err(ApiError::SsoSessionInvalid);
+    }
+
+    let pending = oidc
+        .take_pending(&csrf_state)
+        .ok_or(ApiError::SsoSessionInvalid)?;
+
+    let claims = async {
+        let id_token = oidc.exchange_code(&code, &pending.verifier).await?;
+        oidc.validate_id_token(&id_token, &pending.nonce).await
+    }
+    .await
+    .map_err(|e| {
+        state.metrics.login("oidc", "failure");
+        warn!("OIDC login failed: {e:#}");
+        ApiError::SsoFailed
+    })?;
+
+    let username = claims
+        .get(&oidc.config.username_claim)
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .ok_or(ApiError::SsoMissingUsername)?;
+    // `iss` and `sub` were validated with the token and are the identity's
+    // stable id; the username claim is not and may be changed at the IdP.
+    let subject = claims
+        .get("sub")
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .ok_or(ApiError::SsoMissingSubject)?;
+    let issuer = claims
+        .get("iss")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let display_name = claims.get("name").and_then(|v| v.as_str()).unwrap_or("");
+    let mut role = oidc.map_role(&claims);
+    let role_exists = state
+        .db
+        .role_exists(role)
+        .await
+        .map_err(ApiError::internal)?;
+    if !role_exists {
+        warn!("OIDC_ROLE_VALUES maps {username} to unknown role {role}; using user");
+        role = "user";
+    }
+
+    let provisioned = state
+        .db
+        .provision_external_user(
+            "oidc",
+            &format!("{issuer} {subject}"),
+            username,
+            display_name,
+            role,
+        )
+        .await
+        .map_err(ApiError::internal)?;
+    let user = match provisioned {
+        Provisioned::User(user) => state
+            .db
+            .get_user_by_id(&user.id)
+            .await
+            .map_err(ApiError::internal)?,
+        Provisioned::Deleted => None,
+        Provisioned::NameTaken => {
+            state.metrics.login("oidc", "conflict");
+            warn!("OIDC login for {username} refused: the name belongs to another account");
+            return Err(ApiError::AccountConflict);
+        }
+    };
+    let Some(user) = user.filter(|u| u.enabled) else {
+        state.metrics.login("oidc", "disabled");
+        return Err(ApiError::UserDisabled);
+    };
+    if user.expired {
+        state.metrics.login("oidc", "expired");
+        return Err(ApiError::UserExpired);
+    }
+
+    if let Err(e) = state.db.record_login(&user.id).await {
+        warn!("Failed to record login of {}: {e:#}", user.username);
+    }
+    let user = user.to_public();
+
+    state.metrics.login("oidc", "success");
+    info!("OIDC login for {} ({})", user.username, user.role);
+    crate::audit::record(
+        &state,
+        &user.username,
+        "auth.login",
+        &user.id,
+        serde_json::json!({ "method": "oidc" }),
+    )
+    .await;
+
+    let token = crate::auth::create_token(&state, &user).map_err(ApiError::internal)?;
+
+    let redirect = Redirect::to(&format!(
+        "{}#token={token}",
+        oidc.config.post_login_redirect
+    ));
+    Ok(([(header::SET_COOKIE, oidc.state_cookie(""))], redirect).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::routing::{get, post};
+    use axum::{Form, Json};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support;
+
+    const CLIENT_ID: &str = "koder";
+    const IDP_KEY: &[u8] = include_bytes!("../testdata/idp_key.pem");
+    /// Not in the IdP's JWKS, but signs with the same `kid`.
+    const OTHER_KEY: &[u8] = include_bytes!("../testdata/other_key.pem");
+
+    /// Codes the mock IdP will redeem: code -> (PKCE challenge, ID token).
+    type Codes = Arc<Mutex<HashMap<String, (String, String)>>>;
+
+    struct Idp {
+        issuer: String,
+        codes: Codes,
+    }
+
+    impl Idp {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+                "jwks_uri": format!("{issuer}/jwks"),
+                "id_token_signing_alg_values_supported": ["RS256"],
+            });
+            let codes = Codes::default();
+            let app = axum::Router::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    get(move || async move { Json(discovery) }),
+                )
+                .route(
+                    "/jwks",
+                    get(|| async {
+                        (
+                            [(header::CONTENT_TYPE, "application/json")],
+                            include_str!("../testdata/idp_jwks.json"),
+                        )
+                    }),
+                )
+                .route("/token", post(token))
+                .with_state(codes.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            Idp { issuer, codes }
+        }
+
+        /// Lets the callback redeem `code` for an ID token with `claims`
+        /// merged over a valid set, signed with `key`.
+        fn issue(&self, code: &str, login: &Login, key: &[u8], claims: Value) {
+            let now = jsonwebtoken::get_current_timestamp();
+            let mut all = json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "sub": "idp-user-42",
+                "iat": now,
+                "exp": now + 300,
+                "nonce": login.nonce,
+                "preferred_username": "alice",
+                "name": "Alice Example",
+                "roles": ["staff", "koder-admins"],
+            });
+            for (k, v) in claims.as_object().unwrap() {
+                all[k] = v.clone();
+            }
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some("idp-1".to_string());
+            let id_token =
+                jsonwebtoken::encode(&header, &all, &EncodingKey::from_rsa_pem(key).unwrap())
+                    .unwrap();
+            self.codes
+                .lock()
+                .unwrap()
+                .insert(code.to_string(), (login.challenge.clone(), id_token));
+        }
+    }
+
+    async fn token(
+        State(codes): State<Codes>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        let code = form.get("code").ok_or(StatusCode::BAD_REQUEST)?;
+        let (challenge, id_token) = codes
+            .lock()
+            .unwrap()
+            .remove(code)
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let verifier = form.get("code_verifier").ok_or(StatusCode::BAD_REQUEST)?;
+        if pkce_challenge(verifier) != challenge
+            || form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    async fn app(idp: &Idp) -> (Arc<AppState>, tempfile::TempDir) {
+        let (mut state, dir) = test_support::state(Config::default()).await;
+        state.oidc = Some(
+            Oidc::new(OidcConfig {
+                issuer: idp.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: None,
+                redirect_uri: "http://koder.test/api/auth/oidc/callback".to_string(),
+                scopes: "openid profile".to_string(),
+                username_claim: "preferred_username".to_string(),
+                role_claim: "roles".to_string(),
+                admin_values: vec!["koder-admins".to_string()],
+                role_values: vec![
+                    ("koder-support".to_string(), "helpdesk".to_string()),
+                    ("koder-ops".to_string(), "no-such-role".to_string()),
+                ],
+                post_login_redirect: "/".to_string(),
+            })
+            .unwrap(),
+        );
+        (Arc::new(state), dir)
+    }
+
+    struct Login {
+        state: String,
+        nonce: String,
+        challenge: String,
+        /// `name=value` from the login's `Set-Cookie`.
+        cookie: String,
+    }
+
+    async fn start_login(state: &Arc<AppState>) -> Login {
+        let resp = login(State(state.clone())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        let url = reqwest::Url::parse(location).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .unwrap()
+                .1
+                .into_owned()
+        };
+        assert_eq!(param("code_challenge_method"), "S256");
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        Login {
+            state: param("state"),
+            nonce: param("nonce"),
+            challenge: param("code_challenge"),
+            cookie: set_cookie.split(';').next().unwrap().to_string(),
+        }
+    }
+
+    async fn finish_login(state: &Arc<AppState>, login: &Login, cookie: &str) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, cookie.parse().unwrap());
+        let query = CallbackQuery {
+            code: Some("code-1".to_string()),
+            state: Some(login.state.clone()),
+            error: None,
+        };
+        match callback(State(state.clone()), headers, Query(query)).await {
+            Ok(resp) => resp,
+            Err(e) => e.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn signs_in_and_provisions_the_user() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, IDP_KEY, json!({}));
+        let resp = finish_login(&state, &login, &login.cookie).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers()[header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("/#token="), "{location}");
+        let cleared = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cleared.contains("Max-Age=0"));
+
+        let user = state
+            .db
+            .get_user_by_username("alice")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.auth_source, "oidc");
+        assert_eq!(user.display_name, "Alice Example");
+        assert_eq!(user.role, "admin");
+
+        // The same identity under a new name is the same account.
+        let login = start_login(&state).await;
+        idp.issue(
+            "code-1",
+            &login,
+            IDP_KEY,
+            json!({ "preferred_username": "alice2", "roles": [] }),
+        );
+        let resp = finish_login(&state, &login, &login.cookie).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let relogged = state.db.get_user_by_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(relogged.role, "user");
+        assert!(state
+            .db
+            .get_user_by_username("alice2")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn maps_claim_values_to_roles() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+        let oidc = state.oidc.as_ref().unwrap();
+
+        let role = |roles: Value| oidc.map_role(&json!({ "roles": roles })).to_string();
+        assert_eq!(role(json!(["koder-support", "koder-admins"])), "admin");
+        assert_eq!(role(json!(["staff", "koder-support"])), "helpdesk");
+        assert_eq!(role(json!("koder-support")), "helpdesk");
+        assert_eq!(role(json!(["staff"])), "user");
+
+        // A mapping to a role that does not exist falls back to `user`.
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, IDP_KEY, json!({ "roles": ["koder-ops"] }));
+        let resp = finish_login(&state, &login, &login.cookie).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let user = state.db.get_user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(user.role, "user");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_token_with_another_nonce() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, IDP_KEY, json!({ "nonce": "replayed" }));
+        let resp = finish_login(&state, &login, &login.cookie).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(state
+            .db
+            .get_user_by_username("alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_token_with_a_bad_signature() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, OTHER_KEY, json!({}));
+        let resp = finish_login(&state, &login, &login.cookie).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(state
+            .db
+            .get_user_by_username("alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_callback_from_another_browser() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, IDP_KEY, json!({}));
+        let other = start_login(&state).await;
+        let resp = finish_login(&state, &login, &other.cookie).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn does_not_take_over_a_local_account() {
+        let idp = Idp::start().await;
+        let (state, _dir) = app(&idp).await;
+        let local = test_support::user(&state, "alice", "Local-Pass-123!", "user").await;
+
+        let login = start_login(&state).await;
+        idp.issue("code-1", &login, IDP_KEY, json!({}));
+        let resp = finish_login(&state, &login, &login.cookie).await;
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let after = state.db.get_user_by_id(&local.id).await.unwrap().unwrap();
+        assert_eq!(after.auth_source, "local");
+        assert_eq!(after.role, "user");
+    }
+}
@@ -0,0 +1,83 @@
+use axum::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::{auth, connections, groups, oidc, roles, sessions, setup, tokens, users};
+
+/// Shape of every error response. Some errors add details, such as
+/// `violations` for `password.policy` or `scope` for `token.unknown_scope`.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct ErrorBody {
+    /// In the request's `Accept-Language`.
+    pub error: String,
+    /// Stable, for clients to match on, e.g. `user.not_found`.
+    pub code: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "koder API"),
+    servers((url = "/api/v1")),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    paths(
+        setup::status,
+        setup::setup,
+        auth::login,
+        auth::me,
+        auth::change_password,
+        oidc::login,
+        oidc::callback,
+        users::list_users,
+        users::create_user,
+        users::get_user,
+        users::update_user,
+        users::delete_user,
+        roles::list_roles,
+        roles::create_role,
+        roles::update_role,
+        roles::delete_role,
+        roles::list_permissions,
+        groups::list_groups,
+        groups::create_group,
+        groups::get_group,
+        groups::update_group,
+        groups::delete_group,
+        groups::add_member,
+        groups::remove_member,
+        groups::list_rules,
+        groups::create_rule,
+        groups::delete_rule,
+        connections::list_connections,
+        connections::create_connection,
+        connections::update_connection,
+        connections::delete_connection,
+        tokens::list_tokens,
+        tokens::create_token,
+        tokens::revoke_token,
+        sessions::list_sessions,
+        sessions::terminate_session,
+    ),
+    components(schemas(ErrorBody))
+)]
+struct ApiDoc;
+
+/// Session JWTs and personal access tokens both go in `Authorization:
+/// Bearer`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// `GET /api/openapi.json`
+pub async fn handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
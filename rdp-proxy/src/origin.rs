@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::ServerConfig;
+use crate::error::ApiError;
+use crate::AppState;
+
+/// Whether a browser request may act on this server: no `Origin` (not a
+/// browser), the server's own origin, or one of `server.cors_origins`.
+pub fn allowed(config: &ServerConfig, headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) else {
+        return !headers.contains_key(header::ORIGIN);
+    };
+    if config.cors_origins.iter().any(|o| o == origin) {
+        return true;
+    }
+    let authority = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"));
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok());
+    matches!((authority, host), (Some(a), Some(h)) if a.eq_ignore_ascii_case(h))
+}
+
+/// Refuses CORS preflights from unlisted origins outright instead of
+/// answering them without CORS headers.
+pub async fn check(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let preflight = req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight && !allowed(&state.config.server, req.headers()) {
+        tracing::warn!(
+            "Refused CORS preflight from {:?}",
+            req.headers().get(header::ORIGIN)
+        );
+        return ApiError::OriginNotAllowed.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+    use tempfile::TempDir;
+
+    use crate::config::Config;
+    use crate::test_support;
+
+    const APP: &str = "https://app.example";
+    const FOREIGN: &str = "https://evil.example";
+
+    /// Serves the full router on a local port and returns its base URL.
+    async fn serve(cors_origins: &[&str]) -> (String, TempDir) {
+        let mut config = Config::default();
+        config.server.cors_origins = cors_origins.iter().map(|o| o.to_string()).collect();
+        let (state, dir) = test_support::state(config).await;
+        (test_support::serve(state).await, dir)
+    }
+
+    async fn preflight(base: &str, origin: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .request(
+                reqwest::Method::OPTIONS,
+                format!("{base}/api/v1/auth/login"),
+            )
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCEPT_LANGUAGE, "en")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn upgrade(base: &str, origin: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{base}/rdp-proxy"))
+            .header(header::ORIGIN, origin)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(header::ACCEPT_LANGUAGE, "en")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    fn allowed_origin(res: &reqwest::Response) -> Option<&str> {
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .and_then(|v| v.to_str().ok())
+    }
+
+    async fn assert_refused(res: reqwest::Response) {
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["code"], "auth.origin_not_allowed");
+        assert_eq!(body["error"], "Origin not allowed");
+    }
+
+    #[tokio::test]
+    async fn listed_origins_may_call_the_api_and_open_sessions() {
+        let (base, _dir) = serve(&[APP]).await;
+
+        let res = preflight(&base, APP).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(allowed_origin(&res), Some(APP));
+
+        let res = reqwest::Client::new()
+            .get(format!("{base}/api/v1/setup"))
+            .header(header::ORIGIN, APP)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(allowed_origin(&res), Some(APP));
+
+        let res = upgrade(&base, APP).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn preflights_from_other_origins_are_refused() {
+        let (base, _dir) = serve(&[APP]).await;
+        assert_refused(preflight(&base, FOREIGN).await).await;
+    }
+
+    #[tokio::test]
+    async fn websockets_from_other_origins_are_refused() {
+        let (base, _dir) = serve(&[APP]).await;
+        assert_refused(upgrade(&base, FOREIGN).await).await;
+    }
+
+    #[tokio::test]
+    async fn the_servers_own_origin_needs_no_cors_origins() {
+        let (base, _dir) = serve(&[]).await;
+
+        let res = preflight(&base, &base).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_refused(preflight(&base, APP).await).await;
+
+        let res = upgrade(&base, &base).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
@@ -0,0 +1,177 @@
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHasher as _, PasswordVerifier as _, Version};
+use tracing::{info_span, Instrument as _};
+
+use crate::config::Argon2Config;
+
+/// Stored in place of a hash for accounts that can only log in through an
+/// external identity provider. Never verifies.
+pub const NO_PASSWORD: &str = "!";
+
+/// Argon2id hashing with the configured parameters. Hashes are PHC strings;
+/// bcrypt hashes from older releases still verify.
+#[derive(Clone)]
+pub struct Hasher {
+    params: Params,
+}
+
+impl Hasher {
+    pub fn new(config: &Argon2Config) -> anyhow::Result<Self> {
+        let params = Params::new(
+            config.memory_kib,
+            config.iterations,
+            config.parallelism,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {e}"))?;
+        Ok(Hasher { params })
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Hashes on the calling thread. Prefer `hash` from async code.
+    pub fn hash_blocking(&self, password: &str) -> anyhow::Result<String> {
+        let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to encode salt: {e}"))?;
+        let hash = self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {e}"))?;
+        Ok(hash.to_string())
+    }
+
+    pub async fn hash(&self, password: &str) -> anyhow::Result<String> {
+        let hasher = self.clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || hasher.hash_blocking(&password))
+            .instrument(info_span!("password.hash"))
+            .await?
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> bool {
+        let scheme = if hash.starts_with("$2") {
+            "bcrypt"
+        } else {
+            "argon2"
+        };
+        let password = password.to_string();
+        let hash = hash.to_string();
+        tokio::task::spawn_blocking(move || verify_blocking(&password, &hash))
+            .instrument(info_span!("password.verify", scheme))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Whether `hash` is bcrypt or Argon2 with other parameters than the
+    /// configured ones, and should be replaced after a successful login.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return hash != NO_PASSWORD;
+        };
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+pub fn verify_blocking(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::extract::State;
+    use axum::Json;
+
+    use super::*;
+    use crate::auth::{login, LoginRequest};
+    use crate::config::Config;
+    use crate::test_support;
+
+    /// Cheap parameters, so the tests do not spend their time hashing.
+    fn hasher(iterations: u32) -> Hasher {
+        Hasher::new(&Argon2Config {
+            memory_kib: 1024,
+            iterations,
+            parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bcrypt_hashes_verify_and_are_marked_for_rehash() {
+        let hasher = hasher(1);
+        let hash = bcrypt::hash("Old-Pass-123!", 4).unwrap();
+        assert!(hasher.verify("Old-Pass-123!", &hash).await);
+        assert!(!hasher.verify("old-pass-123!", &hash).await);
+        assert!(hasher.needs_rehash(&hash));
+    }
+
+    #[tokio::test]
+    async fn hashes_are_marked_for_rehash_when_parameters_change() {
+        let hash = hasher(1).hash("Pass-123!").await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(hasher(1).verify("Pass-123!", &hash).await);
+        assert!(!hasher(1).needs_rehash(&hash));
+
+        // Still verifies, since the parameters are part of the hash.
+        assert!(hasher(2).verify("Pass-123!", &hash).await);
+        assert!(hasher(2).needs_rehash(&hash));
+
+        assert!(!hasher(1).verify("", NO_PASSWORD).await);
+        assert!(!hasher(1).needs_rehash(NO_PASSWORD));
+    }
+
+    #[tokio::test]
+    async fn login_replaces_a_bcrypt_hash_with_argon2id() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let state = Arc::new(state);
+        let bcrypt_hash = bcrypt::hash("Old-Pass-123!", 4).unwrap();
+        let user = state
+            .db
+            .create_user("alice", &bcrypt_hash, "", "user", false, None)
+            .await
+            .unwrap();
+
+        let body = LoginRequest {
+            username: "alice".to_string(),
+            password: "Old-Pass-123!".to_string(),
+        };
+        let Json(response) = login(State(state.clone()), Json(body)).await.unwrap();
+        assert_eq!(response.user.id, user.id);
+
+        let row = state.db.get_user_by_id(&user.id).await.unwrap().unwrap();
+        assert!(row.password_hash.starts_with("$argon2id$"));
+        assert!(!state.hasher.needs_rehash(&row.password_hash));
+        assert!(
+            state
+                .hasher
+                .verify("Old-Pass-123!", &row.password_hash)
+                .await
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashSet;
+
+use anyhow::Context as _;
+
+use crate::config::PasswordConfig;
+use crate::error::{ApiError, Lang};
+use crate::AppState;
+
+/// Hashes kept per user in `password_history`; `password.history` may not
+/// exceed this value.
+pub const HISTORY_MAX: usize = 24;
+
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// How many of lowercase, uppercase, digits and symbols must appear.
+    pub min_classes: usize,
+    pub forbid_username: bool,
+    pub history: usize,
+    blocklist: HashSet<String>,
+}
+
+/// One failed rule. `code` and `params` are stable so clients can render
+/// their own message; `message` is the server's rendering.
+#[derive(Debug)]
+pub enum Violation {
+    TooShort { min: usize },
+    TooFewClasses { min: usize },
+    ContainsUsername,
+    Compromised,
+    Reused { count: usize },
+}
+
+impl Violation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Violation::TooShort { .. } => "password.too_short",
+            Violation::TooFewClasses { .. } => "password.too_few_classes",
+            Violation::ContainsUsername => "password.contains_username",
+            Violation::Compromised => "password.compromised",
+            Violation::Reused { .. } => "password.reused",
+        }
+    }
+
+    pub fn params(&self) -> serde_json::Value {
+        match self {
+            Violation::TooShort { min } | Violation::TooFewClasses { min } => {
+                serde_json::json!({ "min": min })
+            }
+            Violation::Reused { count } => serde_json::json!({ "count": count }),
+            Violation::ContainsUsername | Violation::Compromised => serde_json::json!({}),
+        }
+    }
+
+    pub fn message(&self, lang: Lang) -> String {
+        match (lang, self) {
+            (Lang::PtBr, Violation::TooShort { min }) => {
+                format!("A senha deve ter pelo menos {min} caracteres")
+            }
+            (Lang::PtBr, Violation::TooFewClasses { min }) => format!(
+                "A senha deve combinar pelo menos {min} entre minúsculas, maiúsculas, números e símbolos"
+            ),
+            (Lang::PtBr, Violation::ContainsUsername) => {
+                "A senha não pode conter o nome de usuário".to_string()
+            }
+            (Lang::PtBr, Violation::Compromised) => {
+                "Esta senha aparece em listas de senhas vazadas".to_string()
+            }
+            (Lang::PtBr, Violation::Reused { count }) => {
+                format!("A senha não pode repetir nenhuma das últimas {count} senhas")
+            }
+            (Lang::En, Violation::TooShort { min }) => {
+                format!("The password must be at least {min} characters long")
+            }
+            (Lang::En, Violation::TooFewClasses { min }) => format!(
+                "The password must mix at least {min} of lowercase, uppercase, digits and symbols"
+            ),
+            (Lang::En, Violation::ContainsUsername) => {
+                "The password must not contain the username".to_string()
+            }
+            (Lang::En, Violation::Compromised) => {
+                "This password appears in lists of leaked passwords".to_string()
+            }
+            (Lang::En, Violation::Reused { count }) => {
+                format!("The password must not repeat any of the last {count} passwords")
+            }
+        }
+    }
+
+    pub fn to_json(&self, lang: Lang) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code(),
+            "params": self.params(),
+            "message": self.message(lang),
+        })
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new(config: &PasswordConfig) -> anyhow::Result<Self> {
+        let blocklist = match &config.blocklist_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read password blocklist {}", path.display()))?
+                .lines()
+                .map(|l| l.trim().to_lowercase())
+                .filter(|l| !l.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(PasswordPolicy {
+            min_length: config.min_length,
+            min_classes: config.min_classes,
+            forbid_username: !config.allow_username,
+            history: config.history,
+            blocklist,
+        })
+    }
+
+    pub fn blocklist_len(&self) -> usize {
+        self.blocklist.len()
+    }
+
+    /// Rules that only need the password itself; history is checked
+    /// separately because it needs the stored hashes.
+    pub fn check(&self, username: &str, password: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        // Even with no minimum configured, a password cannot be empty.
+        let min_length = self.min_length.max(1);
+        if password.chars().count() < min_length {
+            violations.push(Violation::TooShort { min: min_length });
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ]
+        .iter()
+        .filter(|present| **present)
+        .count();
+        if classes < self.min_classes {
+            violations.push(Violation::TooFewClasses {
+                min: self.min_classes,
+            });
+        }
+
+        let lowered = password.to_lowercase();
+        let username = username.trim().to_lowercase();
+        if self.forbid_username && username.chars().count() >= 3 && lowered.contains(&username) {
+            violations.push(Violation::ContainsUsername);
+        }
+
+        if self.blocklist.contains(&lowered) {
+            violations.push(Violation::Compromised);
+        }
+
+        violations
+    }
+}
+
+/// Checks `password` against the policy and, for an existing user, against
+/// their current and recent passwords.
+pub async fn enforce(
+    state: &AppState,
+    username: &str,
+    password: &str,
+    user_id: Option<&str>,
+) -> Result<(), ApiError> {
+    let policy = &state.password_policy;
+    let mut violations = policy.check(username, password);
+
+    if let (Some(id), true) = (user_id, policy.history > 0) {
+        let hashes = state
+            .db
+            .recent_password_hashes(id, policy.history)
+            .await
+            .map_err(ApiError::internal)?;
+        let mut reused = false;
+        for hash in &hashes {
+            if state.hasher.verify(password, hash).await {
+                reused = true;
+                break;
+            }
+        }
+        if reused {
+            violations.push(Violation::Reused {
+                count: policy.history,
+            });
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::PasswordPolicy(violations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support;
+
+    fn codes(violations: &[Violation]) -> Vec<&'static str> {
+        violations.iter().map(Violation::code).collect()
+    }
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new(&PasswordConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn requires_a_minimum_length() {
+        let policy = policy();
+        assert_eq!(
+            codes(&policy.check("alice", "Sh0rt-pw")),
+            ["password.too_short"]
+        );
+        // Counted in characters, not bytes.
+        assert!(policy.check("alice", "Çãõ-Senha-12").is_empty());
+
+        let no_minimum = PasswordPolicy::new(&PasswordConfig {
+            min_length: 0,
+            min_classes: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(no_minimum.check("alice", "x").is_empty());
+        assert_eq!(
+            codes(&no_minimum.check("alice", "")),
+            ["password.too_short"]
+        );
+    }
+
+    #[test]
+    fn requires_enough_character_classes() {
+        let policy = policy();
+        assert_eq!(
+            codes(&policy.check("alice", "onlylowercaseletters")),
+            ["password.too_few_classes"]
+        );
+        assert_eq!(
+            codes(&policy.check("alice", "lowercase-and-symbols")),
+            ["password.too_few_classes"]
+        );
+        assert!(policy.check("alice", "Lower-and-Upper").is_empty());
+        assert!(policy.check("alice", "lower-and-123").is_empty());
+    }
+
+    #[test]
+    fn forbids_the_username_unless_allowed() {
+        let policy = policy();
+        assert_eq!(
+            codes(&policy.check("Alice", "My-ALICE-pass-1")),
+            ["password.contains_username"]
+        );
+        // Names this short would match too many passwords.
+        assert!(policy.check("al", "My-al-password-1").is_empty());
+
+        let allowed = PasswordPolicy::new(&PasswordConfig {
+            allow_username: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(allowed.check("alice", "My-alice-pass-1").is_empty());
+    }
+
+    #[test]
+    fn rejects_blocklisted_passwords_in_any_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        std::fs::write(&path, "Correct-Horse-1\n\n  summer-2024-PASS  \n").unwrap();
+        let policy = PasswordPolicy::new(&PasswordConfig {
+            blocklist_file: Some(path),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(policy.blocklist_len(), 2);
+        assert_eq!(
+            codes(&policy.check("alice", "correct-HORSE-1")),
+            ["password.compromised"]
+        );
+        assert_eq!(
+            codes(&policy.check("alice", "Summer-2024-pass")),
+            ["password.compromised"]
+        );
+        assert!(policy.check("alice", "Summer-2025-pass").is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_recent_passwords() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let user = test_support::user(&state, "alice", "First-Pass-123", "user").await;
+        let second = state.hasher.hash("Second-Pass-123").await.unwrap();
+        state.db.update_password(&user.id, &second).await.unwrap();
+
+        for reused in ["First-Pass-123", "Second-Pass-123"] {
+            let Err(ApiError::PasswordPolicy(violations)) =
+                enforce(&state, "alice", reused, Some(&user.id)).await
+            else {
+                panic!("{reused} was accepted");
+            };
+            assert_eq!(codes(&violations), ["password.reused"]);
+        }
+        assert!(enforce(&state, "alice", "Third-Pass-123", Some(&user.id))
+            .await
+            .is_ok());
+        // A new user has no history yet.
+        assert!(enforce(&state, "bob", "First-Pass-123", None).await.is_ok());
+    }
+}
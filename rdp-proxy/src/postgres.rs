@@ -0,0 +1,1398 @@
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Transaction};
+use postgres_native_tls::MakeTlsConnector;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{NoTls, Row};
+
+use crate::db::{
+    search_text, ApiToken, ApiTokenOwner, AuditEntry, ConnectionProfile, DestinationRule, Group,
+    Provisioned, Role, User, UserChanges, UserPage, UserQuery, UserRow,
+};
+use crate::storage::Storage;
+
+/// Mirrors the SQLite migrations in `migrations.rs`; versions line up so
+/// both backends report the same schema version.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "users",
+        "CREATE OR REPLACE FUNCTION koder_now() RETURNS TEXT LANGUAGE SQL STABLE AS
+            $$ SELECT to_char(now() AT TIME ZONE 'utc', 'YYYY-MM-DD HH24:MI:SS') $$;
+        CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            username TEXT UNIQUE NOT NULL,
+            password_hash TEXT NOT NULL,
+            display_name TEXT NOT NULL DEFAULT '',
+            role TEXT NOT NULL DEFAULT 'user',
+            created_at TEXT NOT NULL DEFAULT koder_now(),
+            updated_at TEXT NOT NULL DEFAULT koder_now()
+        );",
+    ),
+    (
+        2,
+        "external_identities",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS auth_source TEXT NOT NULL DEFAULT 'local';
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS external_id TEXT;
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_users_external ON users(auth_source, external_id);",
+    ),
+    (
+        3,
+        "api_tokens",
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            token_hash TEXT UNIQUE NOT NULL,
+            scopes TEXT NOT NULL DEFAULT '',
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT koder_now(),
+            last_used_at TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_api_tokens_user ON api_tokens(user_id);",
+    ),
+    (
+        4,
+        "roles",
+        "CREATE TABLE IF NOT EXISTS roles (
+            name TEXT PRIMARY KEY,
+            description TEXT NOT NULL DEFAULT '',
+            builtin BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at TEXT NOT NULL DEFAULT koder_now()
+        );
+        CREATE TABLE IF NOT EXISTS role_permissions (
+            role TEXT NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+            permission TEXT NOT NULL,
+            PRIMARY KEY (role, permission)
+        );",
+    ),
+    (
+        5,
+        "groups_and_connections",
+        "CREATE TABLE IF NOT EXISTS groups (
+            id TEXT PRIMARY KEY,
+            name TEXT UNIQUE NOT NULL,
+            description TEXT NOT NULL DEFAULT '',
+            created_at TEXT NOT NULL DEFAULT koder_now(),
+            updated_at TEXT NOT NULL DEFAULT koder_now()
+        );
+        CREATE TABLE IF NOT EXISTS group_members (
+            group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+            user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            PRIMARY KEY (group_id, user_id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_group_members_user ON group_members(user_id);
+        CREATE TABLE IF NOT EXISTS connections (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            protocol TEXT NOT NULL DEFAULT 'rdp',
+            host TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            created_at TEXT NOT NULL DEFAULT koder_now(),
+            updated_at TEXT NOT NULL DEFAULT koder_now()
+        );
+        CREATE TABLE IF NOT EXISTS group_connections (
+            group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+            connection_id TEXT NOT NULL REFERENCES connections(id) ON DELETE CASCADE,
+            PRIMARY KEY (group_id, connection_id)
+        );
+        CREATE TABLE IF NOT EXISTS destination_rules (
+            id TEXT PRIMARY KEY,
+            group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+            pattern TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT koder_now()
+        );
+        INSERT INTO groups (id, name, description)
+        SELECT 'legacy-rdp-access', 'Acesso RDP legado',
+            'Criado na atualização para manter o acesso de quem já usava o proxy. Exclua após configurar os grupos.'
+        WHERE EXISTS (SELECT 1 FROM users) AND NOT EXISTS (SELECT 1 FROM groups);
+        INSERT INTO group_members (group_id, user_id)
+        SELECT 'legacy-rdp-access', id FROM users
+        WHERE EXISTS (SELECT 1 FROM groups WHERE id = 'legacy-rdp-access');
+        INSERT INTO destination_rules (id, group_id, pattern)
+        SELECT 'legacy-rdp-access', 'legacy-rdp-access', '*'
+        WHERE EXISTS (SELECT 1 FROM groups WHERE id = 'legacy-rdp-access');",
+    ),
+    (
+        6,
+        "users_must_change_password",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS must_change_password BOOLEAN NOT NULL DEFAULT FALSE;",
+    ),
+    (
+        7,
+        "password_history",
+        "CREATE TABLE IF NOT EXISTS password_history (
+            id BIGSERIAL PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            password_hash TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT koder_now()
+        );
+        CREATE INDEX IF NOT EXISTS idx_password_history_user ON password_history(user_id);",
+    ),
+    (
+        8,
+        "users_enabled",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS enabled BOOLEAN NOT NULL DEFAULT TRUE;",
+    ),
+    (
+        9,
+        "audit_log",
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            id BIGSERIAL PRIMARY KEY,
+            at TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            action TEXT NOT NULL,
+            target TEXT NOT NULL DEFAULT '',
+            details TEXT NOT NULL DEFAULT '',
+            prev_hash TEXT NOT NULL,
+            hash TEXT NOT NULL
+        );",
+    ),
+    (
+        10,
+        "user_search",
+        // Filled in by `initialize`, which lowercases the way `search_text`
+        // does.
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS username_search TEXT;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name_search TEXT;",
+    ),
+    (
+        11,
+        "users_lifecycle",
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS expires_at TEXT;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TEXT;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TEXT;",
+    ),
+];
+
+/// Held while migrating so replicas starting together do not race.
+const MIGRATION_LOCK: i64 = 0x006b_6f64_6572;
+
+const USER_COLUMNS: &str =
+    "id, username, password_hash, display_name, role, must_change_password, created_at, updated_at, enabled,
+     expires_at, last_login_at, COALESCE(expires_at <= koder_now(), FALSE), auth_source";
+
+const GROUP_COLUMNS: &str = "g.id, g.name, g.description,
+    (SELECT COUNT(*) FROM group_members m WHERE m.group_id = g.id), g.created_at, g.updated_at";
+
+const CONNECTION_COLUMNS: &str = "c.id, c.name, c.protocol, c.host, c.port,
+    COALESCE((SELECT string_agg(gc.group_id, ' ') FROM group_connections gc WHERE gc.connection_id = c.id), ''),
+    c.created_at, c.updated_at";
+
+const ROLE_QUERY: &str =
+    "SELECT r.name, r.description, r.builtin, COALESCE(string_agg(p.permission, ' '), '')
+     FROM roles r LEFT JOIN role_permissions p ON p.role = r.name";
+
+fn split(list: String) -> Vec<String> {
+    list.split_whitespace().map(str::to_string).collect()
+}
+
+fn map_user_row(row: &Row) -> Result<UserRow, tokio_postgres::Error> {
+    Ok(UserRow {
+        id: row.try_get(0)?,
+        username: row.try_get(1)?,
+        password_hash: row.try_get(2)?,
+        display_name: row.try_get(3)?,
+        role: row.try_get(4)?,
+        must_change_password: row.try_get(5)?,
+        created_at: row.try_get(6)?,
+        updated_at: row.try_get(7)?,
+        enabled: row.try_get(8)?,
+        expires_at: row.try_get(9)?,
+        last_login_at: row.try_get(10)?,
+        expired: row.try_get(11)?,
+        auth_source: row.try_get(12)?,
+    })
+}
+
+fn map_api_token(row: &Row) -> Result<ApiToken, tokio_postgres::Error> {
+    Ok(ApiToken {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        scopes: split(row.try_get(2)?),
+        expires_at: row.try_get(3)?,
+        created_at: row.try_get(4)?,
+        last_used_at: row.try_get(5)?,
+    })
+}
+
+fn map_role(row: &Row) -> Result<Role, tokio_postgres::Error> {
+    Ok(Role {
+        name: row.try_get(0)?,
+        description: row.try_get(1)?,
+        builtin: row.try_get(2)?,
+        permissions: split(row.try_get(3)?),
+    })
+}
+
+fn map_group(row: &Row) -> Result<Group, tokio_postgres::Error> {
+    Ok(Group {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        description: row.try_get(2)?,
+        member_count: row.try_get(3)?,
+        created_at: row.try_get(4)?,
+        updated_at: row.try_get(5)?,
+    })
+}
+
+fn map_connection(row: &Row) -> Result<ConnectionProfile, tokio_postgres::Error> {
+    Ok(ConnectionProfile {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        protocol: row.try_get(2)?,
+        host: row.try_get(3)?,
+        port: row.try_get::<_, i32>(4)? as u16,
+        group_ids: split(row.try_get(5)?),
+        created_at: row.try_get(6)?,
+        updated_at: row.try_get(7)?,
+    })
+}
+
+fn map_destination_rule(row: &Row) -> Result<DestinationRule, tokio_postgres::Error> {
+    Ok(DestinationRule {
+        id: row.try_get(0)?,
+        group_id: row.try_get(1)?,
+        pattern: row.try_get(2)?,
+        created_at: row.try_get(3)?,
+    })
+}
+
+async fn record_password(tx: &Transaction<'_>, user_id: &str, hash: &str) -> Result<()> {
+    tx.execute(
+        "INSERT INTO password_history (user_id, password_hash) VALUES ($1, $2)",
+        &[&user_id, &hash],
+    )
+    .await?;
+    tx.execute(
+        "DELETE FROM password_history WHERE user_id = $1 AND id NOT IN (
+             SELECT id FROM password_history WHERE user_id = $1 ORDER BY id DESC LIMIT $2
+         )",
+        &[&user_id, &(crate::password_policy::HISTORY_MAX as i64)],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn user_by_id(tx: &Transaction<'_>, id: &str) -> Result<Option<UserRow>> {
+    let row = tx
+        .query_opt(
+            &format!("SELECT {USER_COLUMNS} FROM users WHERE id = $1 AND deleted_at IS NULL"),
+            &[&id],
+        )
+        .await?;
+    Ok(row.as_ref().map(map_user_row).transpose()?)
+}
+
+async fn insert_user(
+    tx: &Transaction<'_>,
+    username: &str,
+    password_hash: &str,
+    display_name: &str,
+    role: &str,
+    must_change_password: bool,
+    expires_at: Option<&str>,
+) -> Result<User> {
+    let id = uuid::Uuid::new_v4().to_string();
+    tx.execute(
+        "INSERT INTO users (id, username, password_hash, display_name, role, must_change_password,
+                            expires_at, username_search, display_name_search)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        &[
+            &id,
+            &username,
+            &password_hash,
+            &display_name,
+            &role,
+            &must_change_password,
+            &expires_at,
+            &search_text(username),
+            &search_text(display_name),
+        ],
+    )
+    .await?;
+    record_password(tx, &id, password_hash).await?;
+    Ok(user_by_id(tx, &id).await?.unwrap().to_public())
+}
+
+/// Applies `changes` in one statement. A new password is also recorded in
+/// the history, hence the transaction.
+async fn update_user_fields(
+    tx: &Transaction<'_>,
+    id: &str,
+    changes: &UserChanges,
+) -> Result<Option<User>> {
+    let rows = tx
+        .execute(
+            "UPDATE users SET
+                display_name = COALESCE($1, display_name),
+                display_name_search = COALESCE($2, display_name_search),
+                role = COALESCE($3, role),
+                password_hash = COALESCE($4, password_hash),
+                must_change_password = CASE WHEN $4 IS NULL THEN must_change_password ELSE TRUE END,
+                enabled = COALESCE($5, enabled),
+                expires_at = CASE WHEN $6 THEN $7 ELSE expires_at END,
+                updated_at = koder_now()
+             WHERE id = $8 AND deleted_at IS NULL",
+            &[
+                &changes.display_name,
+                &changes.display_name.as_deref().map(search_text),
+                &changes.role,
+                &changes.password_hash,
+                &changes.enabled,
+                &changes.expires_at.is_some(),
+                &changes.expires_at.clone().flatten(),
+                &id,
+            ],
+        )
+        .await?;
+    if rows == 0 {
+        return Ok(None);
+    }
+    if let Some(hash) = &changes.password_hash {
+        record_password(tx, id, hash).await?;
+    }
+    Ok(user_by_id(tx, id).await?.map(|u| u.to_public()))
+}
+
+async fn group_by_id(tx: &Transaction<'_>, id: &str) -> Result<Option<Group>> {
+    let row = tx
+        .query_opt(
+            &format!("SELECT {GROUP_COLUMNS} FROM groups g WHERE g.id = $1"),
+            &[&id],
+        )
+        .await?;
+    Ok(row.as_ref().map(map_group).transpose()?)
+}
+
+async fn connection_by_id(tx: &Transaction<'_>, id: &str) -> Result<Option<ConnectionProfile>> {
+    let row = tx
+        .query_opt(
+            &format!("SELECT {CONNECTION_COLUMNS} FROM connections c WHERE c.id = $1"),
+            &[&id],
+        )
+        .await?;
+    Ok(row.as_ref().map(map_connection).transpose()?)
+}
+
+/// PostgreSQL backend, for running several replicas against one database.
+/// Connections use TLS when `DATABASE_URL` has `sslmode=require`, verifying
+/// the server against the system's trusted certificates (`SSL_CERT_FILE`
+/// adds a private CA). Otherwise they are plaintext.
+pub struct PgStorage {
+    pool: Pool,
+}
+
+impl PgStorage {
+    /// Connects with a pool of up to `size` connections.
+    pub async fn connect(url: &str, size: usize) -> Result<Self> {
+        let config: tokio_postgres::Config = url.parse().context("Invalid DATABASE_URL")?;
+        let manager_config = ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        };
+        let manager = if config.get_ssl_mode() == SslMode::Require {
+            let tls = native_tls::TlsConnector::new().context("Failed to set up TLS")?;
+            Manager::from_config(config, MakeTlsConnector::new(tls), manager_config)
+        } else {
+            Manager::from_config(config, NoTls, manager_config)
+        };
+        let pool = Pool::builder(manager).max_size(size).build()?;
+        // Fail at startup rather than on the first request.
+        drop(
+            pool.get()
+                .await
+                .context("Failed to connect to PostgreSQL")?,
+        );
+        Ok(PgStorage { pool })
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Client> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn current_version(client: &deadpool_postgres::Client) -> Result<i64> {
+        let exists: bool = client
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])
+            .await?
+            .try_get(0)?;
+        if !exists {
+            return Ok(0);
+        }
+        Ok(client
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+            .await?
+            .try_get(0)?)
+    }
+
+    async fn pending(
+        client: &deadpool_postgres::Client,
+    ) -> Result<Vec<(i64, &'static str, &'static str)>> {
+        let current = Self::current_version(client).await?;
+        let latest = MIGRATIONS.last().map_or(0, |m| m.0);
+        if current > latest {
+            bail!(
+                "Database schema version {current} is newer than this build supports ({latest}); refusing to start"
+            );
+        }
+        Ok(MIGRATIONS
+            .iter()
+            .copied()
+            .filter(|m| m.0 > current)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn initialize(&self) -> Result<()> {
+        let mut client = self.client().await?;
+        client
+            .execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])
+            .await?;
+
+        let result = async {
+            let pending = Self::pending(&client).await?;
+            if !pending.is_empty() {
+                client
+                    .batch_execute(
+                        "CREATE TABLE IF NOT EXISTS schema_version (
+                            version BIGINT PRIMARY KEY,
+                            name TEXT NOT NULL,
+                            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                        );",
+                    )
+                    .await?;
+            }
+            for (version, name, sql) in pending {
+                let tx = client.transaction().await?;
+                tx.batch_execute(sql)
+                    .await
+                    .with_context(|| format!("Migration {version:04} {name} failed"))?;
+                tx.execute(
+                    "INSERT INTO schema_version (version, name) VALUES ($1, $2)",
+                    &[&version, &name],
+                )
+                .await?;
+                tx.commit().await?;
+                tracing::info!("Applied migration {version:04} {name}");
+            }
+
+            let tx = client.transaction().await?;
+            let unindexed = tx
+                .query(
+                    "SELECT id, username, display_name FROM users WHERE username_search IS NULL",
+                    &[],
+                )
+                .await?;
+            for row in unindexed {
+                let (id, username, display_name): (String, String, String) =
+                    (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?);
+                tx.execute(
+                    "UPDATE users SET username_search = $1, display_name_search = $2 WHERE id = $3",
+                    &[&search_text(&username), &search_text(&display_name), &id],
+                )
+                .await?;
+            }
+            for (name, description, permissions) in crate::rbac::BUILTIN_ROLES {
+                tx.execute(
+                    "INSERT INTO roles (name, description, builtin) VALUES ($1, $2, TRUE)
+                     ON CONFLICT (name) DO UPDATE SET description = excluded.description, builtin = TRUE",
+                    &[name, description],
+                )
+                .await?;
+                tx.execute("DELETE FROM role_permissions WHERE role = $1", &[name])
+                    .await?;
+                for permission in *permissions {
+                    tx.execute(
+                        "INSERT INTO role_permissions (role, permission) VALUES ($1, $2)",
+                        &[name, permission],
+                    )
+                    .await?;
+                }
+            }
+            tx.commit().await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        client
+            .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])
+            .await?;
+        result
+    }
+
+    async fn pending_migrations(&self) -> Result<Vec<String>> {
+        let client = self.client().await?;
+        Ok(Self::pending(&client)
+            .await?
+            .iter()
+            .map(|(version, name, _)| format!("{version:04} {name}"))
+            .collect())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client().await?.execute("SELECT 1", &[]).await?;
+        Ok(())
+    }
+
+    async fn backup(&self, _dest: &std::path::Path) -> Result<()> {
+        bail!("Back up PostgreSQL with pg_dump")
+    }
+
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<UserRow>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!("SELECT {USER_COLUMNS} FROM users WHERE username = $1 AND deleted_at IS NULL"),
+                &[&username],
+            )
+            .await?;
+        Ok(row.as_ref().map(map_user_row).transpose()?)
+    }
+
+    async fn get_user_by_id(&self, id: &str) -> Result<Option<UserRow>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        user_by_id(&tx, id).await
+    }
+
+    async fn list_users(&self, query: &UserQuery) -> Result<UserPage> {
+        let sql = query.to_sql(USER_COLUMNS, |n| format!("${n}"));
+        let params: Vec<&(dyn ToSql + Sync)> =
+            sql.params.iter().map(|p| p as &(dyn ToSql + Sync)).collect();
+        let client = self.client().await?;
+        let total = client
+            .query_one(&sql.count, &params[..sql.count_params])
+            .await?
+            .try_get(0)?;
+        let rows = client.query(&sql.select, &params).await?;
+        let users = rows
+            .iter()
+            .map(|r| map_user_row(r).map(|u| u.to_public()))
+            .collect::<Result<_, _>>()?;
+        Ok(UserPage { users, total })
+    }
+
+    async fn count_users(&self) -> Result<i64> {
+        Ok(self
+            .client()
+            .await?
+            .query_one("SELECT COUNT(*) FROM users", &[])
+            .await?
+            .try_get(0)?)
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        display_name: &str,
+        role: &str,
+        must_change_password: bool,
+        expires_at: Option<&str>,
+    ) -> Result<User> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let user = insert_user(
+            &tx,
+            username,
+            password_hash,
+            display_name,
+            role,
+            must_change_password,
+            expires_at,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn provision_external_user(
+        &self,
+        source: &str,
+        external_id: &str,
+        username: &str,
+        display_name: &str,
+        role: &str,
+    ) -> Result<Provisioned> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let linked = tx
+            .query_opt(
+                "SELECT id, deleted_at IS NOT NULL FROM users WHERE auth_source = $1 AND external_id = $2",
+                &[&source, &external_id],
+            )
+            .await?;
+        let id: String = match linked {
+            Some(row) if row.try_get::<_, bool>(1)? => return Ok(Provisioned::Deleted),
+            Some(row) => row.try_get(0)?,
+            None => {
+                // Users provisioned before identities were linked have no
+                // password and no link, and are adopted. Any other user with
+                // the name is off limits.
+                let unlinked = tx
+                    .query_opt(
+                        "SELECT id FROM users
+                         WHERE username = $1 AND password_hash = $2
+                           AND external_id IS NULL AND deleted_at IS NULL",
+                        &[&username, &crate::password::NO_PASSWORD],
+                    )
+                    .await?;
+                let taken: bool = tx
+                    .query_one(
+                        "SELECT COUNT(*) > 0 FROM users WHERE username = $1",
+                        &[&username],
+                    )
+                    .await?
+                    .try_get(0)?;
+                let id = match unlinked {
+                    Some(row) => row.try_get(0)?,
+                    None if taken => return Ok(Provisioned::NameTaken),
+                    None => {
+                        insert_user(
+                            &tx,
+                            username,
+                            crate::password::NO_PASSWORD,
+                            display_name,
+                            role,
+                            false,
+                            None,
+                        )
+                        .await?
+                        .id
+                    }
+                };
+                tx.execute(
+                    "UPDATE users SET auth_source = $1, external_id = $2 WHERE id = $3",
+                    &[&source, &external_id, &id],
+                )
+                .await?;
+                id
+            }
+        };
+        let changes = UserChanges {
+            display_name: Some(display_name.to_string()).filter(|d| !d.is_empty()),
+            role: Some(role.to_string()),
+            ..Default::default()
+        };
+        let user = update_user_fields(&tx, &id, &changes)
+            .await?
+            .context("Provisioned user not found")?;
+        tx.commit().await?;
+        Ok(Provisioned::User(Box::new(user)))
+    }
+
+    async fn update_user(&self, id: &str, changes: &UserChanges) -> Result<Option<User>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let user = update_user_fields(&tx, id, changes).await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let rows = tx
+            .execute(
+                "UPDATE users SET enabled = FALSE, deleted_at = koder_now(), updated_at = koder_now()
+                 WHERE id = $1 AND deleted_at IS NULL",
+                &[&id],
+            )
+            .await?;
+        tx.execute("DELETE FROM api_tokens WHERE user_id = $1", &[&id])
+            .await?;
+        tx.execute("DELETE FROM group_members WHERE user_id = $1", &[&id])
+            .await?;
+        tx.commit().await?;
+        Ok(rows > 0)
+    }
+
+    async fn purge_user(&self, id: &str) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.execute("DELETE FROM api_tokens WHERE user_id = $1", &[&id])
+            .await?;
+        tx.execute("DELETE FROM group_members WHERE user_id = $1", &[&id])
+            .await?;
+        tx.execute("DELETE FROM password_history WHERE user_id = $1", &[&id])
+            .await?;
+        let rows = tx
+            .execute("DELETE FROM users WHERE id = $1", &[&id])
+            .await?;
+        tx.commit().await?;
+        Ok(rows > 0)
+    }
+
+    async fn update_password(&self, id: &str, password_hash: &str) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let rows = tx
+            .execute(
+                "UPDATE users SET password_hash = $1, must_change_password = FALSE, updated_at = koder_now()
+                 WHERE id = $2 AND deleted_at IS NULL",
+                &[&password_hash, &id],
+            )
+            .await?;
+        if rows > 0 {
+            record_password(&tx, id, password_hash).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn record_login(&self, id: &str) -> Result<()> {
+        self.client()
+            .await?
+            .execute("UPDATE users SET last_login_at = koder_now() WHERE id = $1", &[&id])
+            .await?;
+        Ok(())
+    }
+
+    async fn rehash_password(&self, id: &str, expected: &str, password_hash: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute(
+                "UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3",
+                &[&password_hash, &id, &expected],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn recent_password_hashes(&self, user_id: &str, limit: usize) -> Result<Vec<String>> {
+        let client = self.client().await?;
+        let mut hashes: Vec<String> = client
+            .query_opt("SELECT password_hash FROM users WHERE id = $1", &[&user_id])
+            .await?
+            .map(|r| r.try_get(0))
+            .transpose()?
+            .into_iter()
+            .collect();
+        let rows = client
+            .query(
+                "SELECT password_hash FROM password_history WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
+                &[&user_id, &(limit as i64)],
+            )
+            .await?;
+        for row in rows {
+            let hash: String = row.try_get(0)?;
+            if !hashes.contains(&hash) {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[String],
+        expires_in_days: u32,
+    ) -> Result<ApiToken> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                "INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, expires_at)
+                 VALUES ($1, $2, $3, $4, $5,
+                    to_char((now() AT TIME ZONE 'utc') + make_interval(days => $6), 'YYYY-MM-DD HH24:MI:SS'))
+                 RETURNING id, name, scopes, expires_at, created_at, last_used_at",
+                &[
+                    &id,
+                    &user_id,
+                    &name,
+                    &token_hash,
+                    &scopes.join(" "),
+                    &(expires_in_days as i32),
+                ],
+            )
+            .await?;
+        Ok(map_api_token(&row)?)
+    }
+
+    async fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT id, name, scopes, expires_at, created_at, last_used_at FROM api_tokens WHERE user_id = $1 ORDER BY created_at",
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows.iter().map(map_api_token).collect::<Result<_, _>>()?)
+    }
+
+    async fn delete_api_token(&self, user_id: &str, id: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute(
+                "DELETE FROM api_tokens WHERE id = $1 AND user_id = $2",
+                &[&id, &user_id],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn revoke_api_tokens(&self, user_id: Option<&str>) -> Result<usize> {
+        let client = self.client().await?;
+        let rows = match user_id {
+            Some(id) => {
+                client
+                    .execute("DELETE FROM api_tokens WHERE user_id = $1", &[&id])
+                    .await?
+            }
+            None => client.execute("DELETE FROM api_tokens", &[]).await?,
+        };
+        Ok(rows as usize)
+    }
+
+    async fn get_api_token_owner(&self, token_hash: &str) -> Result<Option<ApiTokenOwner>> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                "SELECT t.id, t.scopes, EXTRACT(EPOCH FROM t.expires_at::timestamp)::BIGINT,
+                        u.id, u.username, u.role, u.must_change_password
+                 FROM api_tokens t JOIN users u ON u.id = t.user_id
+                 WHERE t.token_hash = $1 AND t.expires_at > koder_now() AND u.enabled
+                   AND u.deleted_at IS NULL AND (u.expires_at IS NULL OR u.expires_at > koder_now())",
+                &[&token_hash],
+            )
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let owner = ApiTokenOwner {
+            token_id: row.try_get(0)?,
+            scopes: split(row.try_get(1)?),
+            expires_at: row.try_get(2)?,
+            user_id: row.try_get(3)?,
+            username: row.try_get(4)?,
+            role: row.try_get(5)?,
+            must_change_password: row.try_get(6)?,
+        };
+        client
+            .execute(
+                "UPDATE api_tokens SET last_used_at = koder_now() WHERE id = $1",
+                &[&owner.token_id],
+            )
+            .await?;
+        Ok(Some(owner))
+    }
+
+    async fn role_has_permission(&self, role: &str, permission: &str) -> Result<bool> {
+        Ok(self
+            .client()
+            .await?
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM role_permissions WHERE role = $1 AND permission = $2)",
+                &[&role, &permission],
+            )
+            .await?
+            .try_get(0)?)
+    }
+
+    async fn role_exists(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .client()
+            .await?
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM roles WHERE name = $1)",
+                &[&name],
+            )
+            .await?
+            .try_get(0)?)
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!("{ROLE_QUERY} GROUP BY r.name ORDER BY r.builtin DESC, r.name"),
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(map_role).collect::<Result<_, _>>()?)
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>> {
+        let row = self
+            .client()
+            .await?
+            .query_opt(
+                &format!("{ROLE_QUERY} WHERE r.name = $1 GROUP BY r.name"),
+                &[&name],
+            )
+            .await?;
+        Ok(row.as_ref().map(map_role).transpose()?)
+    }
+
+    async fn create_role(
+        &self,
+        name: &str,
+        description: &str,
+        permissions: &[String],
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.execute(
+            "INSERT INTO roles (name, description) VALUES ($1, $2)",
+            &[&name, &description],
+        )
+        .await?;
+        for permission in permissions {
+            tx.execute(
+                "INSERT INTO role_permissions (role, permission) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&name, permission],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn update_role(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        permissions: Option<&[String]>,
+    ) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let exists: bool = tx
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM roles WHERE name = $1 AND NOT builtin)",
+                &[&name],
+            )
+            .await?
+            .try_get(0)?;
+        if !exists {
+            return Ok(false);
+        }
+        if let Some(d) = description {
+            tx.execute(
+                "UPDATE roles SET description = $1 WHERE name = $2",
+                &[&d, &name],
+            )
+            .await?;
+        }
+        if let Some(perms) = permissions {
+            tx.execute("DELETE FROM role_permissions WHERE role = $1", &[&name])
+                .await?;
+            for permission in perms {
+                tx.execute(
+                    "INSERT INTO role_permissions (role, permission) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                    &[&name, permission],
+                )
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn count_users_with_role(&self, name: &str) -> Result<i64> {
+        Ok(self
+            .client()
+            .await?
+            .query_one(
+                "SELECT COUNT(*) FROM users WHERE role = $1 AND deleted_at IS NULL",
+                &[&name],
+            )
+            .await?
+            .try_get(0)?)
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute(
+                "DELETE FROM roles WHERE name = $1 AND NOT builtin",
+                &[&name],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!("SELECT {GROUP_COLUMNS} FROM groups g ORDER BY g.name"),
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(map_group).collect::<Result<_, _>>()?)
+    }
+
+    async fn get_group(&self, id: &str) -> Result<Option<Group>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        group_by_id(&tx, id).await
+    }
+
+    async fn create_group(&self, name: &str, description: &str) -> Result<Group> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.execute(
+            "INSERT INTO groups (id, name, description) VALUES ($1, $2, $3)",
+            &[&id, &name, &description],
+        )
+        .await?;
+        let group = group_by_id(&tx, &id).await?.unwrap();
+        tx.commit().await?;
+        Ok(group)
+    }
+
+    async fn update_group(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<Option<Group>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        if let Some(n) = name {
+            tx.execute(
+                "UPDATE groups SET name = $1, updated_at = koder_now() WHERE id = $2",
+                &[&n, &id],
+            )
+            .await?;
+        }
+        if let Some(d) = description {
+            tx.execute(
+                "UPDATE groups SET description = $1, updated_at = koder_now() WHERE id = $2",
+                &[&d, &id],
+            )
+            .await?;
+        }
+        let group = group_by_id(&tx, id).await?;
+        tx.commit().await?;
+        Ok(group)
+    }
+
+    async fn delete_group(&self, id: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute("DELETE FROM groups WHERE id = $1", &[&id])
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn list_group_members(&self, group_id: &str) -> Result<Vec<User>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {USER_COLUMNS} FROM users
+                     WHERE id IN (SELECT user_id FROM group_members WHERE group_id = $1)
+                       AND deleted_at IS NULL
+                     ORDER BY username"
+                ),
+                &[&group_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|r| map_user_row(r).map(|u| u.to_public()))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn add_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO group_members (group_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&group_id, &user_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute(
+                "DELETE FROM group_members WHERE group_id = $1 AND user_id = $2",
+                &[&group_id, &user_id],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn list_connections(&self) -> Result<Vec<ConnectionProfile>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!("SELECT {CONNECTION_COLUMNS} FROM connections c ORDER BY c.name"),
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(map_connection).collect::<Result<_, _>>()?)
+    }
+
+    async fn list_connections_for_user(&self, user_id: &str) -> Result<Vec<ConnectionProfile>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {CONNECTION_COLUMNS} FROM connections c
+                     WHERE c.id IN (
+                        SELECT gc.connection_id FROM group_connections gc
+                        JOIN group_members m ON m.group_id = gc.group_id
+                        WHERE m.user_id = $1
+                     )
+                     ORDER BY c.name"
+                ),
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows.iter().map(map_connection).collect::<Result<_, _>>()?)
+    }
+
+    async fn get_connection(&self, id: &str) -> Result<Option<ConnectionProfile>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        connection_by_id(&tx, id).await
+    }
+
+    async fn create_connection(
+        &self,
+        name: &str,
+        protocol: &str,
+        host: &str,
+        port: u16,
+        group_ids: &[String],
+    ) -> Result<ConnectionProfile> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.execute(
+            "INSERT INTO connections (id, name, protocol, host, port) VALUES ($1, $2, $3, $4, $5)",
+            &[&id, &name, &protocol, &host, &(port as i32)],
+        )
+        .await?;
+        for group_id in group_ids {
+            tx.execute(
+                "INSERT INTO group_connections (group_id, connection_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[group_id, &id],
+            )
+            .await?;
+        }
+        let connection = connection_by_id(&tx, &id).await?.unwrap();
+        tx.commit().await?;
+        Ok(connection)
+    }
+
+    async fn update_connection(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        host: Option<&str>,
+        port: Option<u16>,
+        group_ids: Option<&[String]>,
+    ) -> Result<Option<ConnectionProfile>> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        if let Some(n) = name {
+            tx.execute(
+                "UPDATE connections SET name = $1, updated_at = koder_now() WHERE id = $2",
+                &[&n, &id],
+            )
+            .await?;
+        }
+        if let Some(h) = host {
+            tx.execute(
+                "UPDATE connections SET host = $1, updated_at = koder_now() WHERE id = $2",
+                &[&h, &id],
+            )
+            .await?;
+        }
+        if let Some(p) = port {
+            tx.execute(
+                "UPDATE connections SET port = $1, updated_at = koder_now() WHERE id = $2",
+                &[&(p as i32), &id],
+            )
+            .await?;
+        }
+        if let Some(groups) = group_ids {
+            tx.execute(
+                "DELETE FROM group_connections WHERE connection_id = $1",
+                &[&id],
+            )
+            .await?;
+            for group_id in groups {
+                tx.execute(
+                    "INSERT INTO group_connections (group_id, connection_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                    &[group_id, &id],
+                )
+                .await?;
+            }
+        }
+        let connection = connection_by_id(&tx, id).await?;
+        tx.commit().await?;
+        Ok(connection)
+    }
+
+    async fn delete_connection(&self, id: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute("DELETE FROM connections WHERE id = $1", &[&id])
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn list_destination_rules(&self, group_id: &str) -> Result<Vec<DestinationRule>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT id, group_id, pattern, created_at FROM destination_rules WHERE group_id = $1 ORDER BY created_at",
+                &[&group_id],
+            )
+            .await?;
+        Ok(rows.iter().map(map_destination_rule).collect::<Result<_, _>>()?)
+    }
+
+    async fn create_destination_rule(
+        &self,
+        group_id: &str,
+        pattern: &str,
+    ) -> Result<DestinationRule> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let row = self
+            .client()
+            .await?
+            .query_one(
+                "INSERT INTO destination_rules (id, group_id, pattern) VALUES ($1, $2, $3)
+                 RETURNING id, group_id, pattern, created_at",
+                &[&id, &group_id, &pattern],
+            )
+            .await?;
+        Ok(map_destination_rule(&row)?)
+    }
+
+    async fn delete_destination_rule(&self, group_id: &str, id: &str) -> Result<bool> {
+        let rows = self
+            .client()
+            .await?
+            .execute(
+                "DELETE FROM destination_rules WHERE id = $1 AND group_id = $2",
+                &[&id, &group_id],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    async fn destination_patterns_for_user(&self, user_id: &str) -> Result<Vec<String>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT r.pattern FROM destination_rules r
+                 JOIN group_members m ON m.group_id = r.group_id
+                 WHERE m.user_id = $1
+                 UNION
+                 SELECT c.host || ':' || c.port::text FROM connections c
+                 JOIN group_connections gc ON gc.connection_id = c.id
+                 JOIN group_members m ON m.group_id = gc.group_id
+                 WHERE m.user_id = $1",
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|r| r.try_get(0))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn append_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        details: &str,
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        // Serializes appends so two writers cannot both extend the same head.
+        tx.batch_execute("LOCK TABLE audit_log IN EXCLUSIVE MODE")
+            .await?;
+        let prev_hash: String = match tx
+            .query_opt("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1", &[])
+            .await?
+        {
+            Some(row) => row.try_get(0)?,
+            None => crate::audit::GENESIS.to_string(),
+        };
+        let at: String = tx.query_one("SELECT koder_now()", &[]).await?.try_get(0)?;
+        let hash = crate::audit::entry_hash(&prev_hash, &at, actor, action, target, details);
+        tx.execute(
+            "INSERT INTO audit_log (at, actor, action, target, details, prev_hash, hash)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[&at, &actor, &action, &target, &details, &prev_hash, &hash],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_audit(&self) -> Result<Vec<AuditEntry>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT id, at, actor, action, target, details, prev_hash, hash
+                 FROM audit_log ORDER BY id",
+                &[],
+            )
+            .await?;
+        let entries = rows
+            .iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.try_get(0)?,
+                    at: row.try_get(1)?,
+                    actor: row.try_get(2)?,
+                    action: row.try_get(3)?,
+                    target: row.try_get(4)?,
+                    details: row.try_get(5)?,
+                    prev_hash: row.try_get(6)?,
+                    hash: row.try_get(7)?,
+                })
+            })
+            .collect::<Result<_, tokio_postgres::Error>>()?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /// The two migration lists are kept by hand; they must stay in step.
+    #[test]
+    fn migrations_mirror_sqlite() {
+        let postgres: Vec<_> = super::MIGRATIONS.iter().map(|m| (m.0, m.1)).collect();
+        let sqlite: Vec<_> = crate::migrations::MIGRATIONS
+            .iter()
+            .map(|m| (m.version, m.name))
+            .collect();
+        assert_eq!(postgres, sqlite);
+    }
+}
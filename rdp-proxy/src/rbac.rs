@@ -0,0 +1,157 @@
+use axum::http::HeaderMap;
+
+use crate::auth::{extract_auth, Claims};
+use crate::error::ApiError;
+use crate::AppState;
+
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+pub const USERS_RESET_PASSWORD: &str = "users:reset_password";
+pub const ROLES_ADMIN: &str = "roles:admin";
+pub const GROUPS_ADMIN: &str = "groups:admin";
+pub const CONNECTIONS_ADMIN: &str = "connections:admin";
+pub const SESSIONS_READ: &str = "sessions:read";
+pub const SESSIONS_ADMIN: &str = "sessions:admin";
+pub const AUDIT_READ: &str = "audit:read";
+pub const RECORDINGS_READ: &str = "recordings:read";
+
+/// Every permission a role can be granted. API token scopes use the same
+/// names.
+pub const ALL_PERMISSIONS: &[&str] = &[
+    USERS_READ,
+    USERS_WRITE,
+    USERS_RESET_PASSWORD,
+    ROLES_ADMIN,
+    GROUPS_ADMIN,
+    CONNECTIONS_ADMIN,
+    SESSIONS_READ,
+    SESSIONS_ADMIN,
+    AUDIT_READ,
+    RECORDINGS_READ,
+];
+
+/// Scopes that grant nothing by themselves: a token needs one to do what
+/// every user may do, such as opening an RDP session to an allowed host.
+/// Session JWTs hold them all.
+pub const RDP_CONNECT: &str = "rdp:connect";
+pub const CONNECTIONS_READ: &str = "connections:read";
+pub const PROFILE_READ: &str = "profile:read";
+
+const TOKEN_ONLY_SCOPES: &[&str] = &[RDP_CONNECT, CONNECTIONS_READ, PROFILE_READ];
+
+/// Roles that always exist. Their permissions are re-synced at startup and
+/// cannot be changed through the API.
+pub const BUILTIN_ROLES: &[(&str, &str, &[&str])] = &[
+    ("admin", "Administrador", ALL_PERMISSIONS),
+    ("user", "Usuário", &[]),
+    (
+        "auditor",
+        "Auditor (somente leitura de auditoria e gravações)",
+        &[AUDIT_READ, RECORDINGS_READ],
+    ),
+    (
+        "operator",
+        "Operador (gerencia sessões)",
+        &[SESSIONS_READ, SESSIONS_ADMIN],
+    ),
+    (
+        "helpdesk",
+        "Suporte (redefinição de senhas)",
+        &[USERS_READ, USERS_RESET_PASSWORD],
+    ),
+];
+
+pub fn is_permission(name: &str) -> bool {
+    ALL_PERMISSIONS.contains(&name)
+}
+
+/// Whether an API token may be created with `name` as a scope.
+pub fn is_scope(name: &str) -> bool {
+    is_permission(name) || TOKEN_ONLY_SCOPES.contains(&name)
+}
+
+/// For actions any user may take, which API tokens must still be scoped for.
+pub fn require_scope(claims: &Claims, scope: &str) -> Result<(), ApiError> {
+    if !claims.has_scope(scope) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(())
+}
+
+/// Whether the caller holds `permission` through their role and, for API
+/// tokens, through the token's scopes as well.
+pub async fn has_permission(
+    state: &AppState,
+    claims: &Claims,
+    permission: &str,
+) -> Result<bool, ApiError> {
+    if !claims.has_scope(permission) {
+        return Ok(false);
+    }
+    let granted = state
+        .db
+        .role_has_permission(&claims.role, permission)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(granted)
+}
+
+pub async fn require_permission(
+    headers: &HeaderMap,
+    state: &AppState,
+    permission: &str,
+) -> Result<Claims, ApiError> {
+    let claims = extract_auth(headers, state).await?;
+    if !has_permission(state, &claims, permission).await? {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::UserChanges;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn checks_the_current_role_not_the_one_at_login() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let user = test_support::user(&state, "alice", "Alice-Pass-123!", "admin").await;
+        let headers = test_support::session_headers(&state, &user);
+        require_permission(&headers, &state, USERS_WRITE).await.unwrap();
+
+        let changes = UserChanges {
+            role: Some("helpdesk".into()),
+            ..Default::default()
+        };
+        state.db.update_user(&user.id, &changes).await.unwrap();
+        let denied = require_permission(&headers, &state, USERS_WRITE).await;
+        assert!(matches!(denied, Err(ApiError::Forbidden)));
+        require_permission(&headers, &state, USERS_RESET_PASSWORD)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_builtin_permission_is_granted_to_a_role_below_admin() {
+        // A permission only admins hold could just as well be `admin`.
+        let (state, _dir) = test_support::state(Config::default()).await;
+        for permission in [
+            SESSIONS_READ,
+            SESSIONS_ADMIN,
+            USERS_RESET_PASSWORD,
+            AUDIT_READ,
+            RECORDINGS_READ,
+        ] {
+            let mut holders = Vec::new();
+            for (role, _, _) in BUILTIN_ROLES.iter().filter(|(r, _, _)| *r != "admin") {
+                if state.db.role_has_permission(role, permission).await.unwrap() {
+                    holders.push(*role);
+                }
+            }
+            assert!(!holders.is_empty(), "{permission} is admin-only");
+        }
+    }
+}
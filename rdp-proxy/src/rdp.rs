@@ -1,33 +1,197 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context as _};
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
 use axum::extract::State;
-use axum::response::IntoResponse;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use ironrdp_rdcleanpath::RDCleanPathPdu;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{error, info};
+use tokio_native_tls::TlsStream;
+use tracing::{error, field, info, info_span, Instrument as _, Span};
 
+use crate::error::ApiError;
 use crate::AppState;
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    State(_state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| async {
-        if let Err(e) = handle_rdp_connection(socket).await {
-            error!("RDP proxy error: {e:#}");
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    // CORS does not cover WebSocket upgrades, so the Origin is checked here.
+    if !crate::origin::allowed(&state.config.server, &headers) {
+        tracing::warn!(
+            "Refused RDP WebSocket from {:?}",
+            headers.get(axum::http::header::ORIGIN)
+        );
+        return ApiError::OriginNotAllowed.into_response();
+    }
+    if state.draining.load(Ordering::Relaxed) {
+        return ApiError::ShuttingDown.into_response();
+    }
+
+    // The relay outlives the upgrade request, so it gets its own span;
+    // `user` and `destination` are filled in once the handshake knows them.
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let span = info_span!(
+        "rdp_session",
+        session_id,
+        user = field::Empty,
+        destination = field::Empty,
+    );
+    ws.on_upgrade(|socket| {
+        async move {
+            if let Err(e) = handle_rdp_connection(socket, state, session_id).await {
+                error!("RDP proxy error: {e:#}");
+            }
         }
+        .instrument(span)
     })
 }
 
-async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
+async fn handle_rdp_connection(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    session_id: String,
+) -> anyhow::Result<()> {
     info!("New RDP WebSocket connection");
+    let session = state.sessions.register(&session_id);
 
     let (mut ws_write, mut ws_read) = socket.split();
 
+    let timeout = Duration::from_secs(state.config.timeouts.rdp_handshake_secs);
+    let handshake = tokio::time::timeout(
+        timeout,
+        handshake(&mut ws_write, &mut ws_read, &state).instrument(info_span!("handshake")),
+    );
+    let (tls_stream, destination, username) = match handshake.await {
+        Ok(result) => result?,
+        Err(_) => {
+            state.metrics.rdcleanpath_error("timeout");
+            let _ = send_http_error(&mut ws_write, 504).await;
+            return Err(anyhow!(
+                "RDP handshake timed out after {}s",
+                timeout.as_secs()
+            ));
+        }
+    };
+
+    session.set_target(&username, &destination);
+    info!("RDCleanPath handshake complete for {destination}, starting relay");
+
+    // Step 6: Bidirectional relay (WebSocket <-> TLS TCP)
+    let (mut rdp_read, mut rdp_write) = tokio::io::split(tls_stream);
+    let _session = state.metrics.session("rdp");
+    let client_to_server = state
+        .metrics
+        .relayed_bytes
+        .with_label_values(&["client_to_server"]);
+    let server_to_client = state
+        .metrics
+        .relayed_bytes
+        .with_label_values(&["server_to_client"]);
+
+    let ws_to_rdp = async {
+        while let Some(msg) = ws_read.next().await {
+            match msg {
+                Ok(Message::Binary(data)) => {
+                    if rdp_write.write_all(&data).await.is_err() {
+                        break;
+                    }
+                    client_to_server.inc_by(data.len() as u64);
+                }
+                Ok(Message::Close(_)) | Err(_) => break,
+                _ => continue,
+            }
+        }
+        let _ = rdp_write.shutdown().await;
+    };
+
+    let rdp_to_ws = async {
+        let mut buf = vec![0u8; 16384];
+        let mut warned = false;
+        loop {
+            let read = tokio::select! {
+                read = rdp_read.read(&mut buf) => read,
+                grace = state.sessions.shutdown_notice(), if !warned => {
+                    // A text frame, so clients can tell it from RDP traffic.
+                    warned = true;
+                    let notice = serde_json::json!({
+                        "type": "shutdown",
+                        "grace_secs": grace.as_secs(),
+                        "message": format!(
+                            "Servidor reiniciando; a sessão será encerrada em {}s",
+                            grace.as_secs()
+                        ),
+                    });
+                    if ws_write.send(Message::Text(notice.to_string())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match read {
+                Ok(0) => break,
+                Ok(n) => {
+                    if ws_write
+                        .send(Message::Binary(buf[..n].to_vec()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    server_to_client.inc_by(n as u64);
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = ws_write.close().await;
+    };
+
+    tokio::select! {
+        _ = ws_to_rdp => {
+            info!("WebSocket side closed for {destination}");
+        }
+        _ = rdp_to_ws => {
+            info!("RDP side closed for {destination}");
+        }
+        _ = session.terminated() => {
+            info!("Closing relay to {destination}: terminated by an operator");
+            let _ = ws_write
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Sessão encerrada pelo administrador".into(),
+                })))
+                .await;
+        }
+        _ = state.sessions.closing() => {
+            info!("Closing relay to {destination} for shutdown");
+            let _ = ws_write
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::RESTART,
+                    reason: "Servidor reiniciando".into(),
+                })))
+                .await;
+        }
+    }
+
+    info!("Connection to {destination} terminated");
+    Ok(())
+}
+
+/// Steps 1-5: everything from the first WebSocket message until the relay
+/// can start. Returns the TLS stream to the RDP server, its address and the
+/// user's name.
+async fn handshake(
+    ws_write: &mut SplitSink<WebSocket, Message>,
+    ws_read: &mut SplitStream<WebSocket>,
+    state: &AppState,
+) -> anyhow::Result<(TlsStream<TcpStream>, String, String)> {
     // Step 1: Read the RDCleanPath request (first binary message)
     let request_bytes = loop {
         match ws_read.next().await {
@@ -37,28 +201,40 @@ async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
                 continue;
             }
             Some(Ok(Message::Close(_))) | None => {
+                state.metrics.rdcleanpath_error("closed");
                 return Err(anyhow!("Connection closed before RDCleanPath request"));
             }
             Some(Ok(_)) => continue,
-            Some(Err(e)) => return Err(anyhow!("WebSocket error: {e}")),
+            Some(Err(e)) => {
+                state.metrics.rdcleanpath_error("closed");
+                return Err(anyhow!("WebSocket error: {e}"));
+            }
         }
     };
 
     // Step 2: Parse the RDCleanPath request
     let pdu = RDCleanPathPdu::from_der(&request_bytes)
-        .map_err(|e| anyhow!("Failed to parse RDCleanPath PDU: {e}"))?;
+        .map_err(|e| anyhow!("Failed to parse RDCleanPath PDU: {e}"))
+        .inspect_err(|_| state.metrics.rdcleanpath_error("malformed"))?;
 
     let rdcleanpath = pdu
         .into_enum()
-        .map_err(|e| anyhow!("Invalid RDCleanPath PDU: {e}"))?;
+        .map_err(|e| anyhow!("Invalid RDCleanPath PDU: {e}"))
+        .inspect_err(|_| state.metrics.rdcleanpath_error("malformed"))?;
 
-    let (destination, x224_request) = match rdcleanpath {
+    let (destination, proxy_auth, x224_request) = match rdcleanpath {
         ironrdp_rdcleanpath::RDCleanPath::Request {
             destination,
+            proxy_auth,
             x224_connection_request,
             ..
-        } => (destination, x224_connection_request.as_bytes().to_vec()),
+        } => (
+            destination,
+            proxy_auth,
+            x224_connection_request.as_bytes().to_vec(),
+        ),
         _ => {
+            state.metrics.rdcleanpath_error("unexpected_pdu");
             let err_pdu = RDCleanPathPdu::new_general_error();
             let err_bytes = err_pdu
                 .to_der()
@@ -68,12 +244,55 @@ async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
         }
     };
 
-    info!("RDP destination: {destination}");
+    // The client sends its koder token as the RDCleanPath proxy_auth field.
+    let claims = match crate::auth::authenticate_token(&proxy_auth, state).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            state.metrics.rdcleanpath_error("unauthorized");
+            send_http_error(ws_write, 401).await?;
+            return Err(anyhow!("Rejected RDP connection with invalid token"));
+        }
+    };
+    if crate::rbac::require_scope(&claims, crate::rbac::RDP_CONNECT).is_err() {
+        state.metrics.rdcleanpath_error("forbidden");
+        send_http_error(ws_write, 403).await?;
+        return Err(anyhow!(
+            "Rejected RDP connection with a token of {} lacking {}",
+            claims.username,
+            crate::rbac::RDP_CONNECT
+        ));
+    }
 
-    // Step 3: Connect to the RDP server via TCP
-    let rdp_stream = TcpStream::connect(&destination)
+    let allowed = crate::connections::destination_allowed(state, &claims, &destination)
         .await
-        .context(format!("Failed to connect to RDP server at {destination}"))?;
+        .map_err(|_| anyhow!("Failed to check destination rules"))
+        .inspect_err(|_| state.metrics.rdcleanpath_error("internal"))?;
+    if !allowed {
+        state.metrics.rdcleanpath_error("forbidden");
+        send_http_error(ws_write, 403).await?;
+        return Err(anyhow!(
+            "{} is not allowed to reach {destination}",
+            claims.username
+        ));
+    }
+
+    Span::current()
+        .record("user", claims.username.as_str())
+        .record("destination", destination.as_str());
+    info!("RDP destination: {destination} (user {})", claims.username);
+
+    // Step 3: Connect to the RDP server via TCP
+    let connect_timeout = Duration::from_secs(state.config.timeouts.rdp_connect_secs);
+    let rdp_stream = match tokio::time::timeout(connect_timeout, connect(state, &destination)).await
+    {
+        Ok(result) => result?,
+        Err(_) => {
+            state.metrics.rdcleanpath_error("connect");
+            return Err(anyhow!(
+                "Timed out connecting to RDP server at {destination}"
+            ));
+        }
+    };
 
     let server_addr = rdp_stream
         .peer_addr()
@@ -81,27 +300,35 @@ async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
         .unwrap_or_else(|_| destination.clone());
 
     // Step 4: X.224 exchange then TLS handshake
+    let stage = Instant::now();
     let (mut rdp_read, mut rdp_write) = tokio::io::split(rdp_stream);
-    rdp_write
-        .write_all(&x224_request)
-        .await
-        .context("Failed to send X.224 request")?;
-
-    let mut tpkt_header = [0u8; 4];
-    rdp_read
-        .read_exact(&mut tpkt_header)
-        .await
-        .context("Failed to read X.224 response header")?;
+    let x224_response = async {
+        rdp_write
+            .write_all(&x224_request)
+            .await
+            .context("Failed to send X.224 request")?;
 
-    let tpkt_len = u16::from_be_bytes([tpkt_header[2], tpkt_header[3]]) as usize;
-    let mut x224_response = vec![0u8; tpkt_len];
-    x224_response[..4].copy_from_slice(&tpkt_header);
-    if tpkt_len > 4 {
+        let mut tpkt_header = [0u8; 4];
         rdp_read
-            .read_exact(&mut x224_response[4..])
+            .read_exact(&mut tpkt_header)
             .await
-            .context("Failed to read X.224 response body")?;
+            .context("Failed to read X.224 response header")?;
+
+        let tpkt_len = u16::from_be_bytes([tpkt_header[2], tpkt_header[3]]) as usize;
+        let mut x224_response = vec![0u8; tpkt_len];
+        x224_response[..4].copy_from_slice(&tpkt_header);
+        if tpkt_len > 4 {
+            rdp_read
+                .read_exact(&mut x224_response[4..])
+                .await
+                .context("Failed to read X.224 response body")?;
+        }
+        anyhow::Ok(x224_response)
     }
+    .instrument(info_span!("x224"))
+    .await
+    .inspect_err(|_| state.metrics.rdcleanpath_error("x224"))?;
+    observe_stage(state, "x224", stage);
 
     let rdp_stream = rdp_read.unsplit(rdp_write);
     let tls_connector = native_tls::TlsConnector::builder()
@@ -113,10 +340,14 @@ async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
 
     let hostname = destination.split(':').next().unwrap_or(&destination);
 
+    let stage = Instant::now();
     let tls_stream = tls_connector
         .connect(hostname, rdp_stream)
+        .instrument(info_span!("tls"))
         .await
-        .context("TLS handshake with RDP server failed")?;
+        .context("TLS handshake with RDP server failed")
+        .inspect_err(|_| state.metrics.rdcleanpath_error("tls"))?;
+    observe_stage(state, "tls", stage);
 
     let server_certs: Vec<Vec<u8>> = tls_stream
         .get_ref()
@@ -134,58 +365,57 @@ async fn handle_rdp_connection(socket: WebSocket) -> anyhow::Result<()> {
         .map_err(|e| anyhow!("DER encode error: {e}"))?;
     ws_write
         .send(Message::Binary(response_bytes))
+        .instrument(info_span!("rdcleanpath_response"))
         .await
         .context("Failed to send RDCleanPath response")?;
 
-    info!("RDCleanPath handshake complete for {destination}, starting relay");
-
-    // Step 6: Bidirectional relay (WebSocket <-> TLS TCP)
-    let (mut rdp_read, mut rdp_write) = tokio::io::split(tls_stream);
+    Ok((tls_stream, destination, claims.username))
+}
 
-    let ws_to_rdp = async {
-        while let Some(msg) = ws_read.next().await {
-            match msg {
-                Ok(Message::Binary(data)) => {
-                    if rdp_write.write_all(&data).await.is_err() {
-                        break;
-                    }
-                }
-                Ok(Message::Close(_)) | Err(_) => break,
-                _ => continue,
-            }
-        }
-        let _ = rdp_write.shutdown().await;
-    };
+/// Resolves `destination` and connects to the first address that accepts.
+async fn connect(state: &AppState, destination: &str) -> anyhow::Result<TcpStream> {
+    let stage = Instant::now();
+    let addrs: Vec<_> = tokio::net::lookup_host(destination)
+        .instrument(info_span!("dns"))
+        .await
+        .with_context(|| format!("Failed to resolve {destination}"))
+        .inspect_err(|_| state.metrics.rdcleanpath_error("dns"))?
+        .collect();
+    observe_stage(state, "dns", stage);
 
-    let rdp_to_ws = async {
-        let mut buf = vec![0u8; 16384];
-        loop {
-            match rdp_read.read(&mut buf).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    if ws_write
-                        .send(Message::Binary(buf[..n].to_vec()))
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    }
-                }
-                Err(_) => break,
+    let stage = Instant::now();
+    let mut last_error = anyhow!("{destination} resolved to no addresses");
+    for addr in addrs {
+        match TcpStream::connect(addr)
+            .instrument(info_span!("tcp", %addr))
+            .await
+        {
+            Ok(stream) => {
+                observe_stage(state, "tcp", stage);
+                return Ok(stream);
             }
-        }
-        let _ = ws_write.close().await;
-    };
-
-    tokio::select! {
-        _ = ws_to_rdp => {
-            info!("WebSocket side closed for {destination}");
-        }
-        _ = rdp_to_ws => {
-            info!("RDP side closed for {destination}");
+            Err(e) => last_error = e.into(),
         }
     }
+    state.metrics.rdcleanpath_error("connect");
+    Err(last_error.context(format!("Failed to connect to RDP server at {destination}")))
+}
 
-    info!("Connection to {destination} terminated");
+fn observe_stage(state: &AppState, stage: &str, start: Instant) {
+    state
+        .metrics
+        .handshake_seconds
+        .with_label_values(&[stage])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+async fn send_http_error<S>(ws_write: &mut S, status: u16) -> anyhow::Result<()>
+where
+    S: futures_util::Sink<Message, Error = axum::Error> + Unpin,
+{
+    let err_bytes = RDCleanPathPdu::new_http_error(status)
+        .to_der()
+        .map_err(|e| anyhow!("DER encode error: {e}"))?;
+    ws_write.send(Message::Binary(err_bytes)).await?;
     Ok(())
 }
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::db::Role;
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::rbac::{self, require_permission};
+use crate::AppState;
+
+fn validate_permissions(permissions: &[String]) -> Result<(), ApiError> {
+    if let Some(bad) = permissions.iter().find(|p| !rbac::is_permission(p)) {
+        return Err(ApiError::UnknownPermission(bad.clone()));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/permissions",
+    tag = "roles",
+    responses(
+        (status = 200, body = [String]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_permissions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<&'static [&'static str]>, ApiError> {
+    require_permission(&headers, &state, rbac::USERS_READ).await?;
+    Ok(Json(rbac::ALL_PERMISSIONS))
+}
+
+#[utoipa::path(
+    get,
+    path = "/roles",
+    tag = "roles",
+    responses(
+        (status = 200, body = [Role]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_roles(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Role>>, ApiError> {
+    require_permission(&headers, &state, rbac::USERS_READ).await?;
+
+    let roles = state.db.list_roles().await.map_err(ApiError::internal)?;
+
+    Ok(Json(roles))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateRoleRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/roles",
+    tag = "roles",
+    request_body = CreateRoleRequest,
+    responses(
+        (status = 201, body = Role),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn create_role(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateRoleRequest>,
+) -> Result<(StatusCode, Json<Role>), ApiError> {
+    let claims = require_permission(&headers, &state, rbac::ROLES_ADMIN).await?;
+
+    let name = body.name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+    {
+        return Err(ApiError::InvalidRoleName);
+    }
+    validate_permissions(&body.permissions)?;
+
+    state
+        .db
+        .create_role(
+            name,
+            body.description.as_deref().unwrap_or(""),
+            &body.permissions,
+        )
+        .await
+        .map_err(|e| {
+            if crate::storage::is_unique_violation(&e) {
+                ApiError::RoleExists
+            } else {
+                ApiError::internal(e)
+            }
+        })?;
+
+    let role = state
+        .db
+        .get_role(name)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::Internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "role.create",
+        &role.name,
+        serde_json::json!({ "permissions": role.permissions }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(role)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateRoleRequest {
+    pub description: Option<String>,
+    pub permissions: Option<Vec<String>>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/roles/{name}",
+    tag = "roles",
+    params(("name" = String, Path)),
+    request_body = UpdateRoleRequest,
+    responses(
+        (status = 200, body = Role),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn update_role(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(body): Json<UpdateRoleRequest>,
+) -> Result<Json<Role>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::ROLES_ADMIN).await?;
+
+    if let Some(ref perms) = body.permissions {
+        validate_permissions(perms)?;
+    }
+
+    let updated = state
+        .db
+        .update_role(
+            &name,
+            body.description.as_deref(),
+            body.permissions.as_deref(),
+        )
+        .await
+        .map_err(ApiError::internal)?;
+    if !updated {
+        return Err(ApiError::CustomRoleNotFound);
+    }
+
+    let role = state
+        .db
+        .get_role(&name)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::CustomRoleNotFound)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "role.update",
+        &role.name,
+        serde_json::json!({ "permissions": role.permissions }),
+    )
+    .await;
+
+    Ok(Json(role))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/roles/{name}",
+    tag = "roles",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn delete_role(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::ROLES_ADMIN).await?;
+
+    let in_use = state
+        .db
+        .count_users_with_role(&name)
+        .await
+        .map_err(ApiError::internal)?;
+    if in_use > 0 {
+        return Err(ApiError::RoleInUse);
+    }
+
+    let deleted = state
+        .db
+        .delete_role(&name)
+        .await
+        .map_err(ApiError::internal)?;
+    if !deleted {
+        return Err(ApiError::CustomRoleNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "role.delete",
+        &name,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::rbac::{self, require_permission};
+use crate::AppState;
+
+/// How long relays get to close after being told to, once the grace period
+/// is over.
+const FORCE_CLOSE_WAIT: Duration = Duration::from_secs(1);
+
+struct Entry {
+    user: String,
+    destination: String,
+    started_at: Instant,
+    /// Signalled to close this relay alone.
+    terminate: Arc<Notify>,
+}
+
+/// A running relay, as listed by `GET /sessions`.
+#[derive(Serialize, ToSchema)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Unset until the handshake has authenticated the user.
+    pub user: Option<String>,
+    pub destination: Option<String>,
+    pub duration_secs: u64,
+}
+
+/// Running RDP relays, so shutdown can wait for them and close the rest.
+pub struct Sessions {
+    active: Mutex<HashMap<String, Entry>>,
+    changed: Notify,
+    /// The grace period, once shutdown has started.
+    notice: watch::Sender<Option<Duration>>,
+    close: watch::Sender<bool>,
+}
+
+/// Keeps a relay registered until dropped.
+pub struct Session<'a> {
+    sessions: &'a Sessions,
+    id: String,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions {
+            active: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+            notice: watch::channel(None).0,
+            close: watch::channel(false).0,
+        }
+    }
+
+    pub fn register(&self, id: &str) -> Session<'_> {
+        let entry = Entry {
+            user: String::new(),
+            destination: String::new(),
+            started_at: Instant::now(),
+            terminate: Arc::new(Notify::new()),
+        };
+        self.active.lock().unwrap().insert(id.to_string(), entry);
+        Session {
+            sessions: self,
+            id: id.to_string(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, e)| SessionInfo {
+                id: id.clone(),
+                user: (!e.user.is_empty()).then(|| e.user.clone()),
+                destination: (!e.destination.is_empty()).then(|| e.destination.clone()),
+                duration_secs: e.started_at.elapsed().as_secs(),
+            })
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.duration_secs));
+        sessions
+    }
+
+    /// Tells the relay `id` to close. False if it is not running.
+    pub fn terminate(&self, id: &str) -> bool {
+        match self.active.lock().unwrap().get(id) {
+            Some(entry) => {
+                // `notify_one` keeps the wakeup if the relay is not waiting yet.
+                entry.terminate.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resolves with the grace period once the server starts stopping, so
+    /// relays can warn their users.
+    pub async fn shutdown_notice(&self) -> Duration {
+        let mut notice = self.notice.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let grace = notice.wait_for(Option::is_some).await.ok().and_then(|g| *g);
+        grace.unwrap_or_default()
+    }
+
+    /// Resolves once relays must close because the server is stopping.
+    pub async fn closing(&self) {
+        let mut close = self.close.subscribe();
+        let _ = close.wait_for(|close| *close).await;
+    }
+
+    /// Warns relays, waits up to `grace` for them to end on their own, then
+    /// tells the rest to close. A second SIGTERM or Ctrl-C skips the wait.
+    pub async fn drain(&self, grace: Duration) {
+        let start = Instant::now();
+        self.notice.send_replace(Some(grace));
+        let initial = self.len();
+        if initial > 0 {
+            info!(
+                "Waiting up to {}s for {initial} RDP session(s) to end",
+                grace.as_secs()
+            );
+        }
+
+        let finished = tokio::select! {
+            _ = self.wait_empty() => true,
+            _ = tokio::time::sleep(grace) => false,
+            _ = crate::shutdown_requested() => false,
+        };
+        if finished {
+            if initial > 0 {
+                info!(
+                    "All {initial} RDP session(s) ended after {}s",
+                    start.elapsed().as_secs()
+                );
+            }
+            return;
+        }
+
+        let remaining: Vec<String> = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|e| {
+                let user = if e.user.is_empty() { "-" } else { &e.user };
+                let destination = if e.destination.is_empty() {
+                    "(handshake)"
+                } else {
+                    &e.destination
+                };
+                format!(
+                    "{user} -> {destination} ({}s)",
+                    e.started_at.elapsed().as_secs()
+                )
+            })
+            .collect();
+        warn!(
+            "Closing {} of {initial} RDP session(s) still running: {}",
+            remaining.len(),
+            remaining.join(", ")
+        );
+        self.close.send_replace(true);
+        if tokio::time::timeout(FORCE_CLOSE_WAIT, self.wait_empty())
+            .await
+            .is_err()
+        {
+            warn!("{} RDP session(s) did not close in time", self.len());
+        }
+    }
+
+    async fn wait_empty(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.len() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Session<'_> {
+    /// Resolves once an operator has terminated this relay.
+    pub async fn terminated(&self) {
+        let terminate = self
+            .sessions
+            .active
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .map(|e| e.terminate.clone());
+        match terminate {
+            Some(terminate) => terminate.notified().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn set_target(&self, user: &str, destination: &str) {
+        if let Some(entry) = self.sessions.active.lock().unwrap().get_mut(&self.id) {
+            entry.user = user.to_string();
+            entry.destination = destination.to_string();
+        }
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        self.sessions.active.lock().unwrap().remove(&self.id);
+        self.sessions.changed.notify_waiters();
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    responses(
+        (status = 200, body = [SessionInfo]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    require_permission(&headers, &state, rbac::SESSIONS_READ).await?;
+    Ok(Json(state.sessions.list()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = String, Path)),
+    responses(
+        (status = 204),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn terminate_session(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::SESSIONS_ADMIN).await?;
+    if !state.sessions.terminate(&id) {
+        return Err(ApiError::SessionNotFound);
+    }
+    info!("{} terminated RDP session {id}", claims.username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn terminates_only_the_chosen_session() {
+        let sessions = Sessions::new();
+        let a = sessions.register("a");
+        let b = sessions.register("b");
+        a.set_target("alice", "10.0.0.1:3389");
+
+        assert!(sessions.terminate("a"));
+        assert!(!sessions.terminate("missing"));
+        tokio::time::timeout(Duration::from_secs(1), a.terminated())
+            .await
+            .unwrap();
+        let b_closed = tokio::time::timeout(Duration::from_millis(50), b.terminated()).await;
+        assert!(b_closed.is_err());
+
+        let listed = sessions.list();
+        assert_eq!(listed.len(), 2);
+        let a_info = listed.iter().find(|s| s.id == "a").unwrap();
+        assert_eq!(a_info.user.as_deref(), Some("alice"));
+        assert!(listed.iter().find(|s| s.id == "b").unwrap().user.is_none());
+    }
+
+    #[tokio::test]
+    async fn drain_warns_relays_before_waiting() {
+        let sessions = Arc::new(Sessions::new());
+        let session = sessions.register("a");
+        let drain = tokio::spawn({
+            let sessions = sessions.clone();
+            async move { sessions.drain(Duration::from_secs(60)).await }
+        });
+
+        let grace = tokio::time::timeout(Duration::from_secs(1), sessions.shutdown_notice())
+            .await
+            .unwrap();
+        assert_eq!(grace, Duration::from_secs(60));
+        assert!(!drain.is_finished());
+
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn operators_list_and_terminate_sessions() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let state = Arc::new(state);
+        let operator = test_support::user(&state, "olga", "Olga-Pass-123!", "operator").await;
+        let user = test_support::user(&state, "ursula", "Ursula-Pass-123!", "user").await;
+        let session = state.sessions.register("s1");
+
+        let denied = list_sessions(
+            State(state.clone()),
+            test_support::session_headers(&state, &user),
+        )
+        .await;
+        assert!(matches!(denied, Err(ApiError::Forbidden)));
+
+        let headers = test_support::session_headers(&state, &operator);
+        let Json(listed) = list_sessions(State(state.clone()), headers.clone())
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+
+        let status = terminate_session(State(state.clone()), headers.clone(), Path("s1".into()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        tokio::time::timeout(Duration::from_secs(1), session.terminated())
+            .await
+            .unwrap();
+        drop(session);
+        let gone = terminate_session(State(state.clone()), headers, Path("s1".into())).await;
+        assert!(matches!(gone, Err(ApiError::SessionNotFound)));
+    }
+}
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::auth::{create_token, LoginResponse};
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::password::Hasher;
+use crate::storage::Storage;
+use crate::AppState;
+
+/// The first admin's password, from `ADMIN_PASSWORD_FILE` (e.g. a Docker
+/// secret) or `ADMIN_PASSWORD`.
+fn initial_admin_password() -> anyhow::Result<Option<String>> {
+    if let Ok(path) = std::env::var("ADMIN_PASSWORD_FILE") {
+        let password = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read ADMIN_PASSWORD_FILE {path}"))?;
+        return Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    Ok(std::env::var("ADMIN_PASSWORD").ok())
+}
+
+/// Creates the first admin on an empty database. Without a configured
+/// password, returns a one-time setup token to be redeemed at `/api/v1/setup`.
+pub async fn bootstrap(db: &dyn Storage, hasher: &Hasher) -> anyhow::Result<Option<String>> {
+    if db.count_users().await? > 0 {
+        return Ok(None);
+    }
+
+    let username = std::env::var("ADMIN_USERNAME").unwrap_or_else(|_| "root".to_string());
+
+    if let Some(password) = initial_admin_password()? {
+        if password.is_empty() {
+            anyhow::bail!("Initial admin password is empty");
+        }
+        // Must be changed at first login, where the password policy applies.
+        let hash = hasher.hash(&password).await?;
+        db.create_user(&username, &hash, "Administrador", "admin", true, None)
+            .await?;
+        info!("Created initial admin '{username}'; password must be changed at first login");
+        return Ok(None);
+    }
+
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    warn!("No users exist. Create the first admin with POST /api/v1/setup using setup token: {token}");
+    Ok(Some(token))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SetupStatus {
+    /// No users exist yet, so the first admin must be created.
+    pub required: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/setup",
+    tag = "setup",
+    security(()),
+    responses(
+        (status = 200, body = SetupStatus),
+    )
+)]
+pub async fn status(State(state): State<Arc<AppState>>) -> Json<SetupStatus> {
+    let required = state.setup_token.lock().await.is_some();
+    Json(SetupStatus { required })
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SetupRequest {
+    pub token: String,
+    pub username: String,
+    pub password: String,
+    pub display_name: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/setup",
+    tag = "setup",
+    security(()),
+    request_body = SetupRequest,
+    responses(
+        (status = 201, body = LoginResponse),
+        (status = 400, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
+pub async fn setup(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<SetupRequest>,
+) -> Result<(StatusCode, Json<LoginResponse>), ApiError> {
+    // Held across the insert so two concurrent requests cannot both redeem
+    // the token.
+    let mut setup_token = state.setup_token.lock().await;
+
+    let expected = setup_token.as_deref().ok_or(ApiError::SetupCompleted)?;
+
+    if Sha256::digest(body.token.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+        return Err(ApiError::InvalidSetupToken);
+    }
+
+    if body.username.trim().is_empty() || body.password.is_empty() {
+        return Err(ApiError::UserFieldsRequired);
+    }
+
+    crate::password_policy::enforce(&state, body.username.trim(), &body.password, None).await?;
+    let password_hash = state
+        .hasher
+        .hash(&body.password)
+        .await
+        .map_err(ApiError::internal)?;
+
+    let user = state
+        .db
+        .create_user(
+            body.username.trim(),
+            &password_hash,
+            body.display_name.as_deref().unwrap_or("Administrador"),
+            "admin",
+            false,
+            None,
+        )
+        .await
+        .map_err(ApiError::internal)?;
+
+    *setup_token = None;
+    info!(
+        "Initial admin '{}' created through /api/v1/setup",
+        user.username
+    );
+    crate::audit::record(
+        &state,
+        &user.username,
+        "setup.admin_create",
+        &user.id,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    let token = create_token(&state, &user).map_err(ApiError::internal)?;
+
+    Ok((StatusCode::CREATED, Json(LoginResponse { token, user })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Database;
+    use crate::test_support;
+
+    /// `bootstrap` reads `ADMIN_*` from the process environment.
+    static ENV: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    const ENV_VARS: &[&str] = &["ADMIN_USERNAME", "ADMIN_PASSWORD", "ADMIN_PASSWORD_FILE"];
+
+    fn clear_env() {
+        for name in ENV_VARS {
+            std::env::remove_var(name);
+        }
+    }
+
+    #[tokio::test]
+    async fn bootstraps_the_admin_from_admin_password() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let token = {
+            let _env = ENV.lock().await;
+            clear_env();
+            std::env::set_var("ADMIN_USERNAME", "boss");
+            std::env::set_var("ADMIN_PASSWORD", "Initial-Pass-1");
+            let token = bootstrap(state.db.as_ref(), &state.hasher).await;
+            clear_env();
+            token.unwrap()
+        };
+        assert!(token.is_none());
+
+        let admin = state
+            .db
+            .get_user_by_username("boss")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(admin.role, "admin");
+        assert!(admin.must_change_password);
+        assert!(
+            state
+                .hasher
+                .verify("Initial-Pass-1", &admin.password_hash)
+                .await
+        );
+
+        // Only an empty database is bootstrapped.
+        assert!(bootstrap(state.db.as_ref(), &state.hasher)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(state.db.count_users().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_the_admin_password_from_a_file() {
+        let (state, dir) = test_support::state(Config::default()).await;
+        let path = dir.path().join("admin_password");
+        std::fs::write(&path, "From-A-Secret-1\n").unwrap();
+        let token = {
+            let _env = ENV.lock().await;
+            clear_env();
+            std::env::set_var("ADMIN_PASSWORD_FILE", &path);
+            std::env::set_var("ADMIN_PASSWORD", "ignored");
+            let token = bootstrap(state.db.as_ref(), &state.hasher).await;
+            clear_env();
+            token.unwrap()
+        };
+        assert!(token.is_none());
+
+        let root = state
+            .db
+            .get_user_by_username("root")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            state
+                .hasher
+                .verify("From-A-Secret-1", &root.password_hash)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn the_setup_token_creates_one_admin() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let token = {
+            let _env = ENV.lock().await;
+            clear_env();
+            bootstrap(state.db.as_ref(), &state.hasher).await.unwrap()
+        };
+        let token = token.expect("no setup token without ADMIN_PASSWORD");
+        assert_eq!(state.db.count_users().await.unwrap(), 0);
+        *state.setup_token.lock().await = Some(token.clone());
+        let state = Arc::new(state);
+
+        let request = |token: &str| SetupRequest {
+            token: token.to_string(),
+            username: "admin".to_string(),
+            password: "First-Koder-Pass-1".to_string(),
+            display_name: None,
+        };
+        let wrong = setup(State(state.clone()), Json(request("not-the-token"))).await;
+        assert!(matches!(wrong, Err(ApiError::InvalidSetupToken)));
+
+        let (status, Json(response)) = setup(State(state.clone()), Json(request(&token)))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(response.user.role, "admin");
+        assert!(!response.user.must_change_password);
+        assert!(!status_of(&state).await);
+
+        let again = setup(State(state.clone()), Json(request(&token))).await;
+        assert!(matches!(again, Err(ApiError::SetupCompleted)));
+        assert_eq!(state.db.count_users().await.unwrap(), 1);
+    }
+
+    async fn status_of(state: &Arc<AppState>) -> bool {
+        status(State(state.clone())).await.0.required
+    }
+
+    #[tokio::test]
+    async fn the_old_default_root_must_change_its_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("koder.db"), 1).unwrap();
+        db.initialize().await.unwrap();
+        // As seeded by releases before the setup flow.
+        let old_default = bcrypt::hash("Koder@123", 4).unwrap();
+        let root = db
+            .create_user("root", &old_default, "", "admin", false, None)
+            .await
+            .unwrap();
+        let other = db
+            .create_user("other", &old_default, "", "admin", false, None)
+            .await
+            .unwrap();
+
+        db.initialize().await.unwrap();
+        let root = db.get_user_by_id(&root.id).await.unwrap().unwrap();
+        assert!(root.must_change_password);
+        // Only root was seeded with it.
+        let other = db.get_user_by_id(&other.id).await.unwrap().unwrap();
+        assert!(!other.must_change_password);
+    }
+}
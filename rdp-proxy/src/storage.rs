@@ -0,0 +1,825 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::config::DatabaseConfig;
+use crate::db::{
+    ApiToken, ApiTokenOwner, AuditEntry, ConnectionProfile, DestinationRule, Group, Provisioned,
+    Role, User, UserChanges, UserPage, UserQuery, UserRow,
+};
+
+/// Everything the server persists. SQLite (`db::Database`) is the default;
+/// a `postgres://` `database.url` selects PostgreSQL in builds with the
+/// `postgres` feature, so several replicas can share one database.
+///
+/// Timestamps are `YYYY-MM-DD HH:MM:SS` UTC strings on every backend.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Applies pending schema migrations and syncs the built-in roles.
+    async fn initialize(&self) -> Result<()>;
+    /// Pending migrations as `NNNN name`, without applying them.
+    async fn pending_migrations(&self) -> Result<Vec<String>>;
+    /// Writes a consistent copy of the database to `dest`.
+    async fn backup(&self, dest: &std::path::Path) -> Result<()>;
+    /// Runs a trivial query, for readiness checks.
+    async fn ping(&self) -> Result<()>;
+
+    async fn get_user_by_username(&self, username: &str) -> Result<Option<UserRow>>;
+    async fn get_user_by_id(&self, id: &str) -> Result<Option<UserRow>>;
+    async fn list_users(&self, query: &UserQuery) -> Result<UserPage>;
+    /// Counts deleted users too, so deleting every account does not reopen
+    /// first-run setup.
+    async fn count_users(&self) -> Result<i64>;
+    /// `must_change_password` is set for passwords chosen by someone other
+    /// than the user, such as an admin or a deployment secret.
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        display_name: &str,
+        role: &str,
+        must_change_password: bool,
+        expires_at: Option<&str>,
+    ) -> Result<User>;
+    /// Just-in-time provisioning for users authenticated by an external
+    /// identity provider. `source` names the provider and `external_id` is
+    /// the identity's stable id there; the user is found by those, never by
+    /// username, so an identity cannot take over another account. New users
+    /// get no usable password so they can only log in through that provider;
+    /// linked users have their role synced.
+    async fn provision_external_user(
+        &self,
+        source: &str,
+        external_id: &str,
+        username: &str,
+        display_name: &str,
+        role: &str,
+    ) -> Result<Provisioned>;
+    /// Applies every change or none. `None` if there is no such user.
+    async fn update_user(&self, id: &str, changes: &UserChanges) -> Result<Option<User>>;
+    /// Soft delete: the row stays so audit entries and other references
+    /// still resolve, but the user is disabled, hidden from every lookup and
+    /// loses their API tokens and group memberships. The username stays
+    /// taken until the user is purged.
+    async fn delete_user(&self, id: &str) -> Result<bool>;
+    /// Removes the user's row for good, deleted or not.
+    async fn purge_user(&self, id: &str) -> Result<bool>;
+    async fn update_password(&self, id: &str, password_hash: &str) -> Result<()>;
+    async fn record_login(&self, id: &str) -> Result<()>;
+    /// Swaps the stored hash for a new hash of the same password, e.g. after
+    /// upgrading from bcrypt. `expected` guards against a concurrent change.
+    async fn rehash_password(&self, id: &str, expected: &str, password_hash: &str) -> Result<bool>;
+    /// The user's last `limit` password hashes, newest first. The current
+    /// hash is always included, even for accounts that predate the history.
+    async fn recent_password_hashes(&self, user_id: &str, limit: usize) -> Result<Vec<String>>;
+
+    async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[String],
+        expires_in_days: u32,
+    ) -> Result<ApiToken>;
+    async fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>>;
+    async fn delete_api_token(&self, user_id: &str, id: &str) -> Result<bool>;
+    /// Deletes every token of `user_id`, or of all users. Returns the count.
+    async fn revoke_api_tokens(&self, user_id: Option<&str>) -> Result<usize>;
+    /// Looks up an unexpired token of a user who can log in by hash and
+    /// records its use.
+    async fn get_api_token_owner(&self, token_hash: &str) -> Result<Option<ApiTokenOwner>>;
+
+    async fn role_has_permission(&self, role: &str, permission: &str) -> Result<bool>;
+    async fn role_exists(&self, name: &str) -> Result<bool>;
+    async fn list_roles(&self) -> Result<Vec<Role>>;
+    async fn get_role(&self, name: &str) -> Result<Option<Role>>;
+    async fn create_role(
+        &self,
+        name: &str,
+        description: &str,
+        permissions: &[String],
+    ) -> Result<()>;
+    /// Updates a custom role. Built-in roles are left untouched.
+    async fn update_role(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        permissions: Option<&[String]>,
+    ) -> Result<bool>;
+    async fn count_users_with_role(&self, name: &str) -> Result<i64>;
+    async fn delete_role(&self, name: &str) -> Result<bool>;
+
+    async fn list_groups(&self) -> Result<Vec<Group>>;
+    async fn get_group(&self, id: &str) -> Result<Option<Group>>;
+    async fn create_group(&self, name: &str, description: &str) -> Result<Group>;
+    async fn update_group(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        description: Option<&str>,
+    ) -> Result<Option<Group>>;
+    async fn delete_group(&self, id: &str) -> Result<bool>;
+    async fn list_group_members(&self, group_id: &str) -> Result<Vec<User>>;
+    async fn add_group_member(&self, group_id: &str, user_id: &str) -> Result<()>;
+    async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<bool>;
+
+    async fn list_connections(&self) -> Result<Vec<ConnectionProfile>>;
+    /// Connections assigned to any group the user belongs to.
+    async fn list_connections_for_user(&self, user_id: &str) -> Result<Vec<ConnectionProfile>>;
+    async fn get_connection(&self, id: &str) -> Result<Option<ConnectionProfile>>;
+    async fn create_connection(
+        &self,
+        name: &str,
+        protocol: &str,
+        host: &str,
+        port: u16,
+        group_ids: &[String],
+    ) -> Result<ConnectionProfile>;
+    async fn update_connection(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        host: Option<&str>,
+        port: Option<u16>,
+        group_ids: Option<&[String]>,
+    ) -> Result<Option<ConnectionProfile>>;
+    async fn delete_connection(&self, id: &str) -> Result<bool>;
+
+    async fn list_destination_rules(&self, group_id: &str) -> Result<Vec<DestinationRule>>;
+    async fn create_destination_rule(
+        &self,
+        group_id: &str,
+        pattern: &str,
+    ) -> Result<DestinationRule>;
+    async fn delete_destination_rule(&self, group_id: &str, id: &str) -> Result<bool>;
+    /// Every destination pattern the user may reach through their groups:
+    /// explicit rules plus the `host:port` of each assigned connection.
+    async fn destination_patterns_for_user(&self, user_id: &str) -> Result<Vec<String>>;
+
+    /// Appends to the hash-chained audit log.
+    async fn append_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        details: &str,
+    ) -> Result<()>;
+    /// The whole audit log, oldest first.
+    async fn list_audit(&self) -> Result<Vec<AuditEntry>>;
+}
+
+/// Opens PostgreSQL when `database.url` is set, otherwise the SQLite file at
+/// `database.path`.
+pub async fn open(config: &DatabaseConfig) -> Result<Arc<dyn Storage>> {
+    match &config.url {
+        Some(url) => open_postgres(url, config.pool_size.unwrap_or(8)).await,
+        None => Ok(Arc::new(crate::db::Database::new(
+            &config.path,
+            config.pool_size.unwrap_or(4),
+        )?)),
+    }
+}
+
+#[cfg(feature = "postgres")]
+async fn open_postgres(url: &str, pool_size: usize) -> Result<Arc<dyn Storage>> {
+    Ok(Arc::new(
+        crate::postgres::PgStorage::connect(url, pool_size).await?,
+    ))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn open_postgres(_url: &str, _pool_size: usize) -> Result<Arc<dyn Storage>> {
+    anyhow::bail!("database.url points to PostgreSQL, but this build lacks the `postgres` feature")
+}
+
+/// Whether a write failed because it would duplicate a unique column, on
+/// either backend.
+pub fn is_unique_violation(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(rusqlite::Error::SqliteFailure(failure, _)) = cause.downcast_ref() {
+            return matches!(
+                failure.extended_code,
+                rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                    | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+            );
+        }
+        #[cfg(feature = "postgres")]
+        if let Some(e) = cause.downcast_ref::<tokio_postgres::Error>() {
+            return e.code() == Some(&tokio_postgres::error::SqlState::UNIQUE_VIOLATION);
+        }
+        false
+    })
+}
+
+/// Every test runs against a fresh SQLite database and, in builds with the
+/// `postgres` feature and `DATABASE_URL` set, a fresh schema in that
+/// PostgreSQL database, so both backends are held to the same behaviour.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, UserSort};
+
+    fn unique_violation<T>(result: Result<T>) -> bool {
+        matches!(result, Err(e) if is_unique_violation(&e))
+    }
+
+    /// Runs `check` with the backend's name and its database.
+    async fn on_each_backend<F, Fut>(check: F)
+    where
+        F: Fn(&'static str, Arc<dyn Storage>) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlite = Database::new(&dir.path().join("koder.db"), 2).unwrap();
+        sqlite.initialize().await.unwrap();
+        check("sqlite", Arc::new(sqlite)).await;
+
+        #[cfg(feature = "postgres")]
+        if let Ok(url) = std::env::var("DATABASE_URL") {
+            on_postgres(&url, check).await;
+        }
+    }
+
+    /// Runs `check` in a schema of its own, dropped afterwards even if the
+    /// check panics. The URL must not require TLS.
+    #[cfg(feature = "postgres")]
+    async fn on_postgres<F, Fut>(url: &str, check: F)
+    where
+        F: Fn(&'static str, Arc<dyn Storage>) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let (client, connection) = tokio_postgres::connect(url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let schema = format!("koder_test_{}", uuid::Uuid::new_v4().simple());
+        client
+            .batch_execute(&format!("CREATE SCHEMA {schema}"))
+            .await
+            .unwrap();
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let scoped = format!("{url}{separator}options=-csearch_path%3D{schema}");
+        let db = crate::postgres::PgStorage::connect(&scoped, 2)
+            .await
+            .unwrap();
+        db.initialize().await.unwrap();
+        let result = tokio::spawn(check("postgres", Arc::new(db))).await;
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+        if let Err(e) = result {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_created_updated_and_deleted() {
+        on_each_backend(|name, db| async move {
+            let alice = db
+                .create_user("alice", "hash-1", "Alice", "user", true, None)
+                .await
+                .unwrap();
+            assert_eq!(alice.auth_source, "local", "{name}");
+            assert!(alice.must_change_password, "{name}");
+            assert!(
+                unique_violation(
+                    db.create_user("alice", "hash-2", "", "user", false, None)
+                        .await
+                ),
+                "{name}"
+            );
+
+            let changes = UserChanges {
+                display_name: Some("Alice A.".into()),
+                role: Some("admin".into()),
+                password_hash: Some("hash-2".into()),
+                enabled: Some(false),
+                expires_at: Some(Some("2000-01-01 00:00:00".into())),
+            };
+            let updated = db.update_user(&alice.id, &changes).await.unwrap().unwrap();
+            assert_eq!(
+                (updated.display_name.as_str(), updated.role.as_str()),
+                ("Alice A.", "admin"),
+                "{name}"
+            );
+            let row = db.get_user_by_username("alice").await.unwrap().unwrap();
+            assert_eq!(row.password_hash, "hash-2", "{name}");
+            assert!(row.must_change_password && !row.enabled, "{name}");
+            assert!(row.expired && !row.can_log_in(), "{name}");
+            assert_eq!(
+                db.recent_password_hashes(&alice.id, 5).await.unwrap(),
+                ["hash-2", "hash-1"],
+                "{name}"
+            );
+            assert!(
+                db.update_user("missing", &changes).await.unwrap().is_none(),
+                "{name}"
+            );
+
+            // Fields left out stay as they are; `Some(None)` clears the expiry.
+            let changes = UserChanges {
+                enabled: Some(true),
+                expires_at: Some(None),
+                ..Default::default()
+            };
+            let updated = db.update_user(&alice.id, &changes).await.unwrap().unwrap();
+            assert_eq!(
+                (updated.display_name.as_str(), updated.expires_at),
+                ("Alice A.", None),
+                "{name}"
+            );
+
+            db.record_login(&alice.id).await.unwrap();
+            let row = db.get_user_by_id(&alice.id).await.unwrap().unwrap();
+            assert!(row.last_login_at.is_some() && row.can_log_in(), "{name}");
+
+            assert!(db.delete_user(&alice.id).await.unwrap(), "{name}");
+            assert!(!db.delete_user(&alice.id).await.unwrap(), "{name}");
+            assert!(
+                db.get_user_by_username("alice").await.unwrap().is_none(),
+                "{name}"
+            );
+            let enable = UserChanges {
+                enabled: Some(true),
+                ..Default::default()
+            };
+            assert!(
+                db.update_user(&alice.id, &enable).await.unwrap().is_none(),
+                "{name}"
+            );
+            assert_eq!(db.count_users().await.unwrap(), 1, "{name}");
+            assert!(
+                unique_violation(
+                    db.create_user("alice", "hash-2", "", "user", false, None)
+                        .await
+                ),
+                "{name}"
+            );
+
+            assert!(db.purge_user(&alice.id).await.unwrap(), "{name}");
+            assert_eq!(db.count_users().await.unwrap(), 0, "{name}");
+            let alice = db
+                .create_user(
+                    "alice",
+                    "hash-2",
+                    "",
+                    "user",
+                    false,
+                    Some("2000-01-01 00:00:00"),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                alice.expires_at.as_deref(),
+                Some("2000-01-01 00:00:00"),
+                "{name}"
+            );
+            assert_eq!(
+                db.recent_password_hashes(&alice.id, 5).await.unwrap(),
+                ["hash-2"],
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn passwords_keep_a_history() {
+        on_each_backend(|name, db| async move {
+            let user = db
+                .create_user("bob", "hash-1", "", "user", true, None)
+                .await
+                .unwrap();
+            db.update_password(&user.id, "hash-2").await.unwrap();
+            db.update_password(&user.id, "hash-3").await.unwrap();
+            let row = db.get_user_by_id(&user.id).await.unwrap().unwrap();
+            assert_eq!(row.password_hash, "hash-3", "{name}");
+            assert!(!row.must_change_password, "{name}");
+            assert_eq!(
+                db.recent_password_hashes(&user.id, 2).await.unwrap(),
+                ["hash-3", "hash-2"],
+                "{name}"
+            );
+
+            assert!(
+                !db.rehash_password(&user.id, "hash-2", "hash-4")
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            assert!(
+                db.rehash_password(&user.id, "hash-3", "hash-4")
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            let row = db.get_user_by_id(&user.id).await.unwrap().unwrap();
+            assert_eq!(row.password_hash, "hash-4", "{name}");
+
+            db.delete_user(&user.id).await.unwrap();
+            db.update_password(&user.id, "hash-5").await.unwrap();
+            assert_eq!(
+                db.recent_password_hashes(&user.id, 2).await.unwrap(),
+                ["hash-4", "hash-3", "hash-2"],
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn external_users_are_found_by_their_identity() {
+        on_each_backend(|name, db| async move {
+            let Provisioned::User(user) = db
+                .provision_external_user("oidc", "sub-1", "carol", "Carol", "user")
+                .await
+                .unwrap()
+            else {
+                panic!("{name}: carol was not provisioned");
+            };
+            assert_eq!(user.auth_source, "oidc", "{name}");
+
+            // Renamed at the provider: still the same account, role synced.
+            let Provisioned::User(again) = db
+                .provision_external_user("oidc", "sub-1", "caroline", "", "admin")
+                .await
+                .unwrap()
+            else {
+                panic!("{name}: carol was not found again");
+            };
+            assert_eq!(
+                (again.id.as_str(), again.role.as_str()),
+                (user.id.as_str(), "admin"),
+                "{name}"
+            );
+            assert_eq!(again.display_name, "Carol", "{name}");
+
+            db.create_user("dave", "hash", "", "user", false, None)
+                .await
+                .unwrap();
+            assert!(
+                matches!(
+                    db.provision_external_user("oidc", "sub-2", "dave", "", "admin")
+                        .await
+                        .unwrap(),
+                    Provisioned::NameTaken
+                ),
+                "{name}"
+            );
+
+            db.delete_user(&user.id).await.unwrap();
+            assert!(
+                matches!(
+                    db.provision_external_user("oidc", "sub-1", "carol", "", "user")
+                        .await
+                        .unwrap(),
+                    Provisioned::Deleted
+                ),
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn users_are_listed_by_page() {
+        on_each_backend(|name, db| async move {
+            for (username, display_name, role) in [
+                ("erin", "Erin Silva", "user"),
+                ("frank", "Frank", "admin"),
+                ("gina", "Gina Silva", "user"),
+                ("hugo", "Hugo", "user"),
+            ] {
+                db.create_user(username, "hash", display_name, role, false, None)
+                    .await
+                    .unwrap();
+            }
+            let gone = db
+                .create_user("ivan", "hash", "Ivan Silva", "user", false, None)
+                .await
+                .unwrap();
+            db.delete_user(&gone.id).await.unwrap();
+
+            let usernames = |page: &UserPage| -> Vec<String> {
+                page.users.iter().map(|u| u.username.clone()).collect()
+            };
+            let mut query = UserQuery {
+                sort: UserSort::Username,
+                limit: Some(2),
+                ..Default::default()
+            };
+            let first = db.list_users(&query).await.unwrap();
+            assert_eq!(
+                (usernames(&first), first.total),
+                (vec!["erin".into(), "frank".into()], 4),
+                "{name}"
+            );
+            let last = first.users.last().unwrap();
+            query.after = Some((UserSort::Username.key(last).to_string(), last.id.clone()));
+            let second = db.list_users(&query).await.unwrap();
+            assert_eq!(usernames(&second), ["gina", "hugo"], "{name}");
+
+            let query = UserQuery {
+                search: Some("SILVA".into()),
+                role: Some("user".into()),
+                sort: UserSort::Username,
+                descending: true,
+                ..Default::default()
+            };
+            let page = db.list_users(&query).await.unwrap();
+            assert_eq!(
+                (usernames(&page), page.total),
+                (vec!["gina".into(), "erin".into()], 2),
+                "{name}"
+            );
+
+            let query = UserQuery {
+                search: Some("%".into()),
+                ..Default::default()
+            };
+            assert_eq!(db.list_users(&query).await.unwrap().total, 0, "{name}");
+
+            // Matching ignores case beyond ASCII, and follows renames.
+            let eloise = db
+                .create_user("éloïse", "hash", "Élodie", "user", false, None)
+                .await
+                .unwrap();
+            let search = |text: &str| UserQuery {
+                search: Some(text.into()),
+                ..Default::default()
+            };
+            let found = db.list_users(&search("ÉLOÏ")).await.unwrap();
+            assert_eq!(usernames(&found), ["éloïse"], "{name}");
+            let changes = UserChanges {
+                display_name: Some("Ângela Ávila".into()),
+                ..Default::default()
+            };
+            db.update_user(&eloise.id, &changes).await.unwrap();
+            assert_eq!(
+                db.list_users(&search("ÉLOD")).await.unwrap().total,
+                0,
+                "{name}"
+            );
+            let found = db.list_users(&search("ângela ÁVILA")).await.unwrap();
+            assert_eq!(usernames(&found), ["éloïse"], "{name}");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn api_tokens_resolve_to_owners_who_can_log_in() {
+        on_each_backend(|name, db| async move {
+            let user = db
+                .create_user("judy", "hash", "", "user", false, None)
+                .await
+                .unwrap();
+            let scopes = vec!["rdp:connect".to_string(), "profile:read".to_string()];
+            let token = db
+                .create_api_token(&user.id, "ci", "token-hash", &scopes, 30)
+                .await
+                .unwrap();
+            assert_eq!(
+                db.list_api_tokens(&user.id).await.unwrap().len(),
+                1,
+                "{name}"
+            );
+
+            let owner = db.get_api_token_owner("token-hash").await.unwrap().unwrap();
+            assert_eq!(
+                (owner.token_id.as_str(), owner.user_id.as_str()),
+                (token.id.as_str(), user.id.as_str()),
+                "{name}"
+            );
+            assert_eq!(owner.scopes, scopes, "{name}");
+            assert!(
+                db.get_api_token_owner("other-hash")
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+            assert!(
+                db.list_api_tokens(&user.id).await.unwrap()[0]
+                    .last_used_at
+                    .is_some(),
+                "{name}"
+            );
+
+            let enabled = |enabled| UserChanges {
+                enabled: Some(enabled),
+                ..Default::default()
+            };
+            db.update_user(&user.id, &enabled(false)).await.unwrap();
+            assert!(
+                db.get_api_token_owner("token-hash")
+                    .await
+                    .unwrap()
+                    .is_none(),
+                "{name}"
+            );
+            db.update_user(&user.id, &enabled(true)).await.unwrap();
+
+            assert!(
+                !db.delete_api_token("someone-else", &token.id)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            assert!(
+                db.delete_api_token(&user.id, &token.id).await.unwrap(),
+                "{name}"
+            );
+            db.create_api_token(&user.id, "a", "hash-a", &[], 1)
+                .await
+                .unwrap();
+            db.create_api_token(&user.id, "b", "hash-b", &[], 1)
+                .await
+                .unwrap();
+            assert_eq!(
+                db.revoke_api_tokens(Some(&user.id)).await.unwrap(),
+                2,
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn only_custom_roles_change() {
+        on_each_backend(|name, db| async move {
+            assert!(db.role_exists("admin").await.unwrap(), "{name}");
+            assert!(
+                db.role_has_permission("admin", crate::rbac::USERS_WRITE)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            assert!(
+                !db.update_role("admin", Some("x"), None).await.unwrap(),
+                "{name}"
+            );
+            assert!(!db.delete_role("user").await.unwrap(), "{name}");
+
+            let permissions = vec![crate::rbac::USERS_READ.to_string()];
+            db.create_role("reviewer", "Revisão", &permissions)
+                .await
+                .unwrap();
+            assert!(
+                unique_violation(db.create_role("reviewer", "", &[]).await),
+                "{name}"
+            );
+            let permissions = vec![crate::rbac::SESSIONS_READ.to_string()];
+            assert!(
+                db.update_role("reviewer", None, Some(&permissions))
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+            let role = db.get_role("reviewer").await.unwrap().unwrap();
+            assert_eq!(
+                (role.description.as_str(), role.builtin),
+                ("Revisão", false),
+                "{name}"
+            );
+            assert_eq!(role.permissions, permissions, "{name}");
+
+            db.create_user("kim", "hash", "", "reviewer", false, None)
+                .await
+                .unwrap();
+            assert_eq!(
+                db.count_users_with_role("reviewer").await.unwrap(),
+                1,
+                "{name}"
+            );
+            let kim = db.get_user_by_username("kim").await.unwrap().unwrap();
+            db.delete_user(&kim.id).await.unwrap();
+            assert_eq!(
+                db.count_users_with_role("reviewer").await.unwrap(),
+                0,
+                "{name}"
+            );
+            assert!(db.delete_role("reviewer").await.unwrap(), "{name}");
+            assert!(
+                !db.role_has_permission("reviewer", crate::rbac::SESSIONS_READ)
+                    .await
+                    .unwrap(),
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn groups_grant_destinations_to_their_members() {
+        on_each_backend(|name, db| async move {
+            let user = db
+                .create_user("leo", "hash", "", "user", false, None)
+                .await
+                .unwrap();
+            let group = db.create_group("ops", "Operations").await.unwrap();
+            assert!(unique_violation(db.create_group("ops", "").await), "{name}");
+            db.add_group_member(&group.id, &user.id).await.unwrap();
+            assert_eq!(
+                db.get_group(&group.id).await.unwrap().unwrap().member_count,
+                1,
+                "{name}"
+            );
+            assert_eq!(
+                db.list_group_members(&group.id).await.unwrap()[0].id,
+                user.id,
+                "{name}"
+            );
+
+            db.create_destination_rule(&group.id, "*.lab:3389")
+                .await
+                .unwrap();
+            let connection = db
+                .create_connection(
+                    "jump",
+                    "rdp",
+                    "jump.example",
+                    3389,
+                    std::slice::from_ref(&group.id),
+                )
+                .await
+                .unwrap();
+            assert_eq!(connection.group_ids, [group.id.as_str()], "{name}");
+            let mut patterns = db.destination_patterns_for_user(&user.id).await.unwrap();
+            patterns.sort();
+            assert_eq!(patterns, ["*.lab:3389", "jump.example:3389"], "{name}");
+            assert_eq!(
+                db.list_connections_for_user(&user.id).await.unwrap().len(),
+                1,
+                "{name}"
+            );
+
+            let renamed = db
+                .update_connection(&connection.id, None, None, Some(3390), Some(&[]))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(
+                renamed.port == 3390 && renamed.group_ids.is_empty(),
+                "{name}"
+            );
+            assert_eq!(
+                db.destination_patterns_for_user(&user.id).await.unwrap(),
+                ["*.lab:3389"],
+                "{name}"
+            );
+
+            assert!(
+                db.remove_group_member(&group.id, &user.id).await.unwrap(),
+                "{name}"
+            );
+            assert!(
+                db.destination_patterns_for_user(&user.id)
+                    .await
+                    .unwrap()
+                    .is_empty(),
+                "{name}"
+            );
+            db.add_group_member(&group.id, &user.id).await.unwrap();
+            assert!(db.delete_group(&group.id).await.unwrap(), "{name}");
+            assert!(
+                db.destination_patterns_for_user(&user.id)
+                    .await
+                    .unwrap()
+                    .is_empty(),
+                "{name}"
+            );
+            assert!(db.list_groups().await.unwrap().is_empty(), "{name}");
+
+            // Deleting a user drops their memberships, but a membership added
+            // afterwards must not bring them back.
+            let group = db.create_group("ops", "").await.unwrap();
+            db.delete_user(&user.id).await.unwrap();
+            db.add_group_member(&group.id, &user.id).await.unwrap();
+            assert!(
+                db.list_group_members(&group.id).await.unwrap().is_empty(),
+                "{name}"
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn audit_entries_form_a_chain() {
+        on_each_backend(|name, db| async move {
+            db.append_audit("alice", "user.create", "u1", "").await.unwrap();
+            db.append_audit("alice", "user.delete", "u1", r#"{"purge":true}"#)
+                .await
+                .unwrap();
+            let entries = db.list_audit().await.unwrap();
+            assert_eq!(entries.len(), 2, "{name}");
+            assert_eq!(entries[0].prev_hash, crate::audit::GENESIS, "{name}");
+            assert_eq!(entries[1].prev_hash, entries[0].hash, "{name}");
+            assert!(crate::audit::verify(&entries).is_ok(), "{name}");
+        })
+        .await;
+    }
+}
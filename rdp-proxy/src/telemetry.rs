@@ -0,0 +1,172 @@
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::util::SubscriberInitExt as _;
+use tracing_subscriber::{EnvFilter, Layer as _};
+
+use crate::config::{Config, LogFormat};
+
+/// Flushes exported spans when dropped, so keep it alive until exit.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Installs the log output and, in builds with the `otel` feature and an
+/// `otel.endpoint`, the OTLP span exporter. `RUST_LOG` selects the spans
+/// exported as well as the lines logged.
+pub fn init(config: &Config) -> anyhow::Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| "rdp_proxy=info".into());
+    let output = match config.log.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(output);
+
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &config.otel.endpoint {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let provider = otel::provider(endpoint, &config.otel)?;
+        let tracer = provider.tracer("rdp-proxy");
+        registry
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+        tracing::info!("Exporting traces to {endpoint}");
+        return Ok(Telemetry {
+            provider: Some(provider),
+        });
+    }
+
+    registry.init();
+    Ok(Telemetry::default())
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig as _};
+    use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+
+    use crate::config::OtelConfig;
+
+    pub fn provider(endpoint: &str, config: &OtelConfig) -> anyhow::Result<SdkTracerProvider> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        // Child spans follow their root span's decision, so sampled traces
+        // are never missing stages.
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(sampler)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build())
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::Router;
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use crate::config::{Config, OtelConfig};
+    use crate::db::UserQuery;
+    use crate::test_support;
+
+    type Received = Arc<Mutex<Vec<Bytes>>>;
+
+    /// An OTLP/HTTP collector that keeps the bodies posted to it.
+    async fn collector() -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/v1/traces",
+                post(|State(received): State<Received>, body: Bytes| async move {
+                    received.lock().unwrap().push(body);
+                }),
+            )
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, received)
+    }
+
+    /// Runs a query and a password check while exporting at `sample_ratio`,
+    /// and returns everything the collector received. Span names and
+    /// attributes are plain strings in the protobuf payload.
+    async fn export(sample_ratio: f64) -> Vec<u8> {
+        let (endpoint, received) = collector().await;
+        let config = OtelConfig {
+            endpoint: Some(endpoint.clone()),
+            sample_ratio,
+            ..OtelConfig::default()
+        };
+        let provider = super::otel::provider(&endpoint, &config).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let hash = state.hasher.hash("secret").await.unwrap();
+        {
+            let _guard = tracing::subscriber::set_default(subscriber);
+            state.db.list_users(&UserQuery::default()).await.unwrap();
+            assert!(state.hasher.verify("secret", &hash).await);
+        }
+        // Shutting down blocks until the collector has answered.
+        tokio::task::spawn_blocking(move || provider.shutdown().unwrap())
+            .await
+            .unwrap();
+        let bodies = received.lock().unwrap().concat();
+        bodies
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_query_and_password_spans() {
+        let exported = export(1.0).await;
+        for expected in [
+            "koder",
+            "db.query",
+            "list_users",
+            "sqlite",
+            "password.verify",
+            "argon2",
+        ] {
+            assert!(contains(&exported, expected), "{expected} was not exported");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_unsampled_traces() {
+        assert!(export(0.0).await.is_empty());
+    }
+}
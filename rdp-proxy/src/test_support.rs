@@ -0,0 +1,81 @@
+//! Fixtures shared by the unit tests.
+
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap};
+use tempfile::TempDir;
+
+use crate::config::Config;
+use crate::db::{Database, User};
+use crate::storage::Storage;
+use crate::{auth, metrics, password, password_policy, sessions, AppState};
+
+pub const JWT_SECRET: &str = "test-secret-test-secret-test-secret";
+
+/// Server state over a fresh, migrated SQLite database in a temporary
+/// directory. Keep the directory alive as long as the state.
+pub async fn state(config: Config) -> (AppState, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::new(&dir.path().join("koder.db"), 2).unwrap();
+    db.initialize().await.unwrap();
+    let state = AppState {
+        password_policy: password_policy::PasswordPolicy::new(&config.password).unwrap(),
+        hasher: password::Hasher::new(&config.argon2).unwrap(),
+        config,
+        db: Arc::new(db),
+        jwt_secret: JWT_SECRET.to_string(),
+        auth_backend: auth::AuthBackend::Local,
+        oidc: None,
+        metrics: metrics::Metrics::new().unwrap(),
+        started_at: std::time::Instant::now(),
+        draining: AtomicBool::new(false),
+        sessions: sessions::Sessions::new(),
+        setup_token: tokio::sync::Mutex::new(None),
+    };
+    (state, dir)
+}
+
+/// Serves the full router over `state` on a local port and returns its
+/// base URL.
+pub async fn serve(state: AppState) -> String {
+    let app = crate::router(Arc::new(state), None).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+    base
+}
+
+/// A local user with `password`, which need not be changed.
+pub async fn user(state: &AppState, username: &str, password: &str, role: &str) -> User {
+    let hash = state.hasher.hash(password).await.unwrap();
+    state
+        .db
+        .create_user(username, &hash, "", role, false, None)
+        .await
+        .unwrap()
+}
+
+/// `Authorization` for a session of `user`.
+pub fn session_headers(state: &AppState, user: &User) -> HeaderMap {
+    let token = auth::create_token(state, user).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+    headers
+}
+
+/// `Authorization` for a new API token of `user` with `scopes`.
+pub async fn token_headers(state: &AppState, user: &User, scopes: &[&str]) -> HeaderMap {
+    let token = format!("{}{}", crate::tokens::TOKEN_PREFIX, uuid::Uuid::new_v4().simple());
+    let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+    state
+        .db
+        .create_api_token(&user.id, "test", &crate::tokens::hash_token(&token), &scopes, 1)
+        .await
+        .unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+    headers
+}
@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::config::TlsConfig;
+
+/// Loads the configured certificate, generating a self-signed one first if
+/// asked to. Returns `None` when TLS is not configured.
+pub async fn load(config: &TlsConfig) -> Result<Option<RustlsConfig>> {
+    let (Some(cert), Some(key)) = (&config.cert_file, &config.key_file) else {
+        return Ok(None);
+    };
+    if config.self_signed && !cert.exists() && !key.exists() {
+        write_self_signed(cert, key)?;
+    }
+    let rustls = RustlsConfig::from_pem_file(cert, key)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load TLS certificate {} and key {}",
+                cert.display(),
+                key.display()
+            )
+        })?;
+    spawn_reloader(
+        rustls.clone(),
+        cert.clone(),
+        key.clone(),
+        config.reload_secs,
+    )?;
+    Ok(Some(rustls))
+}
+
+fn write_self_signed(cert: &Path, key: &Path) -> Result<()> {
+    let names = vec!["localhost".to_string(), hostname()];
+    let generated = rcgen::generate_simple_self_signed(names)?;
+    std::fs::write(cert, generated.cert.pem())
+        .with_context(|| format!("Failed to write {}", cert.display()))?;
+    write_private(key, generated.key_pair.serialize_pem().as_bytes())
+        .with_context(|| format!("Failed to write {}", key.display()))?;
+    warn!(
+        "Generated a self-signed TLS certificate at {}; browsers will not trust it",
+        cert.display()
+    );
+    Ok(())
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "koder".to_string())
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let mtime = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    Some((mtime(cert)?, mtime(key)?))
+}
+
+/// Swaps in the certificate on SIGHUP, or when the files change if
+/// `poll_secs` is not 0. Only new handshakes see it, so live relays keep
+/// running; a certificate that fails to load is logged and the current one
+/// stays in use.
+fn spawn_reloader(rustls: RustlsConfig, cert: PathBuf, key: PathBuf, poll_secs: u64) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut poll = (poll_secs > 0).then(|| tokio::time::interval(Duration::from_secs(poll_secs)));
+    let mut last = modified(&cert, &key);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = async {
+                    match poll.as_mut() {
+                        Some(poll) => poll.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if modified(&cert, &key) == last {
+                        continue;
+                    }
+                }
+            }
+            last = modified(&cert, &key);
+            match rustls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => info!("Reloaded TLS certificate from {}", cert.display()),
+                Err(e) => error!("Failed to reload TLS certificate, keeping the current one: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::audit;
+use crate::auth::{extract_auth, Claims};
+use crate::db::ApiToken;
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::AppState;
+
+pub const TOKEN_PREFIX: &str = "kdr_";
+
+/// Only the SHA-256 of a token is stored; the token itself carries enough
+/// entropy that a slow hash is unnecessary.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn generate_token() -> String {
+    format!(
+        "{TOKEN_PREFIX}{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Tokens are managed with a login session only, so a leaked token cannot
+/// mint new ones.
+async fn require_session(headers: &HeaderMap, state: &AppState) -> Result<Claims, ApiError> {
+    let claims = extract_auth(headers, state).await?;
+    if claims.is_api_token() {
+        return Err(ApiError::ApiTokenNotAllowed);
+    }
+    Ok(claims)
+}
+
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    tag = "tokens",
+    responses(
+        (status = 200, body = [ApiToken]),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn list_tokens(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ApiToken>>, ApiError> {
+    let claims = require_session(&headers, &state).await?;
+
+    let tokens = state
+        .db
+        .list_api_tokens(&claims.sub)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(Json(tokens))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    /// Permissions, or `rdp:connect`, `connections:read` and
+    /// `profile:read` for what any user may do.
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateTokenResponse {
+    /// Returned only once; it cannot be recovered later.
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body = CreateTokenRequest,
+    responses(
+        (status = 201, body = CreateTokenResponse),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+pub async fn create_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<CreateTokenResponse>), ApiError> {
+    let claims = require_session(&headers, &state).await?;
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::NameRequired);
+    }
+
+    if body.scopes.is_empty() {
+        return Err(ApiError::TokenScopesRequired);
+    }
+    if let Some(bad) = body.scopes.iter().find(|s| !crate::rbac::is_scope(s)) {
+        return Err(ApiError::UnknownScope(bad.clone()));
+    }
+
+    let max_days = state.config.auth.api_token_max_days;
+    let days = body
+        .expires_in_days
+        .unwrap_or(state.config.auth.api_token_default_days);
+    if days == 0 || days > max_days {
+        return Err(ApiError::InvalidTokenLifetime(max_days));
+    }
+
+    let token = generate_token();
+    let info = state
+        .db
+        .create_api_token(&claims.sub, name, &hash_token(&token), &body.scopes, days)
+        .await
+        .map_err(ApiError::internal)?;
+
+    audit::record(
+        &state,
+        &claims.username,
+        "token.create",
+        &info.id,
+        serde_json::json!({ "name": info.name, "scopes": info.scopes }),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(CreateTokenResponse { token, info })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/tokens/{id}",
+    tag = "tokens",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_session(&headers, &state).await?;
+
+    let deleted = state
+        .db
+        .delete_api_token(&claims.sub, &id)
+        .await
+        .map_err(ApiError::internal)?;
+
+    if !deleted {
+        return Err(ApiError::TokenNotFound);
+    }
+
+    audit::record(
+        &state,
+        &claims.username,
+        "token.revoke",
+        &id,
+        serde_json::Value::Null,
+    )
+    .await;
+
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
@@ -1,154 +1,569 @@
 use std::sync::Arc;
 
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde::Deserialize;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-use crate::auth::extract_auth;
-use crate::db::User;
+use crate::audit;
+use crate::db::{User, UserChanges, UserQuery, UserSort};
+use crate::error::ApiError;
+use crate::openapi::ErrorBody;
+use crate::password_policy;
+use crate::rbac::{self, require_permission};
 use crate::AppState;
 
-fn err(status: StatusCode, msg: &str) -> (StatusCode, Json<serde_json::Value>) {
-    (status, Json(serde_json::json!({ "error": msg })))
+async fn validate_role(state: &AppState, role: &str) -> Result<(), ApiError> {
+    let exists = state
+        .db
+        .role_exists(role)
+        .await
+        .map_err(ApiError::internal)?;
+    if !exists {
+        return Err(ApiError::RoleNotFound);
+    }
+    Ok(())
 }
 
-fn require_admin(
-    headers: &HeaderMap,
-    state: &AppState,
-) -> Result<crate::auth::Claims, (StatusCode, Json<serde_json::Value>)> {
-    let claims = extract_auth(headers, state)?;
-    if claims.role != "admin" {
-        return Err(err(StatusCode::FORBIDDEN, "Acesso restrito a administradores"));
+/// Accepts `YYYY-MM-DD HH:MM:SS` or the RFC 3339 form with `T` and an
+/// optional `Z`, both in UTC, and returns the former, which is how
+/// timestamps are stored.
+fn normalize_expiry(value: &str) -> Result<String, ApiError> {
+    let value = value.trim();
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let parsed = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| ApiError::InvalidExpiry)?;
+    Ok(parsed.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Tells an absent field (`None`) from an explicit `null` (`Some(None)`).
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 200;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersQuery {
+    /// Part of the username or display name, ignoring case.
+    pub q: Option<String>,
+    pub role: Option<String>,
+    /// `username`, `display_name` or `created_at` (the default); a leading
+    /// `-` sorts in descending order.
+    pub sort: Option<String>,
+    /// Page size, at most 200. Defaults to 50.
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserList {
+    pub users: Vec<User>,
+    /// Users matching `q` and `role` across all pages.
+    pub total: i64,
+    /// Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Opaque to clients. Records the sort it was made for, since its key is
+/// meaningless under another one.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    key: String,
+    id: String,
+}
+
+fn encode_cursor(sort: &str, by: UserSort, last: &User) -> String {
+    let cursor = Cursor {
+        sort: sort.to_string(),
+        key: by.key(last).to_string(),
+        id: last.id.clone(),
+    };
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str, sort: &str) -> Result<(String, String), ApiError> {
+    let cursor: Cursor = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(ApiError::InvalidCursor)?;
+    if cursor.sort != sort {
+        return Err(ApiError::InvalidCursor);
     }
-    Ok(claims)
+    Ok((cursor.key, cursor.id))
 }
 
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(ListUsersQuery),
+    responses(
+        (status = 200, body = UserList),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
 pub async fn list_users(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
-) -> Result<Json<Vec<User>>, (StatusCode, Json<serde_json::Value>)> {
-    require_admin(&headers, &state)?;
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<UserList>, ApiError> {
+    require_permission(&headers, &state, rbac::USERS_READ).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    find_users(&state, query, Some(limit)).await.map(Json)
+}
+
+/// `GET /api/users`, from before pagination. Older clients expect every
+/// user as a bare array, so that is what they get unless they ask for a
+/// page with `limit` or `cursor`.
+pub async fn list_users_unversioned(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Response, ApiError> {
+    if query.limit.is_some() || query.cursor.is_some() {
+        return list_users(State(state), headers, Query(query))
+            .await
+            .map(IntoResponse::into_response);
+    }
+    require_permission(&headers, &state, rbac::USERS_READ).await?;
+    let list = find_users(&state, query, None).await?;
+    Ok(Json(list.users).into_response())
+}
 
-    let users = state
+/// Pages of `limit` users, or all of them when there is no limit.
+async fn find_users(
+    state: &AppState,
+    query: ListUsersQuery,
+    limit: Option<usize>,
+) -> Result<UserList, ApiError> {
+    let sort_param = query.sort.as_deref().unwrap_or("created_at");
+    let (descending, name) = match sort_param.strip_prefix('-') {
+        Some(name) => (true, name),
+        None => (false, sort_param),
+    };
+    let sort = UserSort::from_name(name).ok_or(ApiError::InvalidSort)?;
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|c| decode_cursor(c, sort_param))
+        .transpose()?;
+
+    // One extra row tells whether there is a next page.
+    let mut page = state
         .db
-        .list_users()
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?;
+        .list_users(&UserQuery {
+            search: query
+                .q
+                .as_deref()
+                .map(str::trim)
+                .filter(|q| !q.is_empty())
+                .map(str::to_string),
+            role: query.role.filter(|r| !r.is_empty()),
+            sort,
+            descending,
+            limit: limit.map(|limit| limit + 1),
+            after,
+        })
+        .await
+        .map_err(ApiError::internal)?;
+    let next_cursor = match limit {
+        Some(limit) if page.users.len() > limit => {
+            page.users.truncate(limit);
+            page.users
+                .last()
+                .map(|last| encode_cursor(sort_param, sort, last))
+        }
+        _ => None,
+    };
 
-    Ok(Json(users))
+    Ok(UserList {
+        users: page.users,
+        total: page.total,
+        next_cursor,
+    })
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct CreateUserRequest {
     pub username: String,
     pub password: String,
     pub display_name: Option<String>,
     pub role: Option<String>,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`. The account cannot log in from then on.
+    pub expires_at: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    request_body = CreateUserRequest,
+    responses(
+        (status = 201, body = User),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody),
+    )
+)]
 pub async fn create_user(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Json(body): Json<CreateUserRequest>,
-) -> Result<(StatusCode, Json<User>), (StatusCode, Json<serde_json::Value>)> {
-    require_admin(&headers, &state)?;
+) -> Result<(StatusCode, Json<User>), ApiError> {
+    let claims = require_permission(&headers, &state, rbac::USERS_WRITE).await?;
 
     if body.username.trim().is_empty() || body.password.is_empty() {
-        return Err(err(StatusCode::BAD_REQUEST, "Usuário e senha são obrigatórios"));
+        return Err(ApiError::UserFieldsRequired);
     }
 
     let role = body.role.as_deref().unwrap_or("user");
-    if role != "admin" && role != "user" {
-        return Err(err(StatusCode::BAD_REQUEST, "Role deve ser 'admin' ou 'user'"));
-    }
+    validate_role(&state, role).await?;
+    let expires_at = body
+        .expires_at
+        .as_deref()
+        .map(normalize_expiry)
+        .transpose()?;
+
+    password_policy::enforce(&state, body.username.trim(), &body.password, None).await?;
+    let password_hash = state
+        .hasher
+        .hash(&body.password)
+        .await
+        .map_err(ApiError::internal)?;
 
     let display_name = body.display_name.as_deref().unwrap_or("");
 
     let user = state
         .db
-        .create_user(&body.username, &body.password, display_name, role)
+        .create_user(
+            &body.username,
+            &password_hash,
+            display_name,
+            role,
+            true,
+            expires_at.as_deref(),
+        )
+        .await
         .map_err(|e| {
-            let msg = e.to_string();
-            if msg.contains("UNIQUE") {
-                err(StatusCode::CONFLICT, "Usuário já existe")
+            if crate::storage::is_unique_violation(&e) {
+                ApiError::UserExists
             } else {
-                err(StatusCode::INTERNAL_SERVER_ERROR, "Erro ao criar usuário")
+                ApiError::internal(e)
             }
         })?;
 
+    audit::record(
+        &state,
+        &claims.username,
+        "user.create",
+        &user.id,
+        serde_json::json!({
+            "username": user.username,
+            "role": user.role,
+            "expires_at": user.expires_at,
+        }),
+    )
+    .await;
+
     Ok((StatusCode::CREATED, Json(user)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = User),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
 pub async fn get_user(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Path(id): Path<String>,
-) -> Result<Json<User>, (StatusCode, Json<serde_json::Value>)> {
-    require_admin(&headers, &state)?;
+) -> Result<Json<User>, ApiError> {
+    require_permission(&headers, &state, rbac::USERS_READ).await?;
 
     let user = state
         .db
         .get_user_by_id(&id)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro interno"))?
-        .ok_or_else(|| err(StatusCode::NOT_FOUND, "Usuário não encontrado"))?;
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?;
 
     Ok(Json(user.to_public()))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct UpdateUserRequest {
     pub display_name: Option<String>,
     pub role: Option<String>,
     pub password: Option<String>,
+    pub enabled: Option<bool>,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`; `null` removes the expiry.
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>)]
+    pub expires_at: Option<Option<String>>,
 }
 
+#[utoipa::path(
+    put,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = String, Path)),
+    request_body = UpdateUserRequest,
+    responses(
+        (status = 200, body = User),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
 pub async fn update_user(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Path(id): Path<String>,
     Json(body): Json<UpdateUserRequest>,
-) -> Result<Json<User>, (StatusCode, Json<serde_json::Value>)> {
-    require_admin(&headers, &state)?;
+) -> Result<Json<User>, ApiError> {
+    let password_only = body.display_name.is_none()
+        && body.role.is_none()
+        && body.enabled.is_none()
+        && body.expires_at.is_none();
+    let claims = if password_only {
+        match require_permission(&headers, &state, rbac::USERS_RESET_PASSWORD).await {
+            Ok(claims) => claims,
+            Err(_) => require_permission(&headers, &state, rbac::USERS_WRITE).await?,
+        }
+    } else {
+        require_permission(&headers, &state, rbac::USERS_WRITE).await?
+    };
 
     if let Some(ref r) = body.role {
-        if r != "admin" && r != "user" {
-            return Err(err(StatusCode::BAD_REQUEST, "Role deve ser 'admin' ou 'user'"));
+        validate_role(&state, r).await?;
+    }
+    if body.enabled == Some(false) && claims.sub == id {
+        return Err(ApiError::CannotDisableSelf);
+    }
+    let expires_at = body
+        .expires_at
+        .as_ref()
+        .map(|e| e.as_deref().map(normalize_expiry).transpose())
+        .transpose()?;
+
+    // Without users:write, only reset passwords of users whose role grants
+    // nothing the caller lacks, so a helpdesk account cannot take over an
+    // admin.
+    if !rbac::has_permission(&state, &claims, rbac::USERS_WRITE).await? {
+        let target = state
+            .db
+            .get_user_by_id(&id)
+            .await
+            .map_err(ApiError::internal)?
+            .ok_or(ApiError::UserNotFound)?;
+        let target_role = state
+            .db
+            .get_role(&target.role)
+            .await
+            .map_err(ApiError::internal)?;
+        for permission in target_role.map(|r| r.permissions).unwrap_or_default() {
+            if !rbac::has_permission(&state, &claims, &permission).await? {
+                return Err(ApiError::Forbidden);
+            }
         }
     }
 
+    let password_hash = match body.password {
+        Some(ref password) => {
+            let target = state
+                .db
+                .get_user_by_id(&id)
+                .await
+                .map_err(ApiError::internal)?
+                .ok_or(ApiError::UserNotFound)?;
+            password_policy::enforce(&state, &target.username, password, Some(&id)).await?;
+            let hash = state
+                .hasher
+                .hash(password)
+                .await
+                .map_err(ApiError::internal)?;
+            Some(hash)
+        }
+        None => None,
+    };
+
+    let details = serde_json::json!({
+        "display_name": body.display_name,
+        "role": body.role,
+        "password_reset": body.password.is_some(),
+        "enabled": body.enabled,
+        "expires_at": expires_at,
+    });
+    let changes = UserChanges {
+        display_name: body.display_name,
+        role: body.role,
+        password_hash,
+        enabled: body.enabled,
+        expires_at,
+    };
     let user = state
         .db
-        .update_user(
-            &id,
-            body.display_name.as_deref(),
-            body.role.as_deref(),
-            body.password.as_deref(),
-        )
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro ao atualizar"))?
-        .ok_or_else(|| err(StatusCode::NOT_FOUND, "Usuário não encontrado"))?;
+        .update_user(&id, &changes)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or(ApiError::UserNotFound)?;
+
+    audit::record(&state, &claims.username, "user.update", &id, details).await;
 
     Ok(Json(user))
 }
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteUserQuery {
+    /// Remove the user's row for good instead of marking it deleted. Also
+    /// works on users that were already deleted.
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    tag = "users",
+    params(("id" = String, Path), DeleteUserQuery),
+    responses(
+        (status = 200, body = serde_json::Value, example = json!({"ok": true})),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
 pub async fn delete_user(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Path(id): Path<String>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
-    let claims = require_admin(&headers, &state)?;
+    Query(query): Query<DeleteUserQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let claims = require_permission(&headers, &state, rbac::USERS_WRITE).await?;
 
     if claims.sub == id {
-        return Err(err(StatusCode::BAD_REQUEST, "Não é possível excluir o próprio usuário"));
+        return Err(ApiError::CannotDeleteSelf);
     }
 
-    let deleted = state
-        .db
-        .delete_user(&id)
-        .map_err(|_| err(StatusCode::INTERNAL_SERVER_ERROR, "Erro ao excluir"))?;
-
-    if !deleted {
-        return Err(err(StatusCode::NOT_FOUND, "Usuário não encontrado"));
+    let (deleted, action) = if query.purge {
+        (state.db.purge_user(&id).await, "user.purge")
+    } else {
+        (state.db.delete_user(&id).await, "user.delete")
+    };
+    if !deleted.map_err(ApiError::internal)? {
+        return Err(ApiError::UserNotFound);
     }
 
+    audit::record(
+        &state,
+        &claims.username,
+        action,
+        &id,
+        serde_json::Value::Null,
+    )
+    .await;
+
     Ok(Json(serde_json::json!({ "ok": true })))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support;
+
+    async fn get(base: &str, path: &str, headers: &axum::http::HeaderMap) -> Value {
+        let mut request = reqwest::Client::new().get(format!("{base}{path}"));
+        for (name, value) in headers {
+            request = request.header(name.as_str(), value.as_bytes());
+        }
+        let res = request.send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK, "{path}");
+        res.json().await.unwrap()
+    }
+
+    #[test]
+    fn expiry_must_be_a_real_utc_time() {
+        for (given, stored) in [
+            ("2026-02-28 23:59:59", "2026-02-28 23:59:59"),
+            ("2028-02-29T12:00:00Z", "2028-02-29 12:00:00"),
+            (" 2026-12-31T00:00:00 ", "2026-12-31 00:00:00"),
+        ] {
+            assert_eq!(normalize_expiry(given).unwrap(), stored, "{given}");
+        }
+        for given in [
+            "2026-02-31 00:00:00",
+            "2026-02-29 00:00:00",
+            "2026-04-31T00:00:00Z",
+            "2026-13-01 00:00:00",
+            "2026-01-01 24:00:00",
+            "2026-01-01",
+            "2026-01-01T00:00:00+02:00",
+        ] {
+            assert!(
+                matches!(normalize_expiry(given), Err(ApiError::InvalidExpiry)),
+                "{given}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unversioned_list_stays_an_array_unless_paged() {
+        let (state, _dir) = test_support::state(Config::default()).await;
+        let admin = test_support::user(&state, "admin", "Admin-Pass-123!", "admin").await;
+        for name in ["bob", "carol"] {
+            test_support::user(&state, name, "User-Pass-123!", "user").await;
+        }
+        let headers = test_support::session_headers(&state, &admin);
+        let base = test_support::serve(state).await;
+
+        let all = get(&base, "/api/users?sort=username", &headers).await;
+        let names: Vec<_> = all
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| u["username"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["admin", "bob", "carol"]);
+
+        let page = get(&base, "/api/users?limit=2", &headers).await;
+        assert_eq!(page["users"].as_array().unwrap().len(), 2);
+        assert_eq!(page["total"], 3);
+        assert!(page["next_cursor"].is_string());
+
+        let versioned = get(&base, "/api/v1/users", &headers).await;
+        assert_eq!(versioned["users"].as_array().unwrap().len(), 3);
+        assert!(versioned["next_cursor"].is_null());
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::Router;
+use tower_http::compression::CompressionLayer;
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::error::ApiError;
+
+/// Flutter does not fingerprint these names, so they must be revalidated for
+/// a new release to be picked up.
+const ENTRY_POINTS: &[&str] = &[
+    "flutter.js",
+    "flutter_bootstrap.js",
+    "flutter_service_worker.js",
+    "main.dart.js",
+    "manifest.json",
+    "version.json",
+];
+
+const LONG_CACHE_EXTENSIONS: &[&str] = &[
+    "js", "css", "png", "jpg", "jpeg", "gif", "ico", "svg", "woff", "woff2", "ttf", "eot", "wasm",
+];
+
+/// Serves the Flutter web bundle the way nginx.container.conf did: unknown
+/// paths fall back to index.html for client-side routing, static assets are
+/// cached for a year and responses are compressed (gzip or brotli).
+/// Returns `None` when there is no bundle to serve.
+pub fn router(root: Option<&Path>) -> Option<Router> {
+    let router = match root {
+        Some(root) => Router::new().fallback_service(
+            ServeDir::new(root).fallback(ServeFile::new(root.join("index.html"))),
+        ),
+        None => embedded()?,
+    };
+    Some(
+        router
+            .layer(middleware::from_fn(cache_control))
+            .layer(CompressionLayer::new()),
+    )
+}
+
+/// Keeps unknown API paths from being answered with index.html.
+pub async fn api_not_found() -> ApiError {
+    ApiError::RouteNotFound
+}
+
+async fn cache_control(req: Request, next: Next) -> Response {
+    let name = req
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut res = next.run(req).await;
+    if !res.status().is_success() {
+        return res;
+    }
+
+    let is_html = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let value = if is_html || ENTRY_POINTS.contains(&name.as_str()) {
+        "no-cache"
+    } else if extension.is_some_and(|ext| LONG_CACHE_EXTENSIONS.contains(&ext.as_str())) {
+        "public, max-age=31536000, immutable"
+    } else {
+        return res;
+    };
+    res.headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+    res
+}
+
+#[cfg(feature = "embed-web")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "../build/web/"]
+struct Bundle;
+
+#[cfg(feature = "embed-web")]
+fn embedded() -> Option<Router> {
+    Some(Router::new().fallback(serve_embedded))
+}
+
+#[cfg(not(feature = "embed-web"))]
+fn embedded() -> Option<Router> {
+    None
+}
+
+#[cfg(feature = "embed-web")]
+async fn serve_embedded(uri: axum::http::Uri, headers: axum::http::HeaderMap) -> Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse as _;
+
+    let path = uri.path().trim_start_matches('/');
+    let (path, file) = match Bundle::get(path).filter(|_| !path.is_empty()) {
+        Some(file) => (path, file),
+        None => match Bundle::get("index.html") {
+            Some(file) => ("index.html", file),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+    };
+
+    let hash: String = file
+        .metadata
+        .sha256_hash()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let etag = format!("\"{hash}\"");
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|v| v.as_bytes() == etag.as_bytes())
+    {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    (
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::ETAG, etag),
+        ],
+        file.data,
+    )
+        .into_response()
+}
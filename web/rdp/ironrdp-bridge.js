@@ -47,6 +47,7 @@ class IronRdpSession {
     this._onStatus = null;
     this._onError = null;
     this._onTerminate = null;
+    this._onNotice = null;
   }
 
   /**
@@ -69,6 +70,7 @@ class IronRdpSession {
    * @param {string} opts.password
    * @param {string} opts.destination - host:port
    * @param {string} opts.proxyAddress - WebSocket URL to the RDCleanPath proxy
+   * @param {string} opts.authToken - koder API token, checked by the proxy
    * @param {string} [opts.domain] - Windows domain (optional)
    * @param {HTMLCanvasElement} opts.canvas - Target canvas element
    * @param {number} [opts.width=1280] - Desktop width
@@ -89,7 +91,7 @@ class IronRdpSession {
     builder = builder.password(opts.password);
     builder = builder.destination(opts.destination);
     builder = builder.proxyAddress(opts.proxyAddress);
-    builder = builder.authToken('koder');
+    builder = builder.authToken(opts.authToken);
     builder = builder.desktopSize(desktopSize);
     builder = builder.renderCanvas(opts.canvas);
 
@@ -112,8 +114,14 @@ class IronRdpSession {
 
     this._reportStatus('connecting');
 
+    // The proxy's socket is opened while connecting.
+    const restoreWebSocket = this._interceptNotices();
     try {
-      this.session = await builder.connect();
+      try {
+        this.session = await builder.connect();
+      } finally {
+        restoreWebSocket();
+      }
       this.active = true;
       this._reportStatus('connected');
       this._setupInputHandlers();
@@ -176,6 +184,7 @@ class IronRdpSession {
   onStatus(callback) { this._onStatus = callback; }
   onError(callback) { this._onError = callback; }
   onTerminate(callback) { this._onTerminate = callback; }
+  onNotice(callback) { this._onNotice = callback; }
 
   // -- Internal --
 
@@ -183,6 +192,35 @@ class IronRdpSession {
     if (this._onStatus) this._onStatus(status);
   }
 
+  /**
+   * The proxy sends notices, such as an upcoming restart, as JSON text
+   * frames. IronRDP only expects binary frames, so text frames are taken
+   * off its socket and reported through onNotice instead.
+   * @returns {Function} restores the original WebSocket constructor
+   */
+  _interceptNotices() {
+    const NativeWebSocket = window.WebSocket;
+    const session = this;
+    window.WebSocket = class extends NativeWebSocket {
+      constructor(...args) {
+        super(...args);
+        this.addEventListener('message', (e) => {
+          if (typeof e.data !== 'string') return;
+          e.stopImmediatePropagation();
+          try {
+            const notice = JSON.parse(e.data);
+            if (session._onNotice && notice.message) {
+              session._onNotice(notice.message);
+            }
+          } catch (_) {
+            // Not a notice; ignore it.
+          }
+        });
+      }
+    };
+    return () => { window.WebSocket = NativeWebSocket; };
+  }
+
   _setupInputHandlers() {
     this._onKeyDown = (e) => this._handleKey(e, true);
     this._onKeyUp = (e) => this._handleKey(e, false);